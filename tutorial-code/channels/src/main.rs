@@ -1,6 +1,57 @@
 use bytes::Bytes;
-use mini_redis::client;
-use tokio::sync::{mpsc, oneshot};
+use futures::stream::{FuturesUnordered, Stream, StreamExt};
+use futures::FutureExt;
+use mini_redis::client::{self, Client};
+use rand::Rng;
+use tokio::net::ToSocketAddrs;
+use tokio::sync::oneshot::error::RecvError;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time;
+
+use std::cmp;
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Address of the mini-redis server.
+const ADDR: &str = "127.0.0.1:6379";
+
+/// How long the manager waits for the mini-redis connection to be established.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of connection attempts the manager makes before giving up.
+const MAX_CONNECT_ATTEMPTS: usize = 5;
+
+/// Delay before the first retry. Later retries back off exponentially.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Upper bound on the delay between two connection attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// How long a requester waits for the manager to answer a command.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to wait, once a manager gave up connecting, before asking it to
+/// try again.
+const RESET_DELAY: Duration = Duration::from_secs(10);
+
+/// Whether a `SET` that runs into a broken connection is replayed once the
+/// manager reconnected. See `run_manager`.
+const REPLAY_WRITES: bool = false;
+
+/// Number of manager tasks. Each one owns a connection and handles a share of
+/// the keys.
+const NUM_SHARDS: usize = 2;
+
+/// Number of commands that may be in flight, across all managers, before new
+/// ones are rejected with `Overloaded`.
+const MAX_IN_FLIGHT: usize = 64;
 
 /// Multiple different commands are multiplexed over a single channel.
 #[derive(Debug)]
@@ -14,39 +65,558 @@ enum Command {
         val: Vec<u8>,
         resp: Responder<()>,
     },
+    /// Try connecting again after the manager gave up, leaving the `Failed`
+    /// state. Ignored while connected.
+    Reset,
+    /// Stop the manager even though senders are still alive. Commands queued
+    /// behind it are dropped, and their requesters see the `oneshot` close.
+    Shutdown,
 }
 
 /// Provided by the requester and used by the manager task to send the command
 /// response back to the requester.
 type Responder<T> = oneshot::Sender<mini_redis::Result<T>>;
 
-#[tokio::main]
-async fn main() {
-    let (mut tx, mut rx) = mpsc::channel(32);
-    // Clone a `tx` handle for the second f
-    let mut tx2 = tx.clone();
-
-    let manager = tokio::spawn(async move {
-        // Open a connection to the mini-redis address.
-        let mut client = client::connect("127.0.0.1:6379").await.unwrap();
-
-        while let Some(cmd) = rx.recv().await {
-            match cmd {
-                Command::Get { key, resp } => {
-                    let res = client.get(&key).await;
-                    // Ignore errors
-                    let _ = resp.send(res);
+/// State of the manager's connection, published through a `watch` channel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ConnectionState {
+    Connecting,
+    Connected,
+    /// All connection attempts failed. Every command is answered with an
+    /// error until the manager receives `Command::Reset`.
+    Failed,
+}
+
+/// Returned by `connect_with_timeout` when the connection could not be
+/// established in time.
+#[derive(Debug)]
+struct ConnectTimeout;
+
+impl fmt::Display for ConnectTimeout {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "timed out connecting to the mini-redis server".fmt(fmt)
+    }
+}
+
+impl Error for ConnectTimeout {}
+
+/// Open a connection to the mini-redis server, giving up after `dur`.
+///
+/// `client::connect` waits as long as the operating system lets it, which can
+/// be a very long time when the server is unreachable. Racing it against a
+/// delay bounds that wait and lets the caller decide what to do next. When the
+/// delay wins, the connect future is dropped and `ConnectTimeout` is returned.
+async fn connect_with_timeout<T: ToSocketAddrs>(
+    addr: T,
+    dur: Duration,
+) -> mini_redis::Result<Client> {
+    match time::timeout(dur, client::connect(addr)).await {
+        Ok(res) => res,
+        Err(_) => Err(ConnectTimeout.into()),
+    }
+}
+
+/// Sent back to requesters once the manager has given up on connecting.
+#[derive(Debug)]
+struct ConnectionFailed;
+
+impl fmt::Display for ConnectionFailed {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "not connected to the mini-redis server".fmt(fmt)
+    }
+}
+
+impl Error for ConnectionFailed {}
+
+/// Sent back to requesters when too many commands are already in flight.
+#[derive(Debug)]
+struct Overloaded;
+
+impl fmt::Display for Overloaded {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "too many requests in flight".fmt(fmt)
+    }
+}
+
+impl Error for Overloaded {}
+
+/// Delays to wait between connection attempts.
+///
+/// Each delay doubles the previous one, up to `max`. Half of every delay is
+/// randomized so that many clients failing at the same time do not retry in
+/// lockstep. The iterator ends once the attempts are used up, which is the
+/// signal to give up.
+struct Backoff {
+    next: Duration,
+    max: Duration,
+    remaining: usize,
+}
+
+impl Backoff {
+    /// Delays for `max_attempts` attempts. The first attempt happens right
+    /// away, so `max_attempts - 1` delays are yielded.
+    fn new(initial: Duration, max: Duration, max_attempts: usize) -> Backoff {
+        Backoff {
+            next: initial,
+            max,
+            remaining: max_attempts.saturating_sub(1),
+        }
+    }
+}
+
+impl Iterator for Backoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        self.remaining -= 1;
+
+        let delay = self.next;
+        self.next = cmp::min(delay * 2, self.max);
+
+        let half = delay.as_millis() as u64 / 2;
+        let jitter = rand::thread_rng().gen_range(0, half + 1);
+
+        Some(Duration::from_millis(half + jitter))
+    }
+}
+
+/// Opens the manager's connection, retrying with backoff.
+struct Connector<F> {
+    /// Makes a single connection attempt.
+    attempt: F,
+    max_attempts: usize,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl<F, Fut> Connector<F>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = mini_redis::Result<Client>>,
+{
+    /// Connect to the mini-redis server, retrying with backoff.
+    ///
+    /// Progress is reported through `state`. Returns `None`, after moving
+    /// `state` to `Failed`, once `max_attempts` attempts have failed.
+    async fn connect(&mut self, state: &watch::Sender<ConnectionState>) -> Option<Client> {
+        let _ = state.broadcast(ConnectionState::Connecting);
+
+        let mut backoff = Backoff::new(self.initial_backoff, self.max_backoff, self.max_attempts);
+
+        loop {
+            match (self.attempt)().await {
+                Ok(client) => {
+                    let _ = state.broadcast(ConnectionState::Connected);
+                    return Some(client);
                 }
-                Command::Set { key, val, resp } => {
-                    let res = client.set(&key, val.into()).await;
-                    // Ignore errors
-                    let _ = resp.send(res);
+                Err(err) => {
+                    eprintln!("failed to connect; err = {}", err);
+
+                    match backoff.next() {
+                        Some(delay) => time::delay_for(delay).await,
+                        None => {
+                            let _ = state.broadcast(ConnectionState::Failed);
+                            return None;
+                        }
+                    }
                 }
             }
         }
+    }
+}
+
+/// Returns `true` if `res` failed because the connection is broken.
+///
+/// I/O errors, including the "connection reset" error `Client` returns when
+/// the server closes the socket, mean the connection is unusable. So does the
+/// "connection reset by peer" error mini-redis returns, as a plain string,
+/// when the socket closes in the middle of a frame. Any other error is the
+/// server rejecting the command, which leaves the connection intact.
+fn is_connection_error<T>(res: &mini_redis::Result<T>) -> bool {
+    match res {
+        Err(err) => err.is::<io::Error>() || err.to_string() == "connection reset by peer",
+        Ok(_) => false,
+    }
+}
+
+/// What became of a command issued by `issue`.
+enum Issued {
+    /// The requester got its response.
+    Answered,
+    /// The connection broke. The command is handed back, unanswered, if it
+    /// may be replayed. Otherwise, the requester got the error.
+    Broken(Option<Command>),
+}
+
+impl Command {
+    /// Returns `true` if the command may be issued a second time after the
+    /// connection broke under it. See `run_manager`.
+    fn is_replayable(&self, replay_writes: bool) -> bool {
+        match self {
+            Command::Get { .. } => true,
+            Command::Set { .. } => replay_writes,
+            Command::Reset | Command::Shutdown => false,
+        }
+    }
+}
+
+/// Issue `cmd`, a `Get` or a `Set`, on `client` and send the result back to
+/// the requester.
+///
+/// If the connection broke and `replay` is set, the command is handed back
+/// instead of answered.
+async fn issue(client: &mut Client, cmd: Command, replay: bool) -> Issued {
+    match cmd {
+        Command::Get { key, resp } => {
+            let res = client.get(&key).await;
+            let lost = is_connection_error(&res);
+
+            if lost && replay {
+                return Issued::Broken(Some(Command::Get { key, resp }));
+            }
+
+            // `send` fails when the requester dropped its receiver, for
+            // example because it gave up waiting. The response has nowhere to
+            // go, so log it and move on.
+            if resp.send(res).is_err() {
+                eprintln!("GET {}: requester went away before the response", key);
+            }
+
+            if lost {
+                Issued::Broken(None)
+            } else {
+                Issued::Answered
+            }
+        }
+        Command::Set { key, val, resp } => {
+            // Same as `Get`. `Client::set` takes the value as `Bytes`, which a
+            // `Vec<u8>` converts into. The value is cloned so it is still
+            // around if the command is replayed.
+            let res = client.set(&key, val.clone().into()).await;
+            let lost = is_connection_error(&res);
+
+            if lost && replay {
+                return Issued::Broken(Some(Command::Set { key, val, resp }));
+            }
+
+            if resp.send(res).is_err() {
+                eprintln!("SET {}: requester went away before the response", key);
+            }
+
+            if lost {
+                Issued::Broken(None)
+            } else {
+                Issued::Answered
+            }
+        }
+        _ => unreachable!("only GET and SET are issued"),
+    }
+}
+
+/// Answer `cmd`, a `Get` or a `Set`, with the error `err` returns.
+fn fail(cmd: Command, err: impl Fn() -> mini_redis::Error) {
+    match cmd {
+        Command::Get { key, resp } => {
+            if resp.send(Err(err())).is_err() {
+                eprintln!("GET {}: requester went away before the response", key);
+            }
+        }
+        Command::Set { key, resp, .. } => {
+            if resp.send(Err(err())).is_err() {
+                eprintln!("SET {}: requester went away before the response", key);
+            }
+        }
+        _ => unreachable!("only GET and SET are answered"),
+    }
+}
+
+/// Manager task: owns one connection, opened by `connector`, and issues the
+/// commands it receives.
+///
+/// When a command runs into a broken connection, the manager reconnects and
+/// replays the command on the new connection. The requester only sees an
+/// error if reconnecting gives up, or if the replay breaks too. A command is
+/// replayed at most once, so a server that keeps dropping connections does
+/// not hold a requester forever.
+///
+/// Replaying is only safe for commands that can be applied twice. A command
+/// may have reached the server before the connection broke, and the replay
+/// then applies it a second time. That is harmless for a `GET`, so reads are
+/// always replayed. A `SET` applied twice may overwrite a value written by
+/// another client in between, so writes are only replayed if
+/// `replay_writes` is set. Otherwise, the requester gets the error.
+///
+/// Runs until it receives `Command::Shutdown` or every sender is dropped.
+async fn run_manager<F, Fut>(
+    mut connector: Connector<F>,
+    mut rx: mpsc::Receiver<Command>,
+    state_tx: watch::Sender<ConnectionState>,
+    replay_writes: bool,
+) where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = mini_redis::Result<Client>>,
+{
+    // Open a connection to the mini-redis address. If this fails, the
+    // manager keeps running so that requesters get an error instead of
+    // waiting forever.
+    let mut client = connector.connect(&state_tx).await;
+
+    while let Some(cmd) = rx.recv().await {
+        let mut cmd = match cmd {
+            Command::Shutdown => break,
+            // Leave the `Failed` state by starting a new round of attempts.
+            Command::Reset => {
+                if client.is_none() {
+                    client = connector.connect(&state_tx).await;
+                }
+                continue;
+            }
+            cmd => cmd,
+        };
+        let mut replayed = false;
+
+        loop {
+            let conn = match client.as_mut() {
+                Some(conn) => conn,
+                None => {
+                    fail(cmd, || ConnectionFailed.into());
+                    break;
+                }
+            };
+
+            // The command failed because the connection itself broke, as
+            // opposed to the server answering with an error. Reconnect, so
+            // this command, if it is replayed, and the next ones run against
+            // a fresh connection.
+            let replay = !replayed && cmd.is_replayable(replay_writes);
+
+            let retry = match issue(conn, cmd, replay).await {
+                Issued::Answered => break,
+                Issued::Broken(retry) => retry,
+            };
+
+            eprintln!("reconnecting to mini-redis");
+            client = connector.connect(&state_tx).await;
+
+            match retry {
+                Some(retry) => {
+                    cmd = retry;
+                    replayed = true;
+                }
+                None => break,
+            }
+        }
+    }
+
+    // Dropping the client closes the connection.
+    drop(client);
+}
+
+/// Returns a responder standing in for `resp`. The response sent on it is
+/// forwarded to `resp`.
+///
+/// `in_flight` is released once the response is forwarded, once the manager
+/// dropped the responder without answering, or once the requester dropped its
+/// receiver: a requester that stops waiting no longer counts against the
+/// limit, even if the manager has yet to get to its command.
+fn track<T: Send + 'static>(mut resp: Responder<T>, in_flight: InFlight) -> Responder<T> {
+    let (tx, rx) = oneshot::channel();
+
+    tokio::spawn(async move {
+        let res = tokio::select! {
+            res = rx => res.ok(),
+            _ = resp.closed() => None,
+        };
+
+        drop(in_flight);
+
+        if let Some(res) = res {
+            let _ = resp.send(res);
+        }
     });
 
-    // Spawn two tasks, each setting a value
+    tx
+}
+
+/// Yields the responses received on `receivers` as they arrive, each with the
+/// index of its receiver in `receivers`.
+///
+/// A requester that fires several commands at once handles each response as
+/// soon as it comes in, instead of in the order the commands were sent. A
+/// receiver whose responder was dropped without answering yields
+/// `Err(RecvError)`. The stream ends once every receiver yielded.
+fn collect_responses<T>(
+    receivers: Vec<oneshot::Receiver<T>>,
+) -> impl Stream<Item = (usize, Result<T, RecvError>)> + Unpin {
+    receivers
+        .into_iter()
+        .enumerate()
+        .map(|(i, rx)| rx.map(move |res| (i, res)))
+        .collect::<FuturesUnordered<_>>()
+}
+
+/// Routes commands to the manager that owns their key.
+///
+/// A single manager funnels every command through one connection. Running
+/// several managers, each with its own connection, and always sending a given
+/// key to the same one spreads the load while keeping the commands for any one
+/// key in order.
+///
+/// Commands sent with `dispatch` count as in flight until their requester got
+/// the response, or stopped waiting for it. Past `max_in_flight`, new commands
+/// are rejected with `Overloaded` instead of piling up in the managers'
+/// queues. Clones share the count.
+#[derive(Clone)]
+struct Shards {
+    senders: Vec<mpsc::Sender<Command>>,
+    in_flight: Arc<AtomicUsize>,
+    max_in_flight: usize,
+}
+
+/// A command counted as in flight. The count is decremented when dropped.
+struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Shards {
+    /// Routes commands to no manager yet, accepting at most `max_in_flight`
+    /// of them at a time.
+    fn new(max_in_flight: usize) -> Shards {
+        Shards {
+            senders: Vec::new(),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            max_in_flight,
+        }
+    }
+
+    /// Returns the index of the manager that owns `key`.
+    fn shard(&self, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+
+        (hasher.finish() % self.senders.len() as u64) as usize
+    }
+
+    /// Returns the sender of the manager that owns `key`.
+    fn sender(&mut self, key: &str) -> &mut mpsc::Sender<Command> {
+        let idx = self.shard(key);
+        &mut self.senders[idx]
+    }
+
+    /// Send `cmd`, a `Get` or a `Set`, to the manager that owns its key.
+    ///
+    /// If too many commands are in flight, `cmd` is answered with
+    /// `Overloaded` right away. Fails if the manager is gone.
+    async fn dispatch(&mut self, cmd: Command) -> Result<(), mpsc::error::SendError<Command>> {
+        let in_flight = match self.acquire() {
+            Some(in_flight) => in_flight,
+            None => {
+                fail(cmd, || Overloaded.into());
+                return Ok(());
+            }
+        };
+
+        let (key, cmd) = match cmd {
+            Command::Get { key, resp } => (
+                key.clone(),
+                Command::Get {
+                    key,
+                    resp: track(resp, in_flight),
+                },
+            ),
+            Command::Set { key, val, resp } => (
+                key.clone(),
+                Command::Set {
+                    key,
+                    val,
+                    resp: track(resp, in_flight),
+                },
+            ),
+            _ => unreachable!("only GET and SET are dispatched"),
+        };
+
+        self.sender(&key).send(cmd).await
+    }
+
+    /// Count one more command as in flight, unless the limit is reached.
+    fn acquire(&self) -> Option<InFlight> {
+        self.in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                if n < self.max_in_flight {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| InFlight(self.in_flight.clone()))
+    }
+
+    /// Tell every manager to stop.
+    async fn shutdown(&mut self) {
+        for tx in &mut self.senders {
+            let _ = tx.send(Command::Shutdown).await;
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let mut shards = Shards::new(MAX_IN_FLIGHT);
+    let mut managers = Vec::with_capacity(NUM_SHARDS);
+
+    for i in 0..NUM_SHARDS {
+        let (tx, rx) = mpsc::channel(32);
+        let (state_tx, mut state_rx) = watch::channel(ConnectionState::Connecting);
+
+        // Report the connection state as the manager moves through it. When
+        // the manager gives up, give the server some time to come back, then
+        // reset the manager.
+        let mut reset_tx = tx.clone();
+        tokio::spawn(async move {
+            while let Some(state) = state_rx.recv().await {
+                println!("shard {}: connection state = {:?}", i, state);
+
+                if state == ConnectionState::Failed {
+                    time::delay_for(RESET_DELAY).await;
+
+                    if reset_tx.send(Command::Reset).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        let connector = Connector {
+            attempt: || connect_with_timeout(ADDR, CONNECT_TIMEOUT),
+            max_attempts: MAX_CONNECT_ATTEMPTS,
+            initial_backoff: INITIAL_BACKOFF,
+            max_backoff: MAX_BACKOFF,
+        };
+
+        shards.senders.push(tx);
+        managers.push(tokio::spawn(run_manager(
+            connector,
+            rx,
+            state_tx,
+            REPLAY_WRITES,
+        )));
+    }
+
+    // Each requester gets its own copy of the senders
+    let mut shards1 = shards.clone();
+    let mut shards2 = shards.clone();
+    let mut shards3 = shards.clone();
+
+    // Spawn two tasks, one getting a key, the other setting a value
     let t1 = tokio::spawn(async move {
         let (resp_tx, resp_rx) = oneshot::channel();
         let cmd = Command::Get {
@@ -55,14 +625,24 @@ async fn main() {
         };
 
         // Send the GET request
-        if tx.send(cmd).await.is_err() {
+        if shards1.dispatch(cmd).await.is_err() {
             eprintln!("connection task shutdown");
             return;
         }
 
-        // Await the response
-        let res = resp_rx.await;
-        println!("GOT = {:?}", res);
+        // Await the response, but not forever: the manager may be stuck on a
+        // dead connection. When the timeout fires, `resp_rx` is dropped and
+        // the manager's `send` fails, so it knows the requester gave up.
+        match time::timeout(REQUEST_TIMEOUT, resp_rx).await {
+            // The manager answered and the command succeeded.
+            Ok(Ok(Ok(Some(val)))) => println!("GET hello = {:?}", val),
+            Ok(Ok(Ok(None))) => println!("GET hello: no value"),
+            // The manager answered, but the command failed.
+            Ok(Ok(Err(err))) => eprintln!("GET hello failed; err = {}", err),
+            // The manager dropped the responder without answering.
+            Ok(Err(_)) => eprintln!("GET hello: manager dropped the request"),
+            Err(elapsed) => eprintln!("GET hello: {}", elapsed),
+        }
     });
 
     let t2 = tokio::spawn(async move {
@@ -74,17 +654,445 @@ async fn main() {
         };
 
         // Send the SET request
-        if tx2.send(cmd).await.is_err() {
+        if shards2.dispatch(cmd).await.is_err() {
             eprintln!("connection task shutdown");
             return;
         }
 
         // Await the response
-        let res = resp_rx.await;
-        println!("GOT = {:?}", res)
+        match time::timeout(REQUEST_TIMEOUT, resp_rx).await {
+            Ok(Ok(Ok(()))) => println!("SET foo = bar"),
+            Ok(Ok(Err(err))) => eprintln!("SET foo failed; err = {}", err),
+            Ok(Err(_)) => eprintln!("SET foo: manager dropped the request"),
+            Err(elapsed) => eprintln!("SET foo: {}", elapsed),
+        }
+    });
+
+    // A third task fires several GETs at once, and handles the responses as
+    // they come in.
+    let t3 = tokio::spawn(async move {
+        let keys = ["hello", "foo"];
+        let mut receivers = Vec::with_capacity(keys.len());
+
+        for key in &keys {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let cmd = Command::Get {
+                key: key.to_string(),
+                resp: resp_tx,
+            };
+
+            if shards3.dispatch(cmd).await.is_err() {
+                eprintln!("connection task shutdown");
+                return;
+            }
+
+            receivers.push(resp_rx);
+        }
+
+        let mut responses = collect_responses(receivers);
+
+        loop {
+            match time::timeout(REQUEST_TIMEOUT, responses.next()).await {
+                Ok(Some((i, Ok(Ok(val))))) => println!("GET {} = {:?}", keys[i], val),
+                Ok(Some((i, Ok(Err(err))))) => eprintln!("GET {} failed; err = {}", keys[i], err),
+                Ok(Some((i, Err(_)))) => eprintln!("GET {}: manager dropped the request", keys[i]),
+                Ok(None) => break,
+                Err(elapsed) => {
+                    eprintln!("GET {:?}: {}", keys, elapsed);
+                    break;
+                }
+            }
+        }
     });
 
     t1.await.unwrap();
     t2.await.unwrap();
-    manager.await.unwrap();
+    t3.await.unwrap();
+
+    // `t1`, `t2` and `t3` dropped their senders when they finished. Had those been
+    // the last senders, `rx.recv()` would return `None` and the managers
+    // would exit on their own. `shards` is still alive, so tell the managers
+    // to stop, then drop the senders.
+    shards.shutdown().await;
+    drop(shards);
+
+    // Wait for the managers to close their connections. A panic in a manager
+    // surfaces here.
+    for manager in managers {
+        manager.await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mini_redis::server;
+    use net2::TcpBuilder;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    use std::collections::HashSet;
+    use std::net::{self, SocketAddr};
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    /// A mini-redis server running in the background.
+    struct Server {
+        addr: SocketAddr,
+        shutdown: oneshot::Sender<()>,
+        handle: JoinHandle<mini_redis::Result<()>>,
+    }
+
+    impl Server {
+        /// Starts a server listening on `addr`. Port 0 picks a free port.
+        async fn start(addr: SocketAddr) -> Server {
+            let listener = TcpListener::bind(addr).await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let (shutdown, stop) = oneshot::channel();
+            let handle = tokio::spawn(server::run(listener, stop));
+
+            Server {
+                addr,
+                shutdown,
+                handle,
+            }
+        }
+
+        /// Stops the server. Its connections are closed by the time this
+        /// returns.
+        async fn stop(self) {
+            let _ = self.shutdown.send(());
+            self.handle.await.unwrap().unwrap();
+        }
+    }
+
+    /// Spawns a manager connected to the server at `addr`. It retries a few
+    /// times in quick succession when the connection breaks.
+    fn spawn_manager(
+        addr: SocketAddr,
+        replay_writes: bool,
+    ) -> (mpsc::Sender<Command>, JoinHandle<()>) {
+        let connector = Connector {
+            attempt: move || connect_with_timeout(addr, CONNECT_TIMEOUT),
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_millis(200),
+        };
+        let (tx, rx) = mpsc::channel(8);
+        let (state_tx, _) = watch::channel(ConnectionState::Connecting);
+
+        (
+            tx,
+            tokio::spawn(run_manager(connector, rx, state_tx, replay_writes)),
+        )
+    }
+
+    /// Sends `cmd`, built around a fresh responder, and waits for the answer.
+    async fn request<T>(
+        tx: &mut mpsc::Sender<Command>,
+        cmd: impl FnOnce(Responder<T>) -> Command,
+    ) -> mini_redis::Result<T> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(cmd(resp_tx)).await.unwrap();
+        resp_rx.await.unwrap()
+    }
+
+    fn get(key: &str) -> impl FnOnce(Responder<Option<Bytes>>) -> Command {
+        let key = key.to_string();
+        move |resp| Command::Get { key, resp }
+    }
+
+    fn set(key: &str, val: &str) -> impl FnOnce(Responder<()>) -> Command {
+        let key = key.to_string();
+        let val = val.as_bytes().to_vec();
+        move |resp| Command::Set { key, val, resp }
+    }
+
+    #[test]
+    fn connection_errors_are_told_apart_from_server_errors() {
+        let reset: mini_redis::Result<()> =
+            Err(
+                io::Error::new(io::ErrorKind::ConnectionReset, "connection reset by server").into(),
+            );
+        let reset_mid_frame: mini_redis::Result<()> = Err("connection reset by peer".into());
+        let rejected: mini_redis::Result<()> = Err("ERR unknown command".into());
+
+        assert!(is_connection_error(&reset));
+        assert!(is_connection_error(&reset_mid_frame));
+        assert!(!is_connection_error(&rejected));
+        assert!(!is_connection_error(&Ok(())));
+    }
+
+    #[tokio::test]
+    async fn reconnects_once_the_server_is_back() {
+        // Bind to a free port, then release it for the server.
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server = Server::start(addr).await;
+
+        let (mut tx, manager) = spawn_manager(addr, false);
+
+        request(&mut tx, set("hello", "world")).await.unwrap();
+
+        // Kill the server. Writes are not replayed: the command that runs
+        // into the closed connection fails, and the manager starts
+        // reconnecting.
+        server.stop().await;
+        assert!(request(&mut tx, set("hello", "again")).await.is_err());
+
+        // Once the server is restored, commands succeed again. The new server
+        // starts with an empty database.
+        let server = Server::start(addr).await;
+        assert_eq!(request(&mut tx, get("hello")).await.unwrap(), None);
+        request(&mut tx, set("hello", "again")).await.unwrap();
+        assert_eq!(
+            request(&mut tx, get("hello")).await.unwrap(),
+            Some(Bytes::from("again"))
+        );
+
+        tx.send(Command::Shutdown).await.unwrap();
+        manager.await.unwrap();
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn commands_hit_by_a_disconnect_are_replayed_after_reconnecting() {
+        let server = Server::start("127.0.0.1:0".parse().unwrap()).await;
+        let addr = server.addr;
+
+        let (mut tx, manager) = spawn_manager(addr, true);
+        request(&mut tx, set("hello", "world")).await.unwrap();
+
+        // Kill the server, then send commands. Both run into the closed
+        // connection, and are held until the manager reconnects.
+        server.stop().await;
+
+        let mut requester = tx.clone();
+        let pending = tokio::spawn(async move {
+            let set = request(&mut requester, set("hello", "again")).await;
+            let get = request(&mut requester, get("hello")).await;
+            (set, get)
+        });
+
+        let server = Server::start(addr).await;
+
+        // The new server starts with an empty database, so the value can only
+        // come from the replayed `SET`.
+        let (set, get) = pending.await.unwrap();
+        set.unwrap();
+        assert_eq!(get.unwrap(), Some(Bytes::from("again")));
+
+        tx.send(Command::Shutdown).await.unwrap();
+        manager.await.unwrap();
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_attempt_until_reset() {
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let recorded = attempts.clone();
+
+        // Every attempt fails right away, as if the server refused it.
+        let connector = Connector {
+            attempt: move || {
+                recorded.lock().unwrap().push(Instant::now());
+                async { Err::<Client, mini_redis::Error>("connection refused".into()) }
+            },
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_secs(1),
+        };
+
+        let (mut tx, rx) = mpsc::channel(8);
+        let (state_tx, state_rx) = watch::channel(ConnectionState::Connecting);
+        let manager = tokio::spawn(run_manager(connector, rx, state_tx, false));
+
+        // The manager only gets to the command once it gave up connecting.
+        assert!(request(&mut tx, get("hello")).await.is_err());
+        assert_eq!(*state_rx.borrow(), ConnectionState::Failed);
+
+        // Delays double from one attempt to the next, and each one is at least
+        // half of its nominal value.
+        let times = attempts.lock().unwrap().clone();
+        assert_eq!(times.len(), 4);
+        for (i, pair) in times.windows(2).enumerate() {
+            let min = Duration::from_millis(10) * 2u32.pow(i as u32);
+            assert!(
+                pair[1] - pair[0] >= min,
+                "delay {} is shorter than {:?}",
+                i,
+                min
+            );
+        }
+
+        // Still failed: commands are answered with an error, without any new
+        // attempt.
+        assert!(request(&mut tx, get("hello")).await.is_err());
+        assert_eq!(attempts.lock().unwrap().len(), 4);
+
+        // A reset makes the manager go through its attempts again.
+        tx.send(Command::Reset).await.unwrap();
+        assert!(request(&mut tx, get("hello")).await.is_err());
+        assert_eq!(attempts.lock().unwrap().len(), 8);
+        assert_eq!(*state_rx.borrow(), ConnectionState::Failed);
+
+        tx.send(Command::Shutdown).await.unwrap();
+        manager.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connecting_to_an_unreachable_server_times_out() {
+        // A listener whose accept queue is full ignores new connection
+        // requests, like an unreachable host would. An unroutable address
+        // does not work everywhere: some networks answer for any address.
+        let listener = TcpBuilder::new_v4()
+            .unwrap()
+            .bind("127.0.0.1:0")
+            .unwrap()
+            .listen(0)
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _queued = net::TcpStream::connect(addr).unwrap();
+
+        let start = Instant::now();
+
+        match connect_with_timeout(addr, Duration::from_millis(100)).await {
+            Ok(_) => panic!("connected to a full listener"),
+            Err(err) => assert!(err.is::<ConnectTimeout>(), "unexpected error: {}", err),
+        }
+
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn commands_past_the_in_flight_limit_are_rejected() {
+        // No manager: the test answers the commands itself.
+        let (tx, mut rx) = mpsc::channel(8);
+        let mut shards = Shards::new(2);
+        shards.senders.push(tx);
+
+        let mut responses = vec![];
+        for i in 0..3 {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            shards
+                .dispatch(get(&format!("key-{}", i))(resp_tx))
+                .await
+                .unwrap();
+            responses.push(resp_rx);
+        }
+
+        let err = responses.pop().unwrap().await.unwrap().unwrap_err();
+        assert!(err.is::<Overloaded>(), "unexpected error: {}", err);
+
+        // The commands in flight still complete.
+        for _ in 0..2 {
+            match rx.recv().await.unwrap() {
+                Command::Get { key, resp } => resp.send(Ok(Some(Bytes::from(key)))).unwrap(),
+                cmd => panic!("unexpected command: {:?}", cmd),
+            }
+        }
+        for (i, resp_rx) in responses.into_iter().enumerate() {
+            let val = resp_rx.await.unwrap().unwrap();
+            assert_eq!(val, Some(Bytes::from(format!("key-{}", i))));
+        }
+
+        // Answered commands no longer count.
+        assert_eq!(shards.in_flight.load(Ordering::SeqCst), 0);
+
+        // Neither do abandoned ones, even before the manager gets to them.
+        let (resp_tx, resp_rx) = oneshot::channel();
+        shards.dispatch(get("abandoned")(resp_tx)).await.unwrap();
+        assert_eq!(shards.in_flight.load(Ordering::SeqCst), 1);
+        drop(resp_rx);
+
+        let released = async {
+            while shards.in_flight.load(Ordering::SeqCst) != 0 {
+                time::delay_for(Duration::from_millis(1)).await;
+            }
+        };
+        time::timeout(Duration::from_secs(1), released)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn shards_route_each_key_to_its_manager() {
+        let server = Server::start("127.0.0.1:0".parse().unwrap()).await;
+        let addr = server.addr;
+
+        let mut shards = Shards::new(MAX_IN_FLIGHT);
+        let mut managers = vec![];
+
+        for _ in 0..2 {
+            let (tx, manager) = spawn_manager(addr, false);
+            shards.senders.push(tx);
+            managers.push(manager);
+        }
+
+        let keys: Vec<_> = (0..8).map(|i| format!("key-{}", i)).collect();
+
+        // The keys are spread over both shards.
+        let used: HashSet<_> = keys.iter().map(|key| shards.shard(key)).collect();
+        assert_eq!(used.len(), 2);
+
+        for key in &keys {
+            request(shards.sender(key), set(key, key)).await.unwrap();
+        }
+        for key in &keys {
+            let val = request(shards.sender(key), get(key)).await.unwrap();
+            assert_eq!(val, Some(Bytes::from(key.clone())));
+        }
+
+        shards.shutdown().await;
+        for manager in managers {
+            manager.await.unwrap();
+        }
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn responses_are_collected_in_completion_order() {
+        // No manager: the test answers each `GET` once as many milliseconds
+        // as its key says have elapsed.
+        let (mut tx, mut rx) = mpsc::channel(8);
+
+        tokio::spawn(async move {
+            while let Some(cmd) = rx.recv().await {
+                match cmd {
+                    Command::Get { key, resp } => {
+                        tokio::spawn(async move {
+                            time::delay_for(Duration::from_millis(key.parse().unwrap())).await;
+                            let _ = resp.send(Ok(Some(Bytes::from(key))));
+                        });
+                    }
+                    cmd => panic!("unexpected command: {:?}", cmd),
+                }
+            }
+        });
+
+        let mut receivers = vec![];
+        for key in &["150", "50", "100"] {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            tx.send(get(key)(resp_tx)).await.unwrap();
+            receivers.push(resp_rx);
+        }
+
+        let responses: Vec<_> = collect_responses(receivers)
+            .map(|(i, res)| (i, res.unwrap().unwrap().unwrap()))
+            .collect()
+            .await;
+
+        assert_eq!(
+            responses,
+            [
+                (1, Bytes::from("50")),
+                (2, Bytes::from("100")),
+                (0, Bytes::from("150")),
+            ]
+        );
+    }
 }
@@ -1,36 +1,103 @@
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Connections that send nothing for this long are closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    let mut listener = TcpListener::bind("127.0.0.1:6142").await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:6142").await.unwrap();
+
+    serve(listener, IDLE_TIMEOUT).await
+}
 
+/// Accepts connections on `listener` and echoes back whatever they send.
+/// Connections idle for `idle_timeout` are closed.
+async fn serve(mut listener: TcpListener, idle_timeout: Duration) -> io::Result<()> {
     loop {
-        let (mut socket, _) = listener.accept().await?;
-
-        tokio::spawn(async move {
-            let mut buf = vec![0; 1024];
-
-            loop {
-                match socket.read(&mut buf).await {
-                    // Return value of `Ok(0)` signifies that the remote has
-                    // closed
-                    Ok(0) => return,
-                    Ok(n) => {
-                        // Copy the data back to socket
-                        if socket.write_all(&buf[..n]).await.is_err() {
-                            // Unexpected socket error. There isn't much we can
-                            // do here so just stop processing.
-                            return;
-                        }
-                    }
-                    Err(_) => {
-                        // Unexpected socket error. There isn't much we can do
-                        // here so just stop processing.
-                        return;
-                    }
+        let (socket, addr) = listener.accept().await?;
+
+        tokio::spawn(echo(socket, addr, idle_timeout));
+    }
+}
+
+async fn echo(mut socket: TcpStream, addr: SocketAddr, idle_timeout: Duration) {
+    let mut buf = vec![0; 1024];
+
+    loop {
+        // Each read gets its own timeout, so the idle period starts over
+        // whenever the peer sends data. A peer that connects and then goes
+        // quiet would otherwise hold the task and its socket forever.
+        let res = match time::timeout(idle_timeout, socket.read(&mut buf)).await {
+            Ok(res) => res,
+            Err(_) => {
+                eprintln!("closing idle connection; addr = {}", addr);
+                return;
+            }
+        };
+
+        match res {
+            // Return value of `Ok(0)` signifies that the remote has
+            // closed
+            Ok(0) => return,
+            Ok(n) => {
+                // Copy the data back to socket
+                if socket.write_all(&buf[..n]).await.is_err() {
+                    // Unexpected socket error. There isn't much we can
+                    // do here so just stop processing.
+                    return;
                 }
             }
-        });
+            Err(_) => {
+                // Unexpected socket error. There isn't much we can do
+                // here so just stop processing.
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_IDLE_TIMEOUT: Duration = Duration::from_millis(200);
+
+    // Starts a server on a free port and connects to it.
+    async fn connect() -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(serve(listener, TEST_IDLE_TIMEOUT));
+
+        TcpStream::connect(addr).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn echoes_until_the_connection_goes_idle() {
+        let mut client = connect().await;
+        let mut buf = [0; 5];
+
+        // Sending data restarts the idle period: the connection outlives
+        // several idle timeouts as long as the client keeps talking.
+        for _ in 0..4 {
+            client.write_all(b"hello").await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            time::delay_for(TEST_IDLE_TIMEOUT / 2).await;
+        }
+
+        // Once the client goes quiet, the server closes the connection.
+        let n = time::timeout(TEST_IDLE_TIMEOUT * 10, client.read(&mut buf))
+            .await
+            .expect("the server did not close the idle connection")
+            .unwrap();
+
+        assert_eq!(n, 0);
     }
 }
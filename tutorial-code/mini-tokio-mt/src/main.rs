@@ -0,0 +1,565 @@
+//! A multi-threaded, work-stealing variant of mini-tokio.
+//!
+//! mini-tokio queues every scheduled task in a single channel. That is easy to
+//! follow, but all worker threads contend on the same queue. Tokio's
+//! multi-threaded scheduler instead gives each worker its own local queue and
+//! only falls back to shared state when a worker runs out of work. This file
+//! shows the three pieces that make that work:
+//!
+//! * Per-worker local queues. A task woken or spawned on a worker thread is
+//!   pushed onto that worker's queue, which no other thread pushes to.
+//! * A global injector queue. Tasks spawned from outside the runtime land
+//!   here, and any worker may take them.
+//! * Work stealing. A worker with an empty local queue and an empty injector
+//!   takes tasks from another worker's queue.
+//!
+//! A worker prefers its local queue, but must not ignore the injector for too
+//! long: tasks that keep waking each other could fill the local queue forever,
+//! starving the tasks waiting in the injector. Every `global_queue_interval`
+//! tasks, the worker checks the injector first.
+
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::iter;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+// A utility that allows us to implement a `std::task::Waker` without having to
+// use `unsafe` code.
+use futures::task::{self, ArcWake};
+// Work-stealing queues. `Worker` is a queue owned by one thread, `Stealer`
+// takes tasks from another thread's `Worker`, and `Injector` is a queue any
+// thread can push to and take from.
+use crossbeam::deque::{Injector, Steal, Stealer, Worker};
+
+// Main entry point. A mini-tokio instance with four workers is created. The
+// root task spawns a batch of tasks from one worker; the other workers get to
+// run them by stealing, or by taking them from the injector once their timers
+// fire.
+fn main() {
+    // Create the mini-tokio instance.
+    let mini_tokio = Builder::new().worker_threads(4).build();
+
+    mini_tokio.spawn(async {
+        // All of these land on the local queue of the worker running the root
+        // task.
+        for i in 0..16 {
+            spawn(async move {
+                for _ in 0..3 {
+                    // Wait without blocking the worker, which runs other
+                    // tasks meanwhile. The timer thread wakes the task, which
+                    // goes to the injector.
+                    delay(Duration::from_millis(5)).await;
+
+                    // Requeue the task on the local queue of the worker that
+                    // took it, where an idle worker may steal it.
+                    yield_now().await;
+                }
+
+                println!(
+                    "task {} finished on {}",
+                    i,
+                    thread::current().name().unwrap()
+                );
+            });
+        }
+    });
+
+    // Start the workers. `run` returns once every task has completed.
+    mini_tokio.run();
+
+    println!("all tasks completed");
+}
+
+/// Builds a mini-tokio instance with custom settings.
+struct Builder {
+    worker_threads: usize,
+    global_queue_interval: u32,
+}
+
+/// A multi-threaded executor with work stealing.
+struct MiniTokio {
+    // State shared by all workers.
+    shared: Arc<Shared>,
+
+    // One local queue per worker. They are created here, rather than in `run`,
+    // so that `Shared` can hold their stealers from the start.
+    workers: Vec<Worker<Arc<Task>>>,
+}
+
+// State shared by all workers and by every task's waker.
+struct Shared {
+    // Tasks spawned from outside the runtime.
+    injector: Injector<Arc<Task>>,
+
+    // Steal handles for every worker's local queue.
+    stealers: Vec<Stealer<Arc<Task>>>,
+
+    // Number of tasks that have not completed. The workers exit once it
+    // reaches zero.
+    live: AtomicUsize,
+
+    // Number of tasks a worker runs in between two checks of the injector.
+    global_queue_interval: u32,
+
+    // Idle workers sleep on `wakeup`. The mutex does not protect any data; it
+    // only orders "check for work, then sleep" against "push work, then
+    // notify" so a notification cannot slip in between the two.
+    sleep: Mutex<()>,
+    wakeup: Condvar,
+}
+
+impl Builder {
+    /// Returns a builder with the default settings: four workers, checking
+    /// the injector every 31 tasks, as Tokio does.
+    fn new() -> Builder {
+        Builder {
+            worker_threads: 4,
+            global_queue_interval: 31,
+        }
+    }
+
+    /// Sets the number of worker threads.
+    fn worker_threads(&mut self, num_workers: usize) -> &mut Builder {
+        assert!(num_workers > 0, "at least one worker is required");
+        self.worker_threads = num_workers;
+        self
+    }
+
+    /// Sets how many tasks a worker runs in between two checks of the
+    /// injector. Lower values are fairer to the tasks in the injector, higher
+    /// values touch shared state less often.
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn global_queue_interval(&mut self, interval: u32) -> &mut Builder {
+        assert!(interval > 0, "interval must be non-zero");
+        self.global_queue_interval = interval;
+        self
+    }
+
+    /// Creates the configured mini-tokio instance.
+    fn build(&self) -> MiniTokio {
+        let workers: Vec<_> = (0..self.worker_threads)
+            .map(|_| Worker::new_fifo())
+            .collect();
+
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: workers.iter().map(Worker::stealer).collect(),
+            live: AtomicUsize::new(0),
+            global_queue_interval: self.global_queue_interval,
+            sleep: Mutex::new(()),
+            wakeup: Condvar::new(),
+        });
+
+        MiniTokio { shared, workers }
+    }
+}
+
+impl MiniTokio {
+    /// Spawn a future onto the mini-tokio instance.
+    ///
+    /// The calling thread is not a worker, so the task goes to the injector.
+    /// The future will be executed when `run` is called.
+    fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        Task::spawn(future, &self.shared);
+    }
+
+    /// Run the executor.
+    ///
+    /// One thread is started per worker. Returns once every spawned task has
+    /// completed.
+    fn run(self) {
+        let MiniTokio { shared, workers } = self;
+
+        let threads: Vec<_> = workers
+            .into_iter()
+            .enumerate()
+            .map(|(i, local)| {
+                let shared = shared.clone();
+
+                thread::Builder::new()
+                    .name(format!("worker-{}", i))
+                    .spawn(move || run_worker(shared, local))
+                    .unwrap()
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+}
+
+// The worker loop. Runs on each worker thread.
+fn run_worker(shared: Arc<Shared>, local: Worker<Arc<Task>>) {
+    // Store the worker's context in the CURRENT thread-local so that `spawn`
+    // and wakers called from this thread can push to the local queue.
+    CURRENT.with(|cell| {
+        *cell.borrow_mut() = Some(Current {
+            shared: shared.clone(),
+            local,
+            tick: Cell::new(0),
+        });
+    });
+
+    while let Some(task) = shared.next_task() {
+        // Execute the task until it either completes or cannot make further
+        // progress and returns `Poll::Pending`.
+        task.poll();
+    }
+}
+
+impl Shared {
+    // Returns the next task for the current worker to run, sleeping while
+    // there is none. Returns `None` once every task has completed.
+    fn next_task(&self) -> Option<Arc<Task>> {
+        loop {
+            if let Some(task) = self.find_task() {
+                return Some(task);
+            }
+
+            let guard = self.sleep.lock().unwrap();
+
+            if self.live.load(Ordering::SeqCst) == 0 {
+                return None;
+            }
+
+            // Check again while holding the lock. Anyone pushing a task takes
+            // the lock before notifying, so either the task is visible here or
+            // the notification arrives after `wait` releases the lock.
+            if self.is_empty() {
+                let _guard = self.wakeup.wait(guard).unwrap();
+            }
+        }
+    }
+
+    // Look for a task, cheapest source first.
+    fn find_task(&self) -> Option<Arc<Task>> {
+        CURRENT.with(|cell| {
+            let borrow = cell.borrow();
+            let current = borrow.as_ref().unwrap();
+            let local = &current.local;
+
+            let tick = current.tick.get().wrapping_add(1);
+            current.tick.set(tick);
+
+            // Every so often, take a task from the injector first, even if the
+            // local queue is not empty. Otherwise, tasks that keep rescheduling
+            // themselves on the local queue would starve the injector.
+            if tick % self.global_queue_interval == 0 {
+                let task = iter::repeat_with(|| self.injector.steal())
+                    .find(|s| !s.is_retry())
+                    .and_then(Steal::success);
+
+                if task.is_some() {
+                    return task;
+                }
+            }
+
+            // Pop a task from the local queue, if not empty.
+            local.pop().or_else(|| {
+                // Otherwise, we need to look for a task elsewhere.
+                iter::repeat_with(|| {
+                    // Try stealing a batch of tasks from the injector. The
+                    // first one is returned and the rest go to the local
+                    // queue, so the next few tasks do not touch shared state.
+                    self.injector
+                        .steal_batch_and_pop(local)
+                        // Or try stealing a task from one of the other workers.
+                        .or_else(|| self.stealers.iter().map(Stealer::steal).collect())
+                })
+                // Loop while no task was stolen and any steal operation needs
+                // to be retried.
+                .find(|s| !s.is_retry())
+                // Extract the stolen task, if there is one.
+                .and_then(Steal::success)
+            })
+        })
+    }
+
+    // Returns `true` if no queue holds a task.
+    fn is_empty(&self) -> bool {
+        self.injector.is_empty() && self.stealers.iter().all(Stealer::is_empty)
+    }
+
+    // Queue a task to be polled.
+    //
+    // On a worker thread of this runtime, the task goes to that worker's local
+    // queue. Elsewhere, for example when a timer thread or a task running on
+    // another runtime wakes it, the task goes to the injector.
+    fn schedule(self: &Arc<Self>, task: Arc<Task>) {
+        CURRENT.with(|cell| match &*cell.borrow() {
+            Some(current) if Arc::ptr_eq(&current.shared, self) => current.local.push(task),
+            _ => self.injector.push(task),
+        });
+
+        // Wake one idle worker. If the task went to a local queue, the woken
+        // worker steals it while the current worker is busy.
+        let _guard = self.sleep.lock().unwrap();
+        self.wakeup.notify_one();
+    }
+
+    // Called when a task completes. The last task to complete wakes every idle
+    // worker so that they see `live == 0` and exit.
+    fn complete(&self) {
+        if self.live.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let _guard = self.sleep.lock().unwrap();
+            self.wakeup.notify_all();
+        }
+    }
+}
+
+// An equivalent to `tokio::spawn`. On a worker thread, the task goes to that
+// worker's local queue. The function panics if called outside of a worker
+// thread.
+fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let shared = CURRENT.with(|cell| {
+        let borrow = cell.borrow();
+        borrow.as_ref().unwrap().shared.clone()
+    });
+
+    Task::spawn(future, &shared);
+}
+
+// Yields execution back to the worker. The task is rescheduled right away, so
+// other tasks get a chance to run, and idle workers a chance to steal it.
+async fn yield_now() {
+    struct YieldNow {
+        yielded: bool,
+    }
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.yielded {
+                return Poll::Ready(());
+            }
+
+            self.yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    YieldNow { yielded: false }.await;
+}
+
+// Asynchronous equivalent to `thread::sleep`. Calling `thread::sleep` in a task
+// would block the worker, and every task queued behind it, for the whole
+// duration.
+//
+// As in the single-threaded mini-tokio, a timer thread is spawned per call. It
+// sleeps for the requested duration, then wakes the task.
+async fn delay(dur: Duration) {
+    struct Delay {
+        // When to complete the delay.
+        when: Instant,
+        // The waker to notify once the delay has completed. Shared with the
+        // timer thread.
+        waker: Option<Arc<Mutex<Waker>>>,
+    }
+
+    impl Future for Delay {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if let Some(waker) = &self.waker {
+                // The task may have been polled with a different waker since
+                // the timer thread started. Keep the latest one.
+                let mut waker = waker.lock().unwrap();
+
+                if !waker.will_wake(cx.waker()) {
+                    *waker = cx.waker().clone();
+                }
+            } else {
+                let when = self.when;
+                let waker = Arc::new(Mutex::new(cx.waker().clone()));
+                self.waker = Some(waker.clone());
+
+                thread::spawn(move || {
+                    let now = Instant::now();
+
+                    if now < when {
+                        thread::sleep(when - now);
+                    }
+
+                    waker.lock().unwrap().wake_by_ref();
+                });
+            }
+
+            if Instant::now() >= self.when {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    Delay {
+        when: Instant::now() + dur,
+        waker: None,
+    }
+    .await;
+}
+
+// The context of the worker running on the current thread.
+struct Current {
+    // The runtime the worker belongs to.
+    shared: Arc<Shared>,
+
+    // The worker's local queue. Only this thread pushes to or pops from it;
+    // other workers take tasks through the matching `Stealer`.
+    local: Worker<Arc<Task>>,
+
+    // Number of times the worker looked for a task. Used to check the
+    // injector every `global_queue_interval` tasks.
+    tick: Cell<u32>,
+}
+
+// Used to track the current worker so that `spawn` and wakers are able to
+// schedule tasks onto its local queue.
+thread_local! {
+    static CURRENT: RefCell<Option<Current>> = const { RefCell::new(None) };
+}
+
+// Task harness. Contains the future as well as the necessary data to schedule
+// the future once it is woken.
+struct Task {
+    // The future is `None` once it has completed.
+    //
+    // Unlike in the single-threaded mini-tokio, two workers may try to poll the
+    // same task: a task that wakes itself while being polled is queued again,
+    // and another worker can steal it before the first poll returns. The mutex
+    // makes the second worker wait for the first. Tokio avoids this with a
+    // per-task state machine that never lets a task be queued while it is
+    // running.
+    future: Mutex<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+
+    // The runtime the task belongs to.
+    shared: Arc<Shared>,
+}
+
+impl Task {
+    // Spawns a new task with the given future.
+    fn spawn<F>(future: F, shared: &Arc<Shared>)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(Box::pin(future))),
+            shared: shared.clone(),
+        });
+
+        shared.live.fetch_add(1, Ordering::SeqCst);
+        shared.schedule(task);
+    }
+
+    // Execute a scheduled task.
+    fn poll(self: Arc<Self>) {
+        // Get a waker referencing the task.
+        let waker = task::waker(self.clone());
+        // Initialize the task context with the waker.
+        let mut cx = Context::from_waker(&waker);
+
+        let mut slot = self.future.lock().unwrap();
+
+        // A task can be queued more than once. If an earlier poll completed it,
+        // there is nothing left to do.
+        if let Some(future) = slot.as_mut() {
+            if future.as_mut().poll(&mut cx).is_ready() {
+                *slot = None;
+                self.shared.complete();
+            }
+        }
+    }
+}
+
+impl ArcWake for Task {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.shared.schedule(arc_self.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicBool;
+    use std::sync::mpsc;
+
+    #[test]
+    fn the_injector_is_checked_while_local_tasks_keep_running() {
+        const INTERVAL: u32 = 4;
+
+        let mini_tokio = Builder::new()
+            .worker_threads(1)
+            .global_queue_interval(INTERVAL)
+            .build();
+        let shared = mini_tokio.shared.clone();
+
+        let injected = Arc::new(AtomicBool::new(false));
+        let done = Arc::new(AtomicBool::new(false));
+        // Polls of the local tasks once the injected task was visible.
+        let polls_after = Arc::new(AtomicUsize::new(0));
+        let (started_tx, started_rx) = mpsc::channel();
+
+        {
+            let injected = injected.clone();
+            let done = done.clone();
+            let polls_after = polls_after.clone();
+
+            mini_tokio.spawn(async move {
+                // Two tasks yielding in turn: the local queue is never empty.
+                for _ in 0..2 {
+                    let injected = injected.clone();
+                    let done = done.clone();
+                    let polls_after = polls_after.clone();
+
+                    spawn(async move {
+                        for _ in 0..10_000 {
+                            if done.load(Ordering::SeqCst) {
+                                return;
+                            }
+                            if injected.load(Ordering::SeqCst) {
+                                polls_after.fetch_add(1, Ordering::SeqCst);
+                            }
+                            yield_now().await;
+                        }
+                    });
+                }
+
+                started_tx.send(()).unwrap();
+            });
+        }
+
+        // Spawned from a plain thread, the task goes to the injector.
+        let injector = thread::spawn(move || {
+            started_rx.recv().unwrap();
+
+            // Set first, so that every poll made once the task is queued is
+            // counted.
+            injected.store(true, Ordering::SeqCst);
+            Task::spawn(
+                async move {
+                    done.store(true, Ordering::SeqCst);
+                },
+                &shared,
+            );
+        });
+
+        mini_tokio.run();
+        injector.join().unwrap();
+
+        assert!(polls_after.load(Ordering::SeqCst) <= INTERVAL as usize);
+    }
+}
@@ -0,0 +1,35 @@
+use mini_tokio::{delay, mpsc, spawn, MiniTokio};
+
+use std::time::Duration;
+
+// A fast producer and a slow consumer connected by a channel holding two
+// values. Once the channel is full, `send().await` parks the producer until
+// the consumer makes room: the producer ends up running at the consumer's
+// pace.
+fn main() {
+    let mini_tokio = MiniTokio::new();
+
+    mini_tokio.block_on(async {
+        let (tx, mut rx) = mpsc::channel(2);
+
+        spawn(async move {
+            for i in 0..6 {
+                println!("producer: sending {}", i);
+                tx.send(i).await.unwrap();
+                println!("producer: sent {}", i);
+            }
+
+            // Dropping `tx` closes the channel once the consumer has received
+            // every value.
+        });
+
+        while let Some(i) = rx.recv().await {
+            println!("consumer: received {}", i);
+
+            // Simulate slow processing.
+            delay(Duration::from_millis(50)).await;
+        }
+
+        println!("consumer: channel closed");
+    });
+}
@@ -0,0 +1,304 @@
+use futures::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use mini_tokio::{
+    noop_context, select2, spawn, timeout, CancellationToken, Either, Handle, JoinError,
+    JoinHandle, JoinSet, MiniTcpListener, MiniTcpStream, MiniTokio, RuntimeMetrics,
+};
+
+use std::io;
+use std::net::SocketAddr;
+use std::task::Poll;
+use std::time::Duration;
+
+// How long each connection gets to finish its request once shutdown begins.
+const GRACE: Duration = Duration::from_secs(1);
+
+// An echo server and a client talking to it, both running on one mini-tokio
+// instance. Every read, write, accept and connect below goes through the
+// reactor: when the socket is not ready, the task is parked until the reactor
+// sees it become ready.
+//
+// The server speaks a line protocol: each line is echoed back, except for
+// `STATS`, which is answered with the instance's runtime metrics. With the
+// server listening on a fixed port, `echo STATS | nc 127.0.0.1 <port>` would
+// print them.
+//
+// Once the client is done, the server is shut down: connections idle in
+// between requests are closed, and the ones in the middle of a request get
+// `GRACE` to finish it before they are aborted.
+fn main() {
+    let mini_tokio = MiniTokio::new();
+    let handle = mini_tokio.handle();
+
+    mini_tokio.spawn(async move {
+        let shutdown = CancellationToken::new();
+        let (server, addr) = start(handle.clone(), shutdown.clone());
+
+        // The client: send a few lines and print the replies.
+        let mut stream = BufReader::new(MiniTcpStream::connect(addr).await.unwrap());
+
+        for msg in &["hello", "world"] {
+            stream
+                .get_mut()
+                .write_all(format!("{}\n", msg).as_bytes())
+                .await
+                .unwrap();
+
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            println!("client: got {:?}", line.trim_end());
+        }
+
+        println!("client: stats\n{}", stats(&mut stream).await.unwrap());
+
+        shutdown.cancel();
+        println!("server: {:?}", server.await.unwrap());
+
+        handle.shutdown();
+    });
+
+    mini_tokio.run();
+}
+
+// What `serve` reports once it is done.
+#[derive(Debug, Default)]
+struct Drained {
+    // Connections that closed on their own, or between two requests.
+    closed: usize,
+
+    // Connections still busy with a request at the deadline.
+    aborted: usize,
+}
+
+// Accepts and serves connections until `shutdown` is cancelled.
+//
+// On shutdown, the listener is closed and every connection gets `grace` to
+// finish the request it is serving. Connections idle in between requests
+// close right away. The ones still busy at the deadline are aborted.
+async fn serve(
+    listener: MiniTcpListener,
+    handle: Handle,
+    shutdown: CancellationToken,
+    grace: Duration,
+) -> Drained {
+    let mut connections = JoinSet::new();
+    let mut drained = Drained::default();
+
+    loop {
+        // Reap the connections that closed, so the set only holds live ones.
+        while let Poll::Ready(Some(_)) = connections.poll_join_next(&mut noop_context()) {
+            drained.closed += 1;
+        }
+
+        match select2(listener.accept(), shutdown.cancelled()).await {
+            Either::Left(Ok((socket, peer))) => {
+                println!("server: accepted {}", peer);
+                connections.spawn(serve_connection(socket, handle.clone(), shutdown.clone()));
+            }
+            Either::Left(Err(err)) => eprintln!("server: failed to accept; err = {}", err),
+            Either::Right(()) => break,
+        }
+    }
+
+    // Refuse new connections while the others drain.
+    drop(listener);
+
+    let _ = timeout(grace, async {
+        while connections.join_next().await.is_some() {
+            drained.closed += 1;
+        }
+    })
+    .await;
+
+    // The connections left are past the deadline. A connection completing
+    // in between still counts as closed.
+    connections.abort_all();
+
+    while let Some(res) = connections.join_next().await {
+        match res {
+            Err(JoinError::Cancelled) => drained.aborted += 1,
+            _ => drained.closed += 1,
+        }
+    }
+
+    drained
+}
+
+// Serves one connection, until the peer closes its half of it or shutdown
+// begins while no request is in progress.
+async fn serve_connection(socket: MiniTcpStream, handle: Handle, shutdown: CancellationToken) {
+    let mut socket = BufReader::new(socket);
+    let mut line = String::new();
+
+    loop {
+        // Wait for the next request. `fill_buf` does not consume anything, so
+        // it can be dropped when shutdown wins. It is polled first: a request
+        // that already arrived is served.
+        match select2(socket.fill_buf(), shutdown.cancelled()).await {
+            Either::Left(Ok([])) => return,
+            Either::Left(Ok(_)) => {}
+            Either::Left(Err(err)) => {
+                eprintln!("server: failed to read; err = {}", err);
+                return;
+            }
+            Either::Right(()) => return,
+        }
+
+        // A request is in progress. It is finished even if shutdown begins
+        // now: `serve` aborts the task if it takes too long.
+        line.clear();
+
+        match socket.read_line(&mut line).await {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("server: failed to read; err = {}", err);
+                return;
+            }
+        }
+
+        let reply = match line.trim_end() {
+            "STATS" => format_metrics(&handle.metrics()),
+            _ => line.clone(),
+        };
+
+        if let Err(err) = socket.get_mut().write_all(reply.as_bytes()).await {
+            eprintln!("server: failed to write; err = {}", err);
+            return;
+        }
+    }
+}
+
+// Starts `serve` in the background, on a port picked by the operating system.
+// Returns its task and the address it listens on.
+//
+// Must be called from a task of the instance `handle` refers to.
+fn start(handle: Handle, shutdown: CancellationToken) -> (JoinHandle<Drained>, SocketAddr) {
+    let listener = MiniTcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    (spawn(serve(listener, handle, shutdown, GRACE)), addr)
+}
+
+// Formats `metrics` as the reply to `STATS`: one `key value` line per metric,
+// followed by an empty line marking the end of the block.
+fn format_metrics(metrics: &RuntimeMetrics) -> String {
+    format!(
+        "tasks_spawned {}\n\
+         tasks_completed {}\n\
+         current_scheduled_len {}\n\
+         total_polls {}\n\
+         total_wakes {}\n\
+         \n",
+        metrics.tasks_spawned,
+        metrics.tasks_completed,
+        metrics.current_scheduled_len,
+        metrics.total_polls,
+        metrics.total_wakes,
+    )
+}
+
+// Sends `STATS` on `stream` and returns the reply, without the empty line
+// ending it.
+async fn stats(stream: &mut BufReader<MiniTcpStream>) -> io::Result<String> {
+    stream.get_mut().write_all(b"STATS\n").await?;
+
+    let mut reply = String::new();
+
+    loop {
+        let mut line = String::new();
+
+        if stream.read_line(&mut line).await? == 0 || line == "\n" {
+            return Ok(reply);
+        }
+
+        reply.push_str(&line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    #[test]
+    fn stats_reports_every_metric() {
+        let mini_tokio = MiniTokio::new();
+        let handle = mini_tokio.handle();
+
+        let reply = mini_tokio.block_on(async move {
+            let (_server, addr) = start(handle, CancellationToken::new());
+            let mut stream = BufReader::new(MiniTcpStream::connect(addr).await.unwrap());
+
+            stats(&mut stream).await.unwrap()
+        });
+
+        let keys: Vec<_> = reply
+            .lines()
+            .map(|line| line.split(' ').next().unwrap())
+            .collect();
+
+        assert_eq!(
+            keys,
+            [
+                "tasks_spawned",
+                "tasks_completed",
+                "current_scheduled_len",
+                "total_polls",
+                "total_wakes",
+            ]
+        );
+
+        // At least the server and the connection's task were spawned.
+        let spawned: u64 = reply
+            .lines()
+            .find_map(|line| line.strip_prefix("tasks_spawned "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(spawned >= 2);
+    }
+
+    #[test]
+    fn shutdown_aborts_the_connections_busy_past_the_deadline() {
+        let mini_tokio = MiniTokio::new();
+        let handle = mini_tokio.handle();
+        let grace = Duration::from_millis(100);
+
+        let (drained, elapsed, idle_closed) = mini_tokio.block_on(async move {
+            let listener = MiniTcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            let addr = listener.local_addr().unwrap();
+            let shutdown = CancellationToken::new();
+            let server = spawn(serve(listener, handle, shutdown.clone(), grace));
+
+            // Both connections complete a request, so both were accepted.
+            let mut idle = BufReader::new(MiniTcpStream::connect(addr).await.unwrap());
+            let mut stuck = BufReader::new(MiniTcpStream::connect(addr).await.unwrap());
+
+            for stream in [&mut idle, &mut stuck] {
+                let mut line = String::new();
+                stream.get_mut().write_all(b"ping\n").await.unwrap();
+                stream.read_line(&mut line).await.unwrap();
+                assert_eq!(line, "ping\n");
+            }
+
+            // The stuck connection starts a request it never finishes.
+            stuck.get_mut().write_all(b"pi").await.unwrap();
+
+            let start = Instant::now();
+            shutdown.cancel();
+            let drained = server.await.unwrap();
+            let elapsed = start.elapsed();
+
+            // The server closed the idle connection.
+            let idle_closed = idle.read_line(&mut String::new()).await.unwrap() == 0;
+
+            (drained, elapsed, idle_closed)
+        });
+
+        assert_eq!(drained.closed, 1);
+        assert_eq!(drained.aborted, 1);
+        assert!(idle_closed);
+        assert!(elapsed >= grace, "aborted after {:?}", elapsed);
+    }
+}
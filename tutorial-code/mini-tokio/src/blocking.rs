@@ -0,0 +1,335 @@
+//! Running blocking code.
+//!
+//! A task must never block: while it does, the worker thread polling it cannot
+//! poll anything else. Some work cannot be made asynchronous though, such as a
+//! long computation or a call into a synchronous library. `spawn_blocking`
+//! moves that work to a separate pool of threads, dedicated to blocking, and
+//! returns a `JoinHandle` that the task can await without blocking.
+
+use crate::join::{self, JoinHandle};
+use crate::{CancellationToken, CURRENT};
+
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Maximum number of threads in the pool. Once all of them are busy, new jobs
+// wait in the queue until a thread is available.
+const MAX_THREADS: usize = 16;
+
+// How long an idle thread waits for a new job before exiting.
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+// Runs `f` on the blocking thread pool of the current mini-tokio instance. The
+// returned `JoinHandle` resolves to the value returned by `f`, or to
+// `JoinError::Panic` if `f` panics.
+//
+// Unlike tasks, blocking jobs cannot be interrupted. Shutting down the executor
+// does not stop a job that is running, and the jobs still in the queue are run
+// before the pool's threads exit. Aborting the `JoinHandle` completes it with
+// `JoinError::Cancelled` right away; the job is skipped if it has not started
+// yet, and its output is dropped otherwise.
+//
+// Panics if called outside of a mini-tokio runtime.
+pub fn spawn_blocking<F, R>(f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    current_pool().spawn(|_| f())
+}
+
+// Like `spawn_blocking`, but `f` is given a `CancellationToken` that is
+// cancelled when the returned `JoinHandle` is aborted. A long job can check
+// the token now and then and stop early, instead of running to completion
+// for an output nobody awaits.
+//
+// Panics if called outside of a mini-tokio runtime.
+pub fn spawn_blocking_cancellable<F, R>(f: F) -> JoinHandle<R>
+where
+    F: FnOnce(CancellationToken) -> R + Send + 'static,
+    R: Send + 'static,
+{
+    current_pool().spawn(f)
+}
+
+fn current_pool() -> BlockingHandle {
+    CURRENT.with(|cell| {
+        let borrow = cell.borrow();
+        let handle = borrow
+            .as_ref()
+            .expect("spawn_blocking called outside of a mini-tokio runtime");
+        handle.blocking.clone()
+    })
+}
+
+// The blocking pool of a mini-tokio instance. The pool's threads exit once the
+// pool is dropped and the queue is empty.
+pub(crate) struct Pool {
+    handle: BlockingHandle,
+}
+
+// Used to submit jobs to a blocking pool. Cheap to clone.
+#[derive(Clone)]
+pub(crate) struct BlockingHandle {
+    shared: Arc<Shared>,
+}
+
+// State shared by the pool's threads and the handles.
+struct Shared {
+    state: Mutex<State>,
+
+    // Signaled when a job is queued or the pool shuts down.
+    condvar: Condvar,
+
+    // Name of the pool's threads.
+    thread_name: String,
+}
+
+struct State {
+    // Jobs waiting for a thread.
+    queue: VecDeque<Job>,
+
+    // Number of threads currently running, busy or idle.
+    num_threads: usize,
+
+    // Number of threads waiting for a job.
+    num_idle: usize,
+
+    // Set when the pool is dropped.
+    shutdown: bool,
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+impl Pool {
+    pub(crate) fn new(thread_name: String) -> Pool {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                num_threads: 0,
+                num_idle: 0,
+                shutdown: false,
+            }),
+            condvar: Condvar::new(),
+            thread_name,
+        });
+
+        Pool {
+            handle: BlockingHandle { shared },
+        }
+    }
+
+    pub(crate) fn handle(&self) -> BlockingHandle {
+        self.handle.clone()
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.handle.shared.state.lock().unwrap().shutdown = true;
+        self.handle.shared.condvar.notify_all();
+
+        // The threads are not joined: a blocking job may run for an arbitrary
+        // amount of time, and waiting for it here would block the thread
+        // dropping the runtime.
+    }
+}
+
+impl BlockingHandle {
+    fn spawn<F, R>(&self, f: F) -> JoinHandle<R>
+    where
+        F: FnOnce(CancellationToken) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let token = CancellationToken::new();
+        let (join_tx, join_handle) = join::blocking_channel(token.clone());
+
+        // If `f` panics, `join_tx` is dropped while unwinding and the
+        // `JoinHandle` completes with `JoinError::Panic`.
+        let job: Job = Box::new(move || {
+            // Aborted while queued. The `JoinHandle` is already complete.
+            if token.is_cancelled() {
+                return;
+            }
+
+            join_tx.send(f(token))
+        });
+
+        let mut state = self.shared.state.lock().unwrap();
+        state.queue.push_back(job);
+
+        if state.num_idle > 0 {
+            // An idle thread picks the job up.
+            self.shared.condvar.notify_one();
+        } else if state.num_threads < MAX_THREADS {
+            // All threads are busy, but the pool may grow.
+            state.num_threads += 1;
+            drop(state);
+
+            let shared = self.shared.clone();
+
+            thread::Builder::new()
+                .name(self.shared.thread_name.clone())
+                .spawn(move || shared.run())
+                .expect("failed to spawn a blocking thread");
+        }
+
+        // Otherwise, the job waits in the queue until a thread is done with
+        // its current job.
+
+        join_handle
+    }
+}
+
+impl Shared {
+    // The loop executed by each thread of the pool.
+    fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if let Some(job) = state.queue.pop_front() {
+                drop(state);
+
+                // A panicking job must not take the thread down. The panic
+                // has already been reported to the `JoinHandle`.
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+
+                state = self.state.lock().unwrap();
+                continue;
+            }
+
+            if state.shutdown {
+                break;
+            }
+
+            // Wait for a new job. Threads that stay idle for too long exit,
+            // so a burst of blocking jobs does not leave threads behind.
+            state.num_idle += 1;
+            let (next, timeout) = self.condvar.wait_timeout(state, KEEP_ALIVE).unwrap();
+            state = next;
+            state.num_idle -= 1;
+
+            if timeout.timed_out() && state.queue.is_empty() {
+                break;
+            }
+        }
+
+        state.num_threads -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        spawn, spawn_blocking, spawn_blocking_cancellable, yield_now, JoinError, MiniTokio,
+    };
+
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn tasks_make_progress_while_a_job_blocks() {
+        let mini_tokio = MiniTokio::new();
+        let (progress_tx, progress_rx) = mpsc::channel();
+
+        let unblocked = mini_tokio.block_on(async move {
+            // The job blocks its thread until the task below has run.
+            let job = spawn_blocking(move || progress_rx.recv_timeout(Duration::from_secs(5)));
+
+            spawn(async move {
+                for i in 0..3 {
+                    yield_now().await;
+                    progress_tx.send(i).unwrap();
+                }
+            });
+
+            job.await.unwrap()
+        });
+
+        assert_eq!(unblocked.unwrap(), 0);
+    }
+
+    #[test]
+    fn a_completed_job_unparks_an_idle_worker() {
+        let mini_tokio = MiniTokio::new_multi_thread(2);
+        let (release_tx, release_rx) = mpsc::channel();
+
+        // Releases the job once both workers had time to park.
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            let released = Instant::now();
+            release_tx.send(()).unwrap();
+            released
+        });
+
+        let completed = mini_tokio.block_on(async move {
+            spawn(async move {
+                spawn_blocking(move || release_rx.recv().unwrap())
+                    .await
+                    .unwrap();
+                Instant::now()
+            })
+            .await
+            .unwrap()
+        });
+
+        let released = releaser.join().unwrap();
+        assert!(completed.duration_since(released) < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn aborting_a_running_job_completes_its_handle() {
+        let mini_tokio = MiniTokio::new();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let (started_tx, started_rx) = mpsc::channel();
+
+        let output = mini_tokio.block_on(async move {
+            let handle = spawn_blocking(move || {
+                started_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+                1
+            });
+
+            started_rx.recv().unwrap();
+            handle.abort();
+
+            // The job is still blocked, but the handle does not wait for it.
+            handle.await
+        });
+
+        assert!(matches!(output, Err(JoinError::Cancelled)));
+        release_tx.send(()).unwrap();
+    }
+
+    #[test]
+    fn aborting_cancels_the_token_of_the_job() {
+        let mini_tokio = MiniTokio::new();
+        let (started_tx, started_rx) = mpsc::channel();
+        let (stopped_tx, stopped_rx) = mpsc::channel();
+
+        let output = mini_tokio.block_on(async move {
+            let handle = spawn_blocking_cancellable(move |token| {
+                started_tx.send(()).unwrap();
+
+                while !token.is_cancelled() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+
+                stopped_tx.send(()).unwrap();
+            });
+
+            started_rx.recv().unwrap();
+            handle.abort();
+            handle.await
+        });
+
+        assert!(matches!(output, Err(JoinError::Cancelled)));
+
+        // The job noticed and stopped early.
+        stopped_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+}
@@ -0,0 +1,306 @@
+//! Configuring a mini-tokio instance.
+
+use crate::{Clock, MiniTokio, SystemClock};
+
+use futures::future::BoxFuture;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Builds a mini-tokio instance with custom settings.
+///
+/// This is the mini-tokio equivalent of `tokio::runtime::Builder`. The
+/// defaults match `MiniTokio::new`: tasks are executed on the thread calling
+/// `run`, the scheduled channel is unbounded, and timers and I/O are enabled.
+#[derive(Clone)]
+pub struct Builder {
+    pub(crate) worker_threads: usize,
+    pub(crate) queue_capacity: Option<usize>,
+    pub(crate) thread_name_prefix: String,
+    pub(crate) enable_timer: bool,
+    pub(crate) enable_io: bool,
+    pub(crate) park_on_io: bool,
+    pub(crate) event_interval: u32,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) spawn_hook: Option<SpawnHook>,
+    pub(crate) max_poll_duration: Option<Duration>,
+}
+
+// Wraps the future of every task spawned on an instance. See
+// `Builder::spawn_hook`.
+pub(crate) type SpawnHook =
+    Arc<dyn Fn(BoxFuture<'static, ()>) -> BoxFuture<'static, ()> + Send + Sync>;
+
+impl Builder {
+    /// Returns a builder with the default settings.
+    pub fn new() -> Builder {
+        Builder {
+            worker_threads: 1,
+            queue_capacity: None,
+            thread_name_prefix: "mini-tokio".to_string(),
+            enable_timer: true,
+            enable_io: true,
+            park_on_io: false,
+            event_interval: 61,
+            clock: Arc::new(SystemClock),
+            spawn_hook: None,
+            max_poll_duration: None,
+        }
+    }
+
+    /// Sets the number of threads executing tasks. See
+    /// `MiniTokio::new_multi_thread`.
+    ///
+    /// With a single worker, the default, tasks are executed on the thread
+    /// calling `run` and no worker thread is spawned.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `num_workers` is zero.
+    pub fn worker_threads(&mut self, num_workers: usize) -> &mut Builder {
+        assert!(num_workers > 0, "at least one worker is required");
+        self.worker_threads = num_workers;
+        self
+    }
+
+    /// Bounds the scheduled channel to `capacity` tasks. See
+    /// `MiniTokio::with_capacity`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn queue_capacity(&mut self, capacity: usize) -> &mut Builder {
+        assert!(capacity > 0, "capacity must be non-zero");
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// Sets the prefix of the names of the threads spawned by the instance.
+    ///
+    /// Worker threads are named `{prefix}-worker-{n}`, the timer thread is
+    /// named `{prefix}-timer`, the I/O thread is named `{prefix}-io` and
+    /// blocking threads are named `{prefix}-blocking`. The default prefix is
+    /// `mini-tokio`.
+    pub fn thread_name_prefix(&mut self, prefix: &str) -> &mut Builder {
+        self.thread_name_prefix = prefix.to_string();
+        self
+    }
+
+    /// Enables or disables timers.
+    ///
+    /// Without timers, no timer thread is spawned, and using `delay` or any
+    /// other timer on the instance panics.
+    pub fn enable_timer(&mut self, enable: bool) -> &mut Builder {
+        self.enable_timer = enable;
+        self
+    }
+
+    /// Enables or disables I/O.
+    ///
+    /// With I/O enabled, the reactor thread is only spawned once the first
+    /// `MiniTcpListener` or `MiniTcpStream` is created. Without I/O, creating
+    /// one on the instance panics.
+    pub fn enable_io(&mut self, enable: bool) -> &mut Builder {
+        self.enable_io = enable;
+        self
+    }
+
+    /// Makes the worker wait for I/O events and fire the timers itself.
+    ///
+    /// By default, the reactor and the timer driver run on threads of their
+    /// own, and wake the worker through the scheduled channel. With this
+    /// setting, neither thread is spawned. Once the worker runs out of
+    /// tasks, it waits for I/O events for as long as the nearest timer
+    /// allows, wakes the tasks whose sockets became ready, fires the expired
+    /// timers, and goes back to running tasks. This is how a minimal runtime
+    /// parks, and how each Tokio worker does.
+    ///
+    /// A task woken from another thread, such as a blocking thread, wakes
+    /// the waiting worker through the reactor.
+    ///
+    /// # Panics
+    ///
+    /// `build` panics if this is set on an instance with more than one worker,
+    /// or with I/O disabled.
+    pub fn park_on_io(&mut self, enable: bool) -> &mut Builder {
+        self.park_on_io = enable;
+        self
+    }
+
+    /// Sets how many tasks the worker polls before checking for I/O events,
+    /// when it parks on I/O. Defaults to 61, like Tokio.
+    ///
+    /// With `park_on_io`, the worker only waits for I/O events and fires
+    /// timers once it runs out of tasks. A task that keeps waking itself, by
+    /// calling `yield_now` in a loop for example, never lets it run out: the
+    /// sockets that became ready and the expired timers would never be
+    /// noticed. So every `interval` tasks, the worker also checks for I/O
+    /// events and expired timers, without waiting. A lower value gets I/O
+    /// serviced sooner, a higher one leaves more time for tasks.
+    ///
+    /// Without `park_on_io`, the reactor and timer threads wake the tasks
+    /// through the scheduled channel, behind the tasks already queued, and
+    /// this setting has no effect.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is zero.
+    pub fn event_interval(&mut self, interval: u32) -> &mut Builder {
+        assert!(interval > 0, "`interval` must be non-zero");
+        self.event_interval = interval;
+        self
+    }
+
+    /// Sets the clock the timers read the current time from. Defaults to
+    /// `SystemClock`.
+    ///
+    /// Tests may pass a `MockClock` to control time: timers then fire when the
+    /// clock is advanced, instead of after some real time has passed.
+    pub fn clock(&mut self, clock: impl Clock) -> &mut Builder {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Sets a function wrapping the future of every task spawned on the
+    /// instance.
+    ///
+    /// The hook is called once per task, when the task is spawned, on the
+    /// thread spawning it. It is given the task's future and returns the
+    /// future the task runs instead, which usually awaits the original one.
+    /// This is the place for concerns shared by every task, such as setting a
+    /// task-local or instrumenting the future. The hook is not involved in
+    /// polling: a wrapper that needs to act on every poll implements `Future`
+    /// itself.
+    ///
+    /// The future given to the hook completes once the task's output has been
+    /// handed to its `JoinHandle`. It is also used for the tasks spawned on
+    /// the instance's behalf, such as the cleanup of `defer_async`, but not
+    /// for the tasks of a `LocalSet`.
+    pub fn spawn_hook<F>(&mut self, hook: F) -> &mut Builder
+    where
+        F: Fn(BoxFuture<'static, ()>) -> BoxFuture<'static, ()> + Send + Sync + 'static,
+    {
+        self.spawn_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Aborts tasks whose poll runs for longer than `limit`.
+    ///
+    /// A watchdog thread, named `{prefix}-watchdog`, checks the polls in
+    /// progress. Once one exceeds `limit`, the task's `JoinHandle` completes
+    /// with `JoinError::Stuck` right away, and the task is aborted. A poll
+    /// cannot be interrupted though: the worker stays blocked until the poll
+    /// returns, and the future is only dropped then. This is a safety valve
+    /// for futures that block by mistake, not a substitute for
+    /// `spawn_blocking`.
+    ///
+    /// The tasks of a `LocalSet` are not watched.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is zero.
+    pub fn max_poll_duration(&mut self, limit: Duration) -> &mut Builder {
+        assert!(limit > Duration::ZERO, "limit must be non-zero");
+        self.max_poll_duration = Some(limit);
+        self
+    }
+
+    /// Creates the configured mini-tokio instance.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `park_on_io` is set along with more than one worker, or with
+    /// I/O disabled.
+    pub fn build(&self) -> MiniTokio {
+        if self.park_on_io {
+            assert!(
+                self.worker_threads == 1,
+                "`park_on_io` requires a single worker"
+            );
+            assert!(self.enable_io, "`park_on_io` requires I/O to be enabled");
+        }
+
+        MiniTokio::from_builder(self)
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Builder {
+        Builder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{spawn, Builder};
+
+    use futures::FutureExt;
+
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    #[test]
+    fn spawn_hook_wraps_every_task() {
+        let completed = Arc::new(AtomicUsize::new(0));
+        let hook_completed = completed.clone();
+
+        let mini_tokio = Builder::new()
+            .spawn_hook(move |future| {
+                let completed = hook_completed.clone();
+
+                async move {
+                    future.await;
+                    completed.fetch_add(1, Ordering::SeqCst);
+                }
+                .boxed()
+            })
+            .build();
+
+        for i in 0..5 {
+            mini_tokio.spawn(async move { i });
+        }
+
+        mini_tokio.run_until_idle();
+
+        assert_eq!(completed.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn workers_are_named_after_the_prefix() {
+        let mini_tokio = Builder::new()
+            .worker_threads(2)
+            .thread_name_prefix("named")
+            .build();
+
+        let names = mini_tokio.block_on(async {
+            // Both tasks block until the other one runs, so each of them
+            // needs a worker of its own.
+            let barrier = Arc::new(Barrier::new(2));
+
+            let handles: Vec<_> = (0..2)
+                .map(|_| {
+                    let barrier = barrier.clone();
+
+                    spawn(async move {
+                        barrier.wait();
+                        thread::current().name().map(str::to_string)
+                    })
+                })
+                .collect();
+
+            let mut names = HashSet::new();
+            for handle in handles {
+                names.insert(handle.await.unwrap().unwrap());
+            }
+            names
+        });
+
+        let expected: HashSet<_> = vec!["named-worker-0", "named-worker-1"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(names, expected);
+    }
+}
@@ -0,0 +1,248 @@
+//! Cooperative cancellation.
+//!
+//! Aborting a task drops its future at its next `.await`, wherever that is.
+//! Some work cannot be stopped that way: a blocking job runs on a thread of its
+//! own and is never polled, and a task may want to finish what it is doing and
+//! clean up rather than be cut short. That work has to be *asked* to stop. A
+//! `CancellationToken` carries the request: one side calls `cancel`, the other
+//! checks `is_cancelled` or awaits `cancelled`.
+//!
+//! Tokens form a tree: cancelling a token also cancels the tokens derived from
+//! it with `child_token`. A request handler can hand child tokens to the tasks
+//! it spawns, which hand their own children to the tasks they spawn, and
+//! cancelling the request's token stops all of them. `spawn_child` does the
+//! plumbing.
+
+use crate::{coop, select2, spawn, Either, JoinHandle};
+
+use std::future::{self, Future};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+
+/// A request to stop, shared between the code cancelling and the code being
+/// cancelled. The mini-tokio equivalent of
+/// `tokio_util::sync::CancellationToken`.
+///
+/// Clones share the same state: cancelling one cancels all of them.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    cancelled: bool,
+
+    // The tasks waiting in `cancelled`.
+    wakers: Vec<Waker>,
+
+    // The tokens created by `child_token`. They are only referenced weakly,
+    // so that a child that is dropped does not stay around until its parent
+    // is cancelled.
+    children: Vec<Weak<Mutex<State>>>,
+}
+
+/// Spawns the future returned by `f` as a task that is cancelled along with
+/// `token`.
+///
+/// `f` is given a child of `token`, to pass on to the tasks it spawns, with
+/// `spawn_child` again. Cancelling `token` then cancels the whole tree of
+/// tasks. A cancelled task's future is dropped and its `JoinHandle` resolves to
+/// `None`.
+///
+/// # Panics
+///
+/// Like `spawn`, panics if called from outside of a mini-tokio runtime.
+pub fn spawn_child<F, Fut>(token: &CancellationToken, f: F) -> JoinHandle<Option<Fut::Output>>
+where
+    F: FnOnce(CancellationToken) -> Fut,
+    Fut: Future + Send + 'static,
+    Fut::Output: Send + 'static,
+{
+    let child = token.child_token();
+    let future = f(child.clone());
+
+    spawn(async move {
+        // The token is polled first, so a task cancelled at the same time it
+        // completes still counts as cancelled.
+        match select2(child.cancelled(), future).await {
+            Either::Left(()) => None,
+            Either::Right(output) => Some(output),
+        }
+    })
+}
+
+impl CancellationToken {
+    /// Creates a token that is not cancelled.
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Cancels the token, waking every task waiting in `cancelled`.
+    ///
+    /// Cancelling a token more than once has no additional effect.
+    pub fn cancel(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.cancelled {
+            return;
+        }
+
+        state.cancelled = true;
+        let wakers = std::mem::take(&mut state.wakers);
+        let children = std::mem::take(&mut state.children);
+        drop(state);
+
+        for waker in wakers {
+            waker.wake();
+        }
+
+        // Each child cancels its own children in turn.
+        for state in children.iter().filter_map(Weak::upgrade) {
+            CancellationToken { state }.cancel();
+        }
+    }
+
+    /// Returns a token that is cancelled when this one is.
+    ///
+    /// Cancelling the child does not cancel its parent. If this token is
+    /// already cancelled, so is the child.
+    pub fn child_token(&self) -> CancellationToken {
+        let child = CancellationToken::new();
+        let mut state = self.state.lock().unwrap();
+
+        if state.cancelled {
+            drop(state);
+            child.cancel();
+        } else {
+            state.children.retain(|child| child.strong_count() > 0);
+            state.children.push(Arc::downgrade(&child.state));
+        }
+
+        child
+    }
+
+    /// Returns `true` if the token has been cancelled.
+    ///
+    /// This is what a blocking job checks every now and then.
+    pub fn is_cancelled(&self) -> bool {
+        self.state.lock().unwrap().cancelled
+    }
+
+    /// Waits until the token is cancelled.
+    pub async fn cancelled(&self) {
+        future::poll_fn(|cx| self.poll_cancelled(cx)).await
+    }
+
+    fn poll_cancelled(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.lock().unwrap();
+
+        if !state.cancelled {
+            // A waker is only stored once per task. A future that is dropped
+            // before the token is cancelled leaves its waker behind, which is
+            // released when the token is cancelled or dropped.
+            if !state.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                state.wakers.push(cx.waker().clone());
+            }
+
+            return Poll::Pending;
+        }
+
+        coop::poll_proceed(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::spawn_child;
+    use crate::{oneshot, CancellationToken, MiniTokio};
+
+    use std::future;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    struct SetOnDrop(Arc<AtomicBool>);
+
+    impl Drop for SetOnDrop {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn cancelling_wakes_the_waiting_tasks() {
+        let mini_tokio = MiniTokio::new();
+        let token = CancellationToken::new();
+
+        let (waiters, started): (Vec<_>, Vec<_>) = (0..3)
+            .map(|_| {
+                let token = token.clone();
+                let (started_tx, started_rx) = oneshot::channel();
+
+                let waiter = mini_tokio.spawn(async move {
+                    let _ = started_tx.send(());
+                    token.cancelled().await
+                });
+
+                (waiter, started_rx)
+            })
+            .unzip();
+
+        // Each task parks on the token in the same poll it reports starting.
+        for started_rx in started {
+            mini_tokio.block_on(started_rx).unwrap();
+        }
+
+        assert!(waiters.iter().all(|waiter| !waiter.is_finished()));
+
+        token.cancel();
+        mini_tokio.run_until_idle();
+
+        assert!(token.is_cancelled());
+        assert!(waiters.iter().all(|waiter| waiter.is_finished()));
+    }
+
+    #[test]
+    fn cancelling_a_child_leaves_its_parent_alone() {
+        let parent = CancellationToken::new();
+        let child = parent.child_token();
+        let grandchild = child.child_token();
+
+        child.cancel();
+        assert!(grandchild.is_cancelled());
+        assert!(!parent.is_cancelled());
+
+        // A child of a cancelled token starts out cancelled.
+        assert!(child.child_token().is_cancelled());
+    }
+
+    #[test]
+    fn cancelling_the_parent_cancels_a_grandchild_task() {
+        let mini_tokio = MiniTokio::new();
+        let token = CancellationToken::new();
+        let dropped = Arc::new(AtomicBool::new(false));
+        let (grandchild_tx, grandchild_rx) = oneshot::channel();
+
+        let guard = SetOnDrop(dropped.clone());
+        let outputs = mini_tokio.block_on(async {
+            let child = spawn_child(&token, move |token| async move {
+                let grandchild = spawn_child(&token, move |_| async move {
+                    let _guard = guard;
+                    future::pending::<()>().await
+                });
+                let _ = grandchild_tx.send(grandchild);
+
+                future::pending::<()>().await
+            });
+
+            let grandchild = grandchild_rx.await.unwrap();
+            assert!(!dropped.load(Ordering::SeqCst));
+
+            token.cancel();
+            (child.await.unwrap(), grandchild.await.unwrap())
+        });
+
+        assert_eq!(outputs, (None, None));
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+}
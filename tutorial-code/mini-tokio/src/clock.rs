@@ -0,0 +1,153 @@
+//! Sources of time.
+//!
+//! The timers of a mini-tokio instance read the current time from a `Clock`.
+//! By default, that is `SystemClock`, which returns `Instant::now()`. Code
+//! using timers is then hard to test: a test of a 60 second timeout takes 60
+//! seconds, and a test checking that a 10ms delay completes before a 20ms one
+//! fails whenever the machine is busy.
+//!
+//! A `MockClock` only moves forward when told to. A runtime built with a
+//! `MockClock` fires its timers when the clock is advanced past their
+//! deadline, without any real waiting.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A source of time for the timers of a mini-tokio instance. Set with
+/// `Builder::clock`.
+pub trait Clock: Send + Sync + 'static {
+    /// Returns the current instant.
+    fn now(&self) -> Instant;
+
+    /// Registers a function to call whenever the clock jumps forward.
+    ///
+    /// The timer driver sleeps until the next deadline, as measured in real
+    /// time. A clock that does not follow real time must call `notify` when
+    /// it moves. `notify` fires the timers that expired before returning. The default
+    /// implementation never calls `notify`, which is correct for clocks
+    /// following real time.
+    fn on_advance(&self, notify: Box<dyn Fn() + Send + Sync>) {
+        let _ = notify;
+    }
+}
+
+/// The real-time clock, used by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+/// A clock that only moves when `advance` is called.
+///
+/// The clock starts at the instant it is created. Clones share the same time.
+#[derive(Clone)]
+pub struct MockClock {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    now: Mutex<Instant>,
+
+    // Called by `advance`. See `Clock::on_advance`.
+    listeners: Mutex<Vec<Box<dyn Fn() + Send + Sync>>>,
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl MockClock {
+    /// Returns a clock frozen at the current instant.
+    pub fn new() -> MockClock {
+        MockClock {
+            inner: Arc::new(Inner {
+                now: Mutex::new(Instant::now()),
+                listeners: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Moves the clock forward by `dur`.
+    ///
+    /// Timers whose deadline is reached fire before this returns: their tasks
+    /// are woken, and run the next time the executor gets to them.
+    pub fn advance(&self, dur: Duration) {
+        *self.inner.now.lock().unwrap() += dur;
+
+        for notify in self.inner.listeners.lock().unwrap().iter() {
+            notify();
+        }
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.inner.now.lock().unwrap()
+    }
+
+    fn on_advance(&self, notify: Box<dyn Fn() + Send + Sync>) {
+        self.inner.listeners.lock().unwrap().push(notify);
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{delay, oneshot, Builder, Clock, MockClock};
+
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn advancing_moves_every_clone() {
+        let clock = MockClock::new();
+        let start = clock.now();
+
+        clock.clone().advance(Duration::from_secs(60));
+
+        assert_eq!(clock.now() - start, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn advancing_fires_the_timers_without_waiting() {
+        let clock = MockClock::new();
+        let mini_tokio = Builder::new().clock(clock.clone()).build();
+        let real_start = Instant::now();
+
+        let (minute_tx, minute_rx) = oneshot::channel();
+        let (early_started_tx, early_started_rx) = oneshot::channel();
+        let (early_tx, early_rx) = oneshot::channel();
+
+        let minute = mini_tokio.spawn(async move {
+            let _ = minute_tx.send(());
+            delay(Duration::from_secs(60)).await;
+        });
+        mini_tokio.spawn(async move {
+            let _ = early_started_tx.send(());
+            delay(Duration::from_secs(59)).await;
+            let _ = early_tx.send(());
+        });
+
+        // The tasks park on their delay in the same poll they report
+        // starting.
+        mini_tokio.block_on(async {
+            minute_rx.await.unwrap();
+            early_started_rx.await.unwrap();
+        });
+
+        // A second short of the deadline, only the earlier timer fires.
+        clock.advance(Duration::from_secs(59));
+        mini_tokio.block_on(early_rx).unwrap();
+        assert!(!minute.is_finished());
+
+        clock.advance(Duration::from_secs(1));
+        mini_tokio.run_until_idle();
+        assert!(minute.is_finished());
+
+        assert!(real_start.elapsed() < Duration::from_secs(59));
+    }
+}
@@ -0,0 +1,112 @@
+//! Cooperative scheduling.
+//!
+//! The executor only regains control when a task's `poll` returns. A task
+//! whose resources are always ready, such as a loop receiving from a channel
+//! that is never empty, never returns `Poll::Pending` and keeps the worker
+//! busy forever. The other tasks starve.
+//!
+//! To prevent that, each task is given a budget every time it is polled. Leaf
+//! futures call `poll_proceed` before completing, which consumes one unit of
+//! the budget. Once the budget is exhausted, `poll_proceed` returns
+//! `Poll::Pending` even though the resource is ready, forcing the task to
+//! yield back to the executor. This is how Tokio's coop mechanism works.
+
+use std::cell::Cell;
+use std::task::{Context, Poll};
+
+// The number of times a task may make progress before being forced to yield.
+const BUDGET: u8 = 128;
+
+thread_local! {
+    // The budget of the task being polled on this thread. `None` when no task
+    // is being polled, in which case the budget is not enforced.
+    static CURRENT: Cell<Option<u8>> = const { Cell::new(None) };
+}
+
+// Runs `f`, the poll of a task, with a fresh budget.
+pub(crate) fn budget<R>(f: impl FnOnce() -> R) -> R {
+    // Restores the previous budget when dropped, including when `f` panics.
+    struct Reset(Option<u8>);
+
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            CURRENT.with(|cell| cell.set(self.0));
+        }
+    }
+
+    let _reset = Reset(CURRENT.with(|cell| cell.replace(Some(BUDGET))));
+
+    f()
+}
+
+/// Consumes a unit of the current task's budget.
+///
+/// Leaf futures call this once they are ready to complete, before returning
+/// `Poll::Ready`. If this returns `Poll::Pending`, the budget is exhausted:
+/// the future must return `Poll::Pending` too. The task has already been
+/// woken and is polled again once the other scheduled tasks had their turn.
+///
+/// Outside of a mini-tokio task, there is no budget and this always returns
+/// `Poll::Ready`.
+pub fn poll_proceed(cx: &mut Context<'_>) -> Poll<()> {
+    CURRENT.with(|cell| match cell.get() {
+        Some(0) => {
+            // Yield, just like `yield_now`: the task is scheduled again right
+            // away, behind the tasks that are already queued.
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+        Some(remaining) => {
+            cell.set(Some(remaining - 1));
+            Poll::Ready(())
+        }
+        None => Poll::Ready(()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BUDGET;
+
+    use crate::{mpsc, MiniTokio};
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn a_greedy_task_does_not_starve_the_others() {
+        let mini_tokio = MiniTokio::new();
+        let received = Arc::new(AtomicUsize::new(0));
+        let received_then = Arc::new(AtomicUsize::new(usize::MAX));
+
+        let (tx, mut rx) = mpsc::channel(1000);
+
+        mini_tokio.block_on(async {
+            for i in 0..1000 {
+                tx.send(i).await.unwrap();
+            }
+        });
+        drop(tx);
+
+        // Every `recv` is ready right away: without a budget, this task would
+        // never return `Poll::Pending` until the channel is empty.
+        let greedy_received = received.clone();
+        mini_tokio.spawn(async move {
+            while rx.recv().await.is_some() {
+                greedy_received.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        let normal_received = received.clone();
+        let normal_received_then = received_then.clone();
+        mini_tokio.spawn(async move {
+            normal_received_then.store(normal_received.load(Ordering::SeqCst), Ordering::SeqCst);
+        });
+
+        mini_tokio.run_until_idle();
+
+        assert_eq!(received.load(Ordering::SeqCst), 1000);
+        // The other task ran as soon as the greedy one ran out of budget.
+        assert!(received_then.load(Ordering::SeqCst) <= BUDGET as usize);
+    }
+}
@@ -0,0 +1,107 @@
+//! Running asynchronous cleanup when a value goes out of scope.
+//!
+//! Rust has no "async drop": `Drop::drop` is a synchronous function and cannot
+//! `.await` anything. A common workaround is to have the destructor *spawn* the
+//! cleanup as a new task. The cleanup then runs concurrently with whatever
+//! caused the drop instead of being awaited inline.
+
+use crate::{Handle, Task, CURRENT};
+
+use std::future::Future;
+
+/// Guard returned by `defer_async`. Spawns the cleanup future when dropped.
+pub struct DeferAsync<F>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    // The cleanup future. Taken when the guard is dropped.
+    cleanup: Option<F>,
+
+    // Handle to the runtime the guard was created in. It is captured up front
+    // because the guard is not always dropped on an executor thread. For
+    // example, a task's future may be dropped by the timer thread holding the
+    // last reference to the task.
+    handle: Handle,
+}
+
+/// Run `cleanup` on the current runtime once the returned guard is dropped.
+///
+/// The guard is dropped when the enclosing scope exits. This happens whether
+/// the task completes normally, returns early or is itself dropped before
+/// completing. In every case, `cleanup` is spawned as a new, detached task.
+///
+/// The cleanup is **not** awaited by the code dropping the guard. It runs
+/// later, once the executor gets to it. Nothing orders it with respect to the
+/// task that created the guard, and if the runtime is gone by the time the
+/// guard is dropped, the cleanup is dropped without ever running.
+///
+/// # Panics
+///
+/// Panics if called outside of a mini-tokio runtime.
+pub fn defer_async<F>(cleanup: F) -> DeferAsync<F>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let handle = CURRENT.with(|cell| {
+        let borrow = cell.borrow();
+        borrow
+            .as_ref()
+            .expect("defer_async called outside of a mini-tokio runtime")
+            .clone()
+    });
+
+    DeferAsync {
+        cleanup: Some(cleanup),
+        handle,
+    }
+}
+
+impl<F> Drop for DeferAsync<F>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    fn drop(&mut self) {
+        if let Some(cleanup) = self.cleanup.take() {
+            Task::spawn_detached(cleanup, &self.handle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{defer_async, delay, oneshot, Builder, MockClock};
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn cleanup_runs_when_the_task_is_aborted() {
+        let mini_tokio = Builder::new().clock(MockClock::new()).build();
+        let (started_tx, started_rx) = oneshot::channel();
+        let cleaned_up = Arc::new(AtomicBool::new(false));
+
+        let task_cleaned_up = cleaned_up.clone();
+
+        let handle = mini_tokio.spawn(async move {
+            let _guard = defer_async(async move {
+                task_cleaned_up.store(true, Ordering::SeqCst);
+            });
+
+            let _ = started_tx.send(());
+
+            // The clock never moves: only aborting ends this task.
+            delay(Duration::from_secs(60)).await;
+        });
+
+        // The task parks on its delay in the same poll it reports starting.
+        mini_tokio.block_on(started_rx).unwrap();
+
+        handle.abort();
+        mini_tokio.run_until_idle();
+
+        // Dropping the aborted task's future dropped the guard, which spawned
+        // the cleanup, which ran before the executor went idle.
+        assert!(cleaned_up.load(Ordering::SeqCst));
+    }
+}
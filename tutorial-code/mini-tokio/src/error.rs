@@ -0,0 +1,29 @@
+//! Errors returned by the executor.
+
+use std::error::Error;
+use std::fmt;
+
+/// Error returned when a task could not be spawned.
+#[derive(Debug)]
+pub enum SpawnError {
+    /// No mini-tokio runtime is running on the current thread.
+    NotInRuntime,
+
+    /// The scheduled channel of the runtime is full.
+    AtCapacity,
+
+    /// The runtime was shut down, or dropped.
+    Shutdown,
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpawnError::NotInRuntime => "not running within a mini-tokio runtime".fmt(fmt),
+            SpawnError::AtCapacity => "scheduled queue is at capacity".fmt(fmt),
+            SpawnError::Shutdown => "runtime is shut down".fmt(fmt),
+        }
+    }
+}
+
+impl Error for SpawnError {}
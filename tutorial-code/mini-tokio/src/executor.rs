@@ -0,0 +1,190 @@
+//! Traits abstracting over the executor.
+//!
+//! The combinators of the `time` module only need two things from a runtime:
+//! a way to wait for some time, and a way to tell the time. `Timer` captures
+//! both, and `timeout_with` and `interval_with` work with any implementation
+//! of it. `timeout` and `interval` are the same combinators, using the timer
+//! of the current mini-tokio instance. `Spawn` does the same for spawning
+//! tasks.
+//!
+//! Implementing the traits on top of another runtime, such as Tokio, lets the
+//! same combinator code run there.
+
+use crate::time::{self, sleep_until};
+use crate::Handle;
+
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// Runs futures in the background.
+pub trait Spawn {
+    /// Spawns `future`. It runs to completion without being awaited, and its
+    /// output is dropped.
+    fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static;
+}
+
+/// Waits for time to pass.
+pub trait Timer {
+    /// Returns a future completing once `dur` has elapsed, counting from the
+    /// call to `sleep`.
+    fn sleep(&self, dur: Duration) -> impl Future<Output = ()>;
+
+    /// Returns the current instant according to this timer.
+    ///
+    /// The default implementation returns the real time. A timer driven by
+    /// a mock clock returns the clock's time instead.
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    /// Returns a future completing once `deadline` is reached.
+    ///
+    /// The default implementation sleeps for the time left until `deadline`,
+    /// as measured by `now`.
+    fn sleep_until(&self, deadline: Instant) -> impl Future<Output = ()> {
+        self.sleep(deadline.saturating_duration_since(self.now()))
+    }
+}
+
+/// The timer of the current mini-tokio instance.
+///
+/// This is the timer used by `delay`, `timeout` and `interval`. Its futures
+/// must be polled from within a mini-tokio runtime with timers enabled.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RuntimeTimer;
+
+impl Timer for RuntimeTimer {
+    fn sleep(&self, dur: Duration) -> impl Future<Output = ()> {
+        // The deadline is computed now, when `sleep` is called, rather than
+        // when the future is first polled.
+        sleep_until(time::now() + dur)
+    }
+
+    fn now(&self) -> Instant {
+        time::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> impl Future<Output = ()> {
+        sleep_until(deadline)
+    }
+}
+
+impl Spawn for Handle {
+    fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        Handle::spawn(self, future);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::{Spawn, Timer};
+    use crate::{noop_context, oneshot, timeout_with};
+
+    use std::cell::{Cell, RefCell};
+    use std::future::{self, Future};
+    use std::mem;
+    use std::pin::{pin, Pin};
+    use std::task::Poll;
+    use std::time::{Duration, Instant};
+
+    // An executor polling everything on the calling thread, with a clock that
+    // moves 1ms forward every time the root future is pending.
+    struct Inline {
+        now: Cell<Instant>,
+        tasks: RefCell<Vec<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+    }
+
+    impl Inline {
+        fn new() -> Inline {
+            Inline {
+                now: Cell::new(Instant::now()),
+                tasks: RefCell::new(vec![]),
+            }
+        }
+
+        // Polls `future` until it completes. In between, the spawned tasks
+        // are polled once each, then the clock moves.
+        fn block_on<F: Future>(&self, future: F) -> F::Output {
+            let mut future = pin!(future);
+            let mut cx = noop_context();
+
+            loop {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                    return output;
+                }
+
+                let tasks = mem::take(&mut *self.tasks.borrow_mut());
+
+                for mut task in tasks {
+                    if task.as_mut().poll(&mut cx).is_pending() {
+                        self.tasks.borrow_mut().push(task);
+                    }
+                }
+
+                self.now.set(self.now.get() + Duration::from_millis(1));
+            }
+        }
+    }
+
+    impl Spawn for Inline {
+        fn spawn<F>(&self, future: F)
+        where
+            F: Future<Output = ()> + Send + 'static,
+        {
+            self.tasks.borrow_mut().push(Box::pin(future));
+        }
+    }
+
+    impl Timer for Inline {
+        fn sleep(&self, dur: Duration) -> impl Future<Output = ()> {
+            self.sleep_until(self.now() + dur)
+        }
+
+        fn now(&self) -> Instant {
+            self.now.get()
+        }
+
+        fn sleep_until(&self, deadline: Instant) -> impl Future<Output = ()> {
+            future::poll_fn(move |_| {
+                if self.now.get() >= deadline {
+                    return Poll::Ready(());
+                }
+
+                Poll::Pending
+            })
+        }
+    }
+
+    #[test]
+    fn timeout_runs_on_another_executor() {
+        let rt = Inline::new();
+        let start = rt.now();
+
+        // A spawned task answers right away, well before the deadline.
+        let (tx, rx) = oneshot::channel();
+        rt.spawn(async move {
+            let _ = tx.send(42);
+        });
+
+        let answered = rt.block_on(timeout_with(&rt, Duration::from_millis(10), rx));
+        assert_eq!(answered.unwrap().unwrap(), 42);
+        assert!(rt.now() - start < Duration::from_millis(10));
+
+        // A spawned task holding on to the sender without ever sending.
+        let (tx, rx) = oneshot::channel::<i32>();
+        rt.spawn(async move {
+            let _tx = tx;
+            future::pending::<()>().await;
+        });
+
+        let start = rt.now();
+        let silent = rt.block_on(timeout_with(&rt, Duration::from_millis(10), rx));
+        assert!(silent.is_err());
+        assert_eq!(rt.now() - start, Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,155 @@
+//! Detecting when a runtime has nothing left to do.
+//!
+//! An empty scheduled channel does not mean the runtime is idle. A task waiting
+//! on a `delay` is not scheduled, yet it will be once the timer fires. Instead,
+//! the runtime counts the tasks that are still alive: spawned, and not yet
+//! completed nor dropped. Once that count reaches zero, no task can ever be
+//! scheduled again, unless a new one is spawned from outside the runtime.
+
+use std::cell::Cell;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::task::{Context, Poll, Waker};
+
+use crossbeam::channel;
+
+thread_local! {
+    // The live tasks of the runtime whose task is being polled on this thread.
+    // Null when no task is being polled.
+    static POLLING: Cell<*const LiveTasks> = const { Cell::new(ptr::null()) };
+}
+
+// The number of live tasks of a mini-tokio instance, along with the callers
+// waiting for it to reach zero.
+#[derive(Default)]
+pub(crate) struct LiveTasks {
+    count: AtomicUsize,
+
+    // Send halves of channels used to signal that the count reached zero. Like
+    // the shutdown channel, they signal by being dropped.
+    waiters: Mutex<Vec<channel::Sender<()>>>,
+
+    // The tasks waiting in `poll_at_most`. Woken every time the count drops.
+    tasks: Mutex<Vec<Waker>>,
+}
+
+impl LiveTasks {
+    // Called when a task is spawned.
+    pub(crate) fn inc(&self) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // Called once the future of a task is dropped, whether it completed or
+    // not.
+    pub(crate) fn dec(&self) {
+        let previous = self.count.fetch_sub(1, Ordering::SeqCst);
+
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+
+        for waker in tasks {
+            waker.wake();
+        }
+
+        if previous == 1 {
+            // The last live task is gone. Notify the waiters by dropping their
+            // senders.
+            self.waiters.lock().unwrap().clear();
+        }
+    }
+
+    // Returns a channel that becomes disconnected once there are no live tasks.
+    pub(crate) fn idle(&self) -> channel::Receiver<()> {
+        let (tx, rx) = channel::bounded(0);
+
+        // Register the waiter *before* checking the count. If the count reaches
+        // zero after the check, `dec` finds the waiter and drops it. Checking
+        // first could miss that: `dec` could clear the waiters in between the
+        // check and the registration, leaving the waiter hanging.
+        self.waiters.lock().unwrap().push(tx);
+
+        if self.count.load(Ordering::SeqCst) == 0 {
+            self.waiters.lock().unwrap().clear();
+        }
+
+        rx
+    }
+
+    // Returns the number of live tasks.
+    pub(crate) fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    // Completes once there are at most `n` live tasks. Unlike `idle`, this is
+    // for tasks, which must not block.
+    pub(crate) fn poll_at_most(&self, n: usize, cx: &mut Context<'_>) -> Poll<()> {
+        // As in `idle`, the waker is registered, under the lock, before the
+        // count is checked. A `dec` after the check takes the lock after the
+        // registration, and finds the waker.
+        let mut tasks = self.tasks.lock().unwrap();
+
+        if self.count() <= n {
+            return Poll::Ready(());
+        }
+
+        if !tasks.iter().any(|w| w.will_wake(cx.waker())) {
+            tasks.push(cx.waker().clone());
+        }
+
+        Poll::Pending
+    }
+
+    // Runs `f`, the poll of a task counted by `self`.
+    pub(crate) fn polling<R>(&self, f: impl FnOnce() -> R) -> R {
+        // Restores the previous value when dropped, including when `f` panics.
+        struct Reset(*const LiveTasks);
+
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                POLLING.with(|cell| cell.set(self.0));
+            }
+        }
+
+        let _reset = Reset(POLLING.with(|cell| cell.replace(self)));
+
+        f()
+    }
+
+    // Returns `true` if called from the poll of a task counted by `self`.
+    pub(crate) fn is_polling(&self) -> bool {
+        POLLING.with(|cell| ptr::eq(cell.get(), self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{delay, MiniTokio};
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn run_until_idle_waits_for_pending_timers() {
+        let mini_tokio = MiniTokio::new();
+        let printed = Arc::new(AtomicBool::new(false));
+
+        let task_printed = printed.clone();
+
+        mini_tokio.spawn(async move {
+            // While the delay is pending, the scheduled channel is empty, but
+            // the executor is not idle.
+            delay(Duration::from_millis(10)).await;
+            task_printed.store(true, Ordering::SeqCst);
+        });
+
+        mini_tokio.run_until_idle();
+
+        assert!(printed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn run_until_idle_returns_right_away_without_tasks() {
+        MiniTokio::new().run_until_idle();
+    }
+}
@@ -0,0 +1,483 @@
+//! Retrieving the output of a spawned task.
+//!
+//! Spawning a task returns a `JoinHandle`. The task harness and the handle
+//! share a slot: when the task's future completes, its output is stored in the
+//! slot and the handle's waker is notified. Awaiting the handle takes the
+//! output back out.
+
+use crate::{coop, CancellationToken, Task};
+
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+/// An owned permission to await the output of a spawned task.
+///
+/// This is the mini-tokio equivalent of `tokio::task::JoinHandle`. Awaiting it
+/// resolves to the task's output, or to a `JoinError` if the task did not run
+/// to completion.
+///
+/// Dropping a `JoinHandle` does not cancel the task. The task keeps running and
+/// its output is dropped once it completes. Use `abort` to cancel the task.
+///
+/// A `JoinHandle` must not be polled again once it has returned
+/// `Poll::Ready`.
+pub struct JoinHandle<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+    abort: AbortHandle,
+}
+
+/// An owned permission to cancel a spawned task, without awaiting its output.
+///
+/// Returned by `JoinHandle::abort_handle`.
+#[derive(Clone)]
+pub struct AbortHandle {
+    target: Target,
+}
+
+// What an `AbortHandle` cancels.
+#[derive(Clone)]
+enum Target {
+    // A task. The handle does not keep the task alive. Once nothing else
+    // references the task, it can never run again and there is nothing left
+    // to abort.
+    //
+    // Empty for the tasks of a `LocalSet`, which cannot be aborted.
+    Task(Weak<Task>),
+
+    // A job spawned with `spawn_blocking`. A running job cannot be
+    // interrupted: aborting cancels the job's token, which the job may check,
+    // and calls the function, which completes the `JoinHandle` right away.
+    Blocking(CancellationToken, Arc<dyn Fn() + Send + Sync>),
+}
+
+/// Error returned when awaiting a task that did not complete.
+#[derive(Debug)]
+pub enum JoinError {
+    /// The task was dropped before its future completed.
+    Cancelled,
+
+    /// The task's future panicked.
+    Panic,
+
+    /// A poll of the task ran for longer than the instance's
+    /// `Builder::max_poll_duration`, and the task was aborted.
+    Stuck,
+}
+
+// Held by the task harness. Stores the output of the task in the slot shared
+// with the `JoinHandle`.
+pub(crate) struct JoinSender<T> {
+    // Set to `None` once the output has been stored.
+    slot: Option<Arc<Mutex<Slot<T>>>>,
+}
+
+// State shared by a `JoinSender` and its `JoinHandle`.
+struct Slot<T> {
+    // The task's output. Set once by the `JoinSender` and taken by the
+    // `JoinHandle`.
+    output: Option<Result<T, JoinError>>,
+
+    // Set along with `output`. Unlike `output`, it stays set once the
+    // `JoinHandle` has taken the output.
+    finished: bool,
+
+    // The waker of the task awaiting the `JoinHandle`, if any.
+    waker: Option<Waker>,
+
+    // Registered by `JoinHandle::on_complete`. Called with the output instead
+    // of storing it.
+    on_complete: Option<OnComplete<T>>,
+}
+
+type OnComplete<T> = Box<dyn FnOnce(Result<T, JoinError>) + Send>;
+
+// Creates the two halves linking a task to its `JoinHandle`. `task` is the task
+// aborted by the handle.
+pub(crate) fn channel<T>(task: Weak<Task>) -> (JoinSender<T>, JoinHandle<T>) {
+    let slot = Arc::new(Mutex::new(Slot {
+        output: None,
+        finished: false,
+        waker: None,
+        on_complete: None,
+    }));
+
+    let tx = JoinSender {
+        slot: Some(slot.clone()),
+    };
+
+    let abort = AbortHandle {
+        target: Target::Task(task),
+    };
+
+    (tx, JoinHandle { slot, abort })
+}
+
+// Creates the two halves linking a blocking job to its `JoinHandle`. Aborting
+// the handle cancels `token`.
+pub(crate) fn blocking_channel<T>(token: CancellationToken) -> (JoinSender<T>, JoinHandle<T>)
+where
+    T: Send + 'static,
+{
+    let (tx, mut join_handle) = channel(Weak::new());
+
+    // The output of an aborted job is no longer awaited: the `JoinHandle` is
+    // completed right away. The job itself may run for a while longer, and
+    // its output is dropped once it is done.
+    let slot = Arc::downgrade(&join_handle.slot);
+    let cancel = move || {
+        if let Some(slot) = slot.upgrade() {
+            complete(&slot, Err(JoinError::Cancelled));
+        }
+    };
+
+    join_handle.abort = AbortHandle {
+        target: Target::Blocking(token, Arc::new(cancel)),
+    };
+
+    (tx, join_handle)
+}
+
+impl<T> JoinHandle<T>
+where
+    T: Send + 'static,
+{
+    // Returns a function completing the handle with `JoinError::Stuck`. Used
+    // by the watchdog, which does not know the output type of the task.
+    pub(crate) fn stuck_fn(&self) -> Box<dyn Fn() + Send + Sync> {
+        let slot = Arc::downgrade(&self.slot);
+
+        Box::new(move || {
+            if let Some(slot) = slot.upgrade() {
+                complete(&slot, Err(JoinError::Stuck));
+            }
+        })
+    }
+}
+
+impl<T> JoinHandle<T> {
+    /// Cancels the task. See `AbortHandle::abort`.
+    pub fn abort(&self) {
+        self.abort.abort();
+    }
+
+    /// Returns an `AbortHandle` that can cancel the task.
+    pub fn abort_handle(&self) -> AbortHandle {
+        self.abort.clone()
+    }
+
+    /// Returns `true` if the task has finished: its future completed,
+    /// panicked, or was dropped because the task was aborted.
+    ///
+    /// This does not wait. Once it returns `true`, awaiting the handle
+    /// completes right away. An aborted task only counts as finished once its
+    /// future has actually been dropped, which happens on the executor.
+    pub fn is_finished(&self) -> bool {
+        self.slot.lock().unwrap().finished
+    }
+
+    /// Calls `f` with the task's output once the task finishes, instead of
+    /// awaiting the handle.
+    ///
+    /// This is meant for bookkeeping, such as a supervisor counting the tasks
+    /// that failed, without holding on to every handle. `f` runs on whichever
+    /// thread finishes the task: the worker polling it when it completes,
+    /// panics or is dropped after an abort, or the blocking thread running a
+    /// `spawn_blocking` job. If the task already finished, `f` runs right
+    /// away, on the calling thread. Either way, `f` should be short and must
+    /// not block.
+    ///
+    /// Consuming the handle does not cancel the task, as with dropping it.
+    pub fn on_complete<F>(self, f: F)
+    where
+        F: FnOnce(Result<T, JoinError>) + Send + 'static,
+    {
+        let mut slot = self.slot.lock().unwrap();
+
+        match slot.output.take() {
+            Some(output) => {
+                drop(slot);
+                f(output);
+            }
+            None => slot.on_complete = Some(Box::new(f)),
+        }
+    }
+}
+
+impl AbortHandle {
+    /// Cancels the task.
+    ///
+    /// A task is never interrupted in the middle of a poll. If the task is
+    /// being polled, on this thread or on another worker, it is cancelled
+    /// once that poll returns. Its future is then dropped without being
+    /// polled again and awaiting the `JoinHandle` returns
+    /// `JoinError::Cancelled`.
+    ///
+    /// Aborting a task that already completed does nothing: its `JoinHandle`
+    /// still resolves to the task's output.
+    ///
+    /// A job spawned with `spawn_blocking` cannot be interrupted. Aborting it
+    /// completes its `JoinHandle` with `JoinError::Cancelled` right away, and
+    /// the job's output is dropped once it is done. A job that has not
+    /// started yet does not run at all, and one spawned with
+    /// `spawn_blocking_cancellable` sees its token cancelled.
+    pub fn abort(&self) {
+        match &self.target {
+            Target::Task(task) => {
+                if let Some(task) = task.upgrade() {
+                    task.abort();
+                }
+            }
+            Target::Blocking(token, cancel) => {
+                token.cancel();
+                cancel();
+            }
+        }
+    }
+}
+
+impl<T> JoinSender<T> {
+    // Called by the task once its future has completed.
+    pub(crate) fn send(mut self, value: T) {
+        self.complete(Ok(value));
+    }
+
+    fn complete(&mut self, output: Result<T, JoinError>) {
+        if let Some(slot) = self.slot.take() {
+            complete(&slot, output);
+        }
+    }
+}
+
+// Store the output of a task in `slot`, unless it already has one.
+fn complete<T>(slot: &Mutex<Slot<T>>, output: Result<T, JoinError>) {
+    let mut slot = slot.lock().unwrap();
+
+    // An aborted blocking job, or a task aborted by the watchdog, has its
+    // `JoinHandle` completed before it is done. Its actual output is dropped.
+    if slot.finished {
+        return;
+    }
+
+    slot.finished = true;
+
+    // The handle was turned into a callback, which is called outside of the
+    // lock.
+    if let Some(on_complete) = slot.on_complete.take() {
+        drop(slot);
+        on_complete(output);
+        return;
+    }
+
+    slot.output = Some(output);
+
+    // Notify the task awaiting the `JoinHandle`.
+    let waker = slot.waker.take();
+    drop(slot);
+
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+impl<T> Drop for JoinSender<T> {
+    // The `JoinSender` lives inside the task's future. If it is dropped without
+    // having sent the output, the future did not complete. Either it panicked,
+    // in which case the sender is dropped while the stack unwinds, or the task
+    // itself was dropped.
+    fn drop(&mut self) {
+        let err = if thread::panicking() {
+            JoinError::Panic
+        } else {
+            JoinError::Cancelled
+        };
+
+        self.complete(Err(err));
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<T, JoinError>> {
+        let mut slot = self.slot.lock().unwrap();
+
+        // Completing consumes the task's coop budget. The output is only taken
+        // once the budget allows it.
+        if slot.output.is_some() && coop::poll_proceed(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        match slot.output.take() {
+            Some(output) => Poll::Ready(output),
+            None => {
+                // The task has not completed yet. Store the waker so that the
+                // `JoinSender` can notify us once it does. As with `Notified`,
+                // the waker is replaced on every poll in case the
+                // `JoinHandle` moved to a different task.
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => "task was cancelled".fmt(fmt),
+            JoinError::Panic => "task panicked".fmt(fmt),
+            JoinError::Stuck => "task poll exceeded the maximum duration".fmt(fmt),
+        }
+    }
+}
+
+impl Error for JoinError {}
+
+#[cfg(test)]
+mod tests {
+    use crate::{delay, oneshot, yield_now, AbortHandle, Builder, JoinError, MiniTokio, MockClock};
+
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn awaiting_the_handle_returns_the_output() {
+        let mini_tokio = MiniTokio::new();
+        let handle = mini_tokio.spawn(async { "done" });
+
+        assert_eq!(mini_tokio.block_on(handle).unwrap(), "done");
+    }
+
+    #[test]
+    fn a_panicking_task_reports_a_panic() {
+        let mini_tokio = MiniTokio::new();
+        let handle = mini_tokio.spawn(async { panic!("boom") });
+
+        assert!(matches!(mini_tokio.block_on(handle), Err(JoinError::Panic)));
+    }
+
+    #[test]
+    fn an_aborted_task_reports_a_cancellation() {
+        let mini_tokio = MiniTokio::new();
+        let (_tx, rx) = oneshot::channel::<()>();
+
+        let handle = mini_tokio.spawn(rx);
+        handle.abort();
+
+        assert!(matches!(
+            mini_tokio.block_on(handle),
+            Err(JoinError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn aborting_a_finished_task_does_nothing() {
+        let mini_tokio = MiniTokio::new();
+        let handle = mini_tokio.spawn(async { 1 });
+
+        mini_tokio.run_until_idle();
+        handle.abort();
+        mini_tokio.run_until_idle();
+
+        assert_eq!(mini_tokio.block_on(handle).unwrap(), 1);
+    }
+
+    #[test]
+    fn abort_takes_effect_at_the_next_poll() {
+        let mini_tokio = MiniTokio::new();
+        let (abort_tx, abort_rx) = oneshot::channel();
+        let steps = Arc::new(Mutex::new(vec![]));
+
+        let task_steps = steps.clone();
+
+        let handle = mini_tokio.spawn(async move {
+            let abort: AbortHandle = abort_rx.await.unwrap();
+
+            // The task aborts itself mid-poll. The current poll carries on.
+            abort.abort();
+            task_steps.lock().unwrap().push("aborted");
+
+            yield_now().await;
+            task_steps.lock().unwrap().push("resumed");
+        });
+
+        let _ = abort_tx.send(handle.abort_handle());
+        mini_tokio.run_until_idle();
+
+        assert_eq!(*steps.lock().unwrap(), ["aborted"]);
+        assert!(matches!(
+            mini_tokio.block_on(handle),
+            Err(JoinError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn an_abort_handle_obtained_later_cancels_the_task() {
+        let mini_tokio = MiniTokio::new();
+        let (_tx, rx) = oneshot::channel::<()>();
+
+        let handle = mini_tokio.spawn(async move {
+            let _ = rx.await;
+        });
+
+        // The abort handle is used from another thread, while the
+        // `JoinHandle` stays here.
+        let abort = handle.abort_handle();
+        thread::spawn(move || abort.abort()).join().unwrap();
+
+        assert!(matches!(
+            mini_tokio.block_on(handle),
+            Err(JoinError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn is_finished_once_the_task_completed() {
+        let clock = MockClock::new();
+        let mini_tokio = Builder::new().clock(clock.clone()).build();
+        let (started_tx, started_rx) = oneshot::channel();
+
+        let handle = mini_tokio.spawn(async move {
+            let _ = started_tx.send(());
+            delay(Duration::from_secs(1)).await;
+        });
+
+        // The task parks on its delay in the same poll it reports starting.
+        mini_tokio.block_on(started_rx).unwrap();
+
+        assert!(!handle.is_finished());
+
+        clock.advance(Duration::from_secs(1));
+        mini_tokio.run_until_idle();
+
+        assert!(handle.is_finished());
+    }
+
+    #[test]
+    fn on_complete_is_called_with_the_output() {
+        let mini_tokio = MiniTokio::new();
+        let outputs = Arc::new(Mutex::new(vec![]));
+
+        let pending = outputs.clone();
+        mini_tokio
+            .spawn(async { 1 })
+            .on_complete(move |output| pending.lock().unwrap().push(output.unwrap()));
+
+        mini_tokio.run_until_idle();
+        assert_eq!(*outputs.lock().unwrap(), vec![1]);
+
+        // The task already finished: the callback is called right away.
+        let handle = mini_tokio.spawn(async { 2 });
+        mini_tokio.run_until_idle();
+
+        let finished = outputs.clone();
+        handle.on_complete(move |output| finished.lock().unwrap().push(output.unwrap()));
+        assert_eq!(*outputs.lock().unwrap(), vec![1, 2]);
+    }
+}
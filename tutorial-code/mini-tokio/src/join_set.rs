@@ -0,0 +1,165 @@
+//! Managing a group of tasks.
+//!
+//! A `JoinSet` owns the `JoinHandle`s of the tasks spawned into it and yields
+//! their outputs in the order the tasks complete, which is rarely the order
+//! they were spawned in. It is built on top of `JoinHandle`: waiting for the
+//! next task polls every handle of the set, each storing the waker of the task
+//! awaiting the set.
+
+use crate::{spawn, AbortHandle, JoinError, JoinHandle};
+
+use std::future::{self, Future};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A collection of tasks, spawned onto the current runtime, whose outputs are
+/// awaited in completion order.
+///
+/// This is the mini-tokio equivalent of `tokio::task::JoinSet`. Dropping the
+/// set aborts the tasks it still holds.
+pub struct JoinSet<T> {
+    handles: Vec<JoinHandle<T>>,
+}
+
+impl<T> JoinSet<T> {
+    /// Creates an empty set.
+    pub fn new() -> JoinSet<T> {
+        JoinSet { handles: vec![] }
+    }
+
+    /// Spawns `future` onto the current runtime and adds its task to the set.
+    ///
+    /// # Panics
+    ///
+    /// Panics in the same cases as the free `spawn` function.
+    pub fn spawn<F>(&mut self, future: F) -> AbortHandle
+    where
+        F: Future<Output = T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let handle = spawn(future);
+        let abort = handle.abort_handle();
+
+        self.handles.push(handle);
+        abort
+    }
+
+    /// Returns the number of tasks in the set.
+    ///
+    /// A task stays in the set until its output is returned by `join_next`,
+    /// even once it has completed.
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    /// Returns `true` if the set holds no task.
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+
+    /// Waits for one of the tasks of the set to complete and returns its
+    /// output, removing it from the set. Returns `None` if the set is empty.
+    pub async fn join_next(&mut self) -> Option<Result<T, JoinError>> {
+        future::poll_fn(|cx| self.poll_join_next(cx)).await
+    }
+
+    /// Polls for one of the tasks of the set to complete. See `join_next`.
+    pub fn poll_join_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<T, JoinError>>> {
+        if self.handles.is_empty() {
+            return Poll::Ready(None);
+        }
+
+        for i in 0..self.handles.len() {
+            if let Poll::Ready(output) = Pin::new(&mut self.handles[i]).poll(cx) {
+                // A handle must not be polled again once ready.
+                self.handles.swap_remove(i);
+                return Poll::Ready(Some(output));
+            }
+        }
+
+        Poll::Pending
+    }
+
+    /// Aborts every task of the set.
+    ///
+    /// The tasks stay in the set: `join_next` returns their outputs, which
+    /// are `JoinError::Cancelled` for the tasks that had not completed yet.
+    pub fn abort_all(&mut self) {
+        for handle in &self.handles {
+            handle.abort();
+        }
+    }
+}
+
+impl<T> Default for JoinSet<T> {
+    fn default() -> JoinSet<T> {
+        JoinSet::new()
+    }
+}
+
+impl<T> Drop for JoinSet<T> {
+    fn drop(&mut self) {
+        self.abort_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{yield_now, JoinError, JoinSet, MiniTokio};
+
+    #[test]
+    fn join_next_yields_every_output() {
+        let mini_tokio = MiniTokio::new();
+
+        let mut outputs = mini_tokio.block_on(async {
+            let mut set = JoinSet::new();
+
+            for i in 0..5 {
+                set.spawn(async move {
+                    for _ in 0..(5 - i) {
+                        yield_now().await;
+                    }
+
+                    i
+                });
+            }
+
+            assert_eq!(set.len(), 5);
+
+            let mut outputs = vec![];
+
+            while let Some(output) = set.join_next().await {
+                outputs.push(output.unwrap());
+            }
+
+            assert!(set.is_empty());
+            outputs
+        });
+
+        // In completion order, which depends on the scheduling.
+        outputs.sort();
+        assert_eq!(outputs, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn abort_all_cancels_the_pending_tasks() {
+        let mini_tokio = MiniTokio::new();
+
+        mini_tokio.block_on(async {
+            let mut set = JoinSet::new();
+
+            for _ in 0..3 {
+                set.spawn(std::future::pending::<()>());
+            }
+
+            set.abort_all();
+
+            for _ in 0..3 {
+                let output = set.join_next().await.unwrap();
+                assert!(matches!(output, Err(JoinError::Cancelled)));
+            }
+
+            assert!(set.join_next().await.is_none());
+        });
+    }
+}
@@ -0,0 +1,1787 @@
+//! Demonstrates how to implement a (very) basic asynchronous rust executor and
+//! timer. The goal of this crate is to provide some context into how the various
+//! building blocks fit together.
+//!
+//! The executor lives in this library. `main.rs` is a small program using it.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::{self, Future};
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::{pin, Pin};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
+
+// A utility that allows us to implement a `std::task::Waker` without having to
+// use `unsafe` code. With the `raw-waker` feature, the `Waker` is built by hand
+// instead. See the `raw_waker` module.
+#[cfg(not(feature = "raw-waker"))]
+use futures::task;
+use futures::task::ArcWake;
+// Used as a channel to queue scheduled tasks.
+use crossbeam::channel;
+
+mod blocking;
+pub use blocking::{spawn_blocking, spawn_blocking_cancellable};
+
+mod builder;
+pub use builder::Builder;
+use builder::SpawnHook;
+
+mod cancellation;
+pub use cancellation::{spawn_child, CancellationToken};
+
+mod clock;
+pub use clock::{Clock, MockClock, SystemClock};
+
+mod coop;
+pub use coop::poll_proceed;
+
+mod defer;
+pub use defer::{defer_async, DeferAsync};
+
+mod error;
+pub use error::SpawnError;
+
+mod executor;
+pub use executor::{RuntimeTimer, Spawn, Timer};
+
+mod idle;
+use idle::LiveTasks;
+
+mod join;
+pub use join::{AbortHandle, JoinError, JoinHandle};
+
+mod join_set;
+pub use join_set::JoinSet;
+
+mod local;
+pub use local::{spawn_local, LocalSet};
+
+mod metrics;
+use metrics::Counters;
+pub use metrics::RuntimeMetrics;
+
+pub mod mpsc;
+
+mod net;
+pub use net::{MiniTcpListener, MiniTcpStream};
+
+mod noop;
+pub use noop::{noop_context, noop_waker};
+
+mod notify;
+pub use notify::{Notified, Notify};
+
+pub mod oneshot;
+
+#[cfg(feature = "raw-waker")]
+mod raw_waker;
+
+mod reactor;
+
+mod select;
+pub use select::{select2, Either};
+
+mod state;
+use state::State;
+
+pub mod stream;
+
+mod task_local;
+pub use task_local::LocalKey;
+
+mod test_runtime;
+pub use test_runtime::{maybe_yield, Controls, TestRuntime};
+
+mod time;
+pub use time::{
+    delay, interval, interval_with, sleep, sleep_until, timeout, timeout_with, Elapsed, FutureExt,
+    Interval,
+};
+
+pub mod timer_wheel;
+
+mod watchdog;
+
+mod yield_now;
+pub use yield_now::yield_now;
+
+/// A very basic futures executor based on a channel. When tasks are woken, they
+/// are scheduled by queuing them in the send half of the channel. The executor
+/// waits on the receive half and executes received tasks.
+///
+/// When a task is executed, the send half of the channel is passed along via
+/// the task's Waker.
+///
+/// By default, tasks are executed on the thread calling `run`. An instance
+/// created with `new_multi_thread` executes tasks on a pool of worker threads
+/// instead. All workers receive from the same channel. Use `Builder` to
+/// configure an instance further.
+pub struct MiniTokio {
+    // Receives scheduled tasks. When a task is scheduled, the associated future
+    // is ready to make progress. This usually happens when a resource the task
+    // uses becomes ready to perform an operation. For example, a socket
+    // received data and a `read` call will succeed.
+    scheduled: channel::Receiver<Arc<Task>>,
+
+    // Handle to this instance. It holds the send half of the scheduled channel
+    // as well as the send half of the shutdown channel.
+    handle: Handle,
+
+    // Receive half of the shutdown channel. No message is ever sent on this
+    // channel. Instead, it becomes disconnected once shutdown is requested,
+    // which makes the executor loop stop waiting for tasks.
+    shutdown: channel::Receiver<()>,
+
+    // Number of threads executing tasks.
+    workers: usize,
+
+    // Prefix of the names of the worker threads.
+    thread_name_prefix: String,
+
+    // Drives the timers of this instance. The driver thread is stopped when
+    // the instance is dropped. `None` if timers are disabled.
+    timer: Option<time::Driver>,
+
+    // Drives the sockets of this instance. The reactor thread is started with
+    // the first socket and stopped when the instance is dropped. `None` if I/O
+    // is disabled.
+    reactor: Option<reactor::Driver>,
+
+    // Set if the worker waits for I/O events and fires the timers itself,
+    // instead of the reactor and timer threads. See `Builder::park_on_io`.
+    park_on_io: bool,
+
+    // The number of tasks polled in between checks for I/O events, when
+    // parking on I/O. See `Builder::event_interval`.
+    event_interval: u32,
+
+    // Runs the jobs submitted with `spawn_blocking`. The pool's threads exit
+    // once the instance is dropped.
+    _blocking: blocking::Pool,
+
+    // Aborts the tasks whose poll takes too long. The watchdog thread exits
+    // once the instance is dropped. `None` unless `max_poll_duration` is set.
+    _watchdog: Option<watchdog::Watchdog>,
+
+    // Counts the workers waiting for work, and the ones woken up by a task.
+    #[cfg(test)]
+    parked: Parked,
+}
+
+// See `MiniTokio::parked`. Only kept by tests, which check how many workers
+// scheduling a task wakes.
+#[cfg(test)]
+#[derive(Default)]
+struct Parked {
+    // Workers that found nothing to do and are blocked on the channels.
+    waiting: std::sync::atomic::AtomicUsize,
+
+    // Workers that were waiting and received a task.
+    unparked: std::sync::atomic::AtomicUsize,
+}
+
+/// A handle to a mini-tokio instance.
+///
+/// Handles are cheap to clone and may be moved into tasks or other threads. A
+/// task uses a handle to stop the executor it is running on. Code running
+/// outside of the executor, on a plain thread or in `spawn_blocking`, uses a
+/// handle to spawn tasks onto it: the free `spawn` function only works on the
+/// executor's own threads.
+#[derive(Clone)]
+pub struct Handle {
+    // Send half of the scheduled channel.
+    sender: channel::Sender<Arc<Task>>,
+
+    // Send half of the shutdown channel. Shutdown is signaled by dropping the
+    // sender: once the last sender is gone, the channel is disconnected and all
+    // receivers are notified. The sender sits behind a mutex so any clone of
+    // the handle can drop it.
+    shutdown: Arc<Mutex<Option<channel::Sender<()>>>>,
+
+    // Cancelled when shutdown is requested, waking the tasks waiting in
+    // `shutdown_signal`.
+    shutdown_signal: CancellationToken,
+
+    // Set by `shutdown_graceful`. New tasks are refused, while the executor
+    // keeps running the existing ones.
+    closed: Arc<AtomicBool>,
+
+    // Used by `delay` to register deadlines with the timer driver. `None` if
+    // timers are disabled.
+    timer: Option<time::TimerHandle>,
+
+    // Used by sockets to register with the reactor. `None` if I/O is
+    // disabled.
+    io: Option<reactor::ReactorHandle>,
+
+    // Wakes the worker while it waits for I/O events. `None` unless the
+    // instance parks on I/O, see `Builder::park_on_io`.
+    unpark: Option<reactor::ReactorHandle>,
+
+    // Counters reported by `MiniTokio::metrics`.
+    counters: Arc<Counters>,
+
+    // Used by `spawn_blocking` to submit jobs to the blocking pool.
+    blocking: blocking::BlockingHandle,
+
+    // Tracks the tasks that are still alive, for `run_until_idle`.
+    live: Arc<LiveTasks>,
+
+    // Woken tasks that did not fit in a bounded scheduled channel.
+    overflow: Overflow,
+
+    // Wraps the future of every task. See `Builder::spawn_hook`.
+    spawn_hook: Option<SpawnHook>,
+
+    // Watches the polls of the tasks. See `Builder::max_poll_duration`.
+    watchdog: Option<watchdog::WatchdogHandle>,
+}
+
+/// Returned by `Handle::shutdown_graceful`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    /// The number of tasks that were alive when the graceful shutdown began,
+    /// and completed before the executor was shut down.
+    pub completed: usize,
+
+    /// The number of tasks still alive when the executor was shut down: the
+    /// task calling `shutdown_graceful`, if any, and the tasks spawned on the
+    /// instance's behalf in the meantime.
+    pub remaining: usize,
+}
+
+// The root future of `block_on`, driven by the executor loop: the channel its
+// wakes are sent on, and a function polling it that returns `true` once it
+// completed.
+type Root<'a> = (&'a channel::Receiver<()>, &'a mut dyn FnMut() -> bool);
+
+// Restores the previous value of `CURRENT` when dropped. See `MiniTokio::enter`.
+struct Enter(Option<Handle>);
+
+// The waker of `block_on`'s root future. Also wakes the worker if it waits
+// for I/O events.
+struct RootWaker(channel::Sender<()>, Option<reactor::ReactorHandle>);
+
+// The id of the next task to be spawned.
+static NEXT_TASK_ID: AtomicU64 = AtomicU64::new(1);
+
+// Tasks waiting for room in the scheduled channel. See `Task::schedule`.
+type Overflow = Arc<Mutex<VecDeque<Arc<Task>>>>;
+
+impl MiniTokio {
+    /// Initialize a new mini-tokio instance. Tasks are executed on the thread
+    /// calling `run`.
+    pub fn new() -> MiniTokio {
+        Builder::new().build()
+    }
+
+    /// Initialize a new mini-tokio instance executing tasks on `num_workers`
+    /// threads.
+    ///
+    /// The worker threads are spawned by `run`, which blocks until all of them
+    /// have stopped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `num_workers` is zero.
+    pub fn new_multi_thread(num_workers: usize) -> MiniTokio {
+        Builder::new().worker_threads(num_workers).build()
+    }
+
+    /// Initialize a new mini-tokio instance whose scheduled channel holds at
+    /// most `capacity` tasks. Tasks are executed on the thread calling `run`.
+    ///
+    /// With the default, unbounded, channel, a task spawning tasks in a loop
+    /// grows the channel without limit. With a bounded channel, spawning a
+    /// task while the channel is full fails with `SpawnError::AtCapacity`.
+    /// This pushes back on the code spawning tasks, which may wait and try
+    /// again later.
+    ///
+    /// Waking a task cannot fail though: there is no one to report the error
+    /// to. A task woken while the channel is full is pushed onto a separate,
+    /// unbounded, overflow queue instead, and moved to the channel once there
+    /// is room. Only spawning is bounded.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn with_capacity(capacity: usize) -> MiniTokio {
+        Builder::new().queue_capacity(capacity).build()
+    }
+
+    fn from_builder(builder: &Builder) -> MiniTokio {
+        let (sender, scheduled) = match builder.queue_capacity {
+            Some(capacity) => channel::bounded(capacity),
+            None => channel::unbounded(),
+        };
+        let (shutdown_tx, shutdown) = channel::bounded(0);
+        let prefix = &builder.thread_name_prefix;
+        let timer = if builder.enable_timer {
+            Some(time::Driver::new(
+                format!("{}-timer", prefix),
+                builder.clock.clone(),
+                builder.park_on_io,
+            ))
+        } else {
+            None
+        };
+        let reactor = if builder.enable_io {
+            Some(reactor::Driver::new(
+                format!("{}-io", prefix),
+                builder.park_on_io,
+            ))
+        } else {
+            None
+        };
+        let blocking = blocking::Pool::new(format!("{}-blocking", prefix));
+        let watchdog = builder
+            .max_poll_duration
+            .map(|limit| watchdog::Watchdog::new(format!("{}-watchdog", prefix), limit));
+
+        let handle = Handle {
+            sender,
+            shutdown: Arc::new(Mutex::new(Some(shutdown_tx))),
+            shutdown_signal: CancellationToken::new(),
+            closed: Arc::new(AtomicBool::new(false)),
+            timer: timer.as_ref().map(time::Driver::handle),
+            io: reactor.as_ref().map(reactor::Driver::handle),
+            unpark: reactor
+                .as_ref()
+                .filter(|_| builder.park_on_io)
+                .map(reactor::Driver::handle),
+            counters: Arc::new(Counters::default()),
+            blocking: blocking.handle(),
+            live: Arc::new(LiveTasks::default()),
+            overflow: Arc::new(Mutex::new(VecDeque::new())),
+            spawn_hook: builder.spawn_hook.clone(),
+            watchdog: watchdog.as_ref().map(watchdog::Watchdog::handle),
+        };
+
+        MiniTokio {
+            scheduled,
+            handle,
+            shutdown,
+            workers: builder.worker_threads,
+            thread_name_prefix: prefix.clone(),
+            timer,
+            reactor,
+            park_on_io: builder.park_on_io,
+            event_interval: builder.event_interval,
+            _blocking: blocking,
+            _watchdog: watchdog,
+            #[cfg(test)]
+            parked: Parked::default(),
+        }
+    }
+
+    /// Returns a handle to this mini-tokio instance.
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    /// Spawn a future onto the mini-tokio instance.
+    ///
+    /// The given future is wrapped with the `Task` harness and pushed into the
+    /// `scheduled` queue. The future will be executed when `run` is called.
+    /// The returned `JoinHandle` resolves to the future's output.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the scheduled channel is full, see `with_capacity`, or if the
+    /// instance is shut down.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.handle.spawn(future)
+    }
+
+    /// Spawn a future onto the mini-tokio instance, or return an error if the
+    /// scheduled channel is full or the instance is shut down.
+    pub fn try_spawn<F>(&self, future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.handle.try_spawn(future)
+    }
+
+    /// Returns a snapshot of the counters of this instance.
+    ///
+    /// This only reads a few atomics and may be called from any thread, while
+    /// the executor is running.
+    pub fn metrics(&self) -> RuntimeMetrics {
+        self.handle.metrics()
+    }
+
+    /// Request the executor to shut down. See `Handle::shutdown`.
+    pub fn shutdown(&self) {
+        self.handle.shutdown();
+    }
+
+    /// Shut down the executor once every task completed. See
+    /// `Handle::shutdown_graceful`.
+    pub fn shutdown_graceful(&self) -> impl Future<Output = ShutdownSummary> + Send + 'static {
+        self.handle.shutdown_graceful()
+    }
+
+    /// Run the executor.
+    ///
+    /// This starts the executor loop and runs it until shutdown is requested
+    /// with `MiniTokio::shutdown` or `Handle::shutdown`.
+    ///
+    /// Tasks are popped from the `scheduled` channel receiver. Receiving a task
+    /// on the channel signifies the task is ready to be executed. This happens
+    /// when the task is first created and when its waker has been used.
+    pub fn run(&self) {
+        // `never()` is a channel that is never ready, the executor only stops
+        // on shutdown.
+        self.run_until(&channel::never());
+    }
+
+    /// Run the executor until there is nothing left to do.
+    ///
+    /// This returns once every task has either completed or been dropped, or
+    /// when shutdown is requested. Returns right away if there are no tasks.
+    ///
+    /// An empty queue is not enough for the executor to be idle: a task waiting
+    /// on a timer or on another task is not in the queue, but will be once it
+    /// is woken. The executor only stops once no task is left that could be
+    /// woken.
+    ///
+    /// A task waiting on something that is never going to happen, and whose
+    /// waker is held by something that outlives it, keeps the executor
+    /// running forever.
+    pub fn run_until_idle(&self) {
+        self.run_until(&self.handle.live.idle());
+    }
+
+    /// Run `future` to completion on the executor and return its output.
+    ///
+    /// This is what `#[tokio::main]` expands to: the body of `main` becomes
+    /// the root future and is passed to `block_on`.
+    ///
+    /// The root future is not spawned as a task. It is polled on the thread
+    /// calling `block_on`, every time it is woken, which is why it needs to
+    /// be neither `Send` nor `'static`: it may borrow from the caller's stack
+    /// or hold an `Rc`. With a single worker, the same thread executes the
+    /// tasks in between polls of the root future. With more, the worker
+    /// threads execute the tasks and the calling thread only drives the root
+    /// future.
+    ///
+    /// `block_on` returns as soon as the root future completes. It does not
+    /// wait for other tasks: tasks spawned by the root future that are still
+    /// pending are left detached. They resume the next time the executor
+    /// runs.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the future panics, or if the executor is shut down before the
+    /// future completes.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut output = None;
+
+        // Wakes of the root future are sent on this channel. It holds a
+        // single message: waking the root future again before it was polled
+        // is redundant.
+        let (woken_tx, woken) = channel::bounded(1);
+        let waker = futures::task::waker(Arc::new(RootWaker(woken_tx, self.handle.unpark.clone())));
+
+        // The root future is polled first.
+        waker.wake_by_ref();
+
+        // Polls the root future. Returns `true` once it completed, its output
+        // is then in `output`. Like a task, it gets a fresh coop budget.
+        let mut poll_root = || {
+            let mut cx = Context::from_waker(&waker);
+
+            match coop::budget(|| future.as_mut().poll(&mut cx)) {
+                Poll::Ready(value) => {
+                    output = Some(value);
+                    true
+                }
+                Poll::Pending => false,
+            }
+        };
+
+        if self.workers == 1 {
+            self.run_worker(&channel::never(), Some((&woken, &mut poll_root)));
+        } else {
+            // Like the shutdown channel, this channel is only used to signal
+            // an event by becoming disconnected: the root future completed
+            // and the workers may stop.
+            let (done_tx, done_rx) = channel::bounded::<()>(0);
+
+            thread::scope(|scope| {
+                // Owned by the closure, so it is also dropped, stopping the
+                // workers, if the root future panics. The scope would
+                // otherwise wait for them forever.
+                let _done_tx = done_tx;
+
+                self.spawn_workers(scope, &done_rx);
+
+                let _enter = self.enter();
+
+                loop {
+                    crossbeam::select! {
+                        recv(woken) -> _ => {
+                            if poll_root() {
+                                break;
+                            }
+                        }
+                        recv(self.shutdown) -> _ => break,
+                    }
+                }
+            });
+        }
+
+        output.expect("executor shut down before the block_on future completed")
+    }
+
+    // Run the executor until shutdown is requested or `stop` becomes ready.
+    fn run_until(&self, stop: &channel::Receiver<()>) {
+        if self.workers == 1 {
+            // Execute tasks on the current thread.
+            self.run_worker(stop, None);
+        } else {
+            thread::scope(|scope| self.spawn_workers(scope, stop));
+        }
+    }
+
+    // Spawn the worker threads. Each one runs its own executor loop,
+    // receiving from the shared `scheduled` channel. The `Task` structure is
+    // `Send + Sync`, so tasks can be executed by any worker. A scope is used
+    // so the workers can borrow `self`; it only returns once every worker has
+    // stopped.
+    //
+    // Scheduling a task wakes a single idle worker: the channel hands each
+    // message to one of the receivers blocked on it, and leaves the others
+    // asleep. Tokio's workers have no shared channel to wait on. To avoid
+    // waking every parked worker for one task, Tokio counts the workers
+    // searching for work and only wakes another one when none is.
+    fn spawn_workers<'scope>(
+        &'scope self,
+        scope: &'scope thread::Scope<'scope, '_>,
+        stop: &'scope channel::Receiver<()>,
+    ) {
+        for i in 0..self.workers {
+            thread::Builder::new()
+                .name(format!("{}-worker-{}", self.thread_name_prefix, i))
+                .spawn_scoped(scope, move || self.run_worker(stop, None))
+                .expect("failed to spawn a worker thread");
+        }
+    }
+
+    // Set the CURRENT thread-local to point to this executor until the
+    // returned guard is dropped.
+    //
+    // Tokio uses a thread-local variable to implement `tokio::spawn`. When
+    // entering the runtime, the executor stores necessary context with the
+    // thread-local to support spawning new tasks.
+    fn enter(&self) -> Enter {
+        Enter(CURRENT.with(|cell| cell.replace(Some(self.handle.clone()))))
+    }
+
+    // The executor loop, executed by every worker thread.
+    //
+    // `root` is used by `block_on` with a single worker: the root future is
+    // polled on this thread whenever it is woken, and the loop stops once it
+    // completes.
+    fn run_worker(&self, stop: &channel::Receiver<()>, mut root: Option<Root<'_>>) {
+        // The executor is no longer running once the guard is dropped, `spawn`
+        // may not be used anymore.
+        let _enter = self.enter();
+
+        let never = channel::never();
+        let root_woken = root.as_ref().map_or(&never, |(woken, _)| *woken);
+
+        // The number of tasks polled since the last check for I/O events.
+        let mut polled = 0;
+
+        // The executor loop. Scheduled tasks are received. If the channel is
+        // empty, the thread blocks until either a task is received or the
+        // executor is asked to stop.
+        loop {
+            // When parking on I/O, the worker must not block on the channels:
+            // it would never see I/O events nor fire timers. It waits for I/O
+            // events instead, until something is ready.
+            if self.park_on_io && !self.has_work(root_woken, stop) {
+                self.park();
+                continue;
+            }
+
+            #[cfg(test)]
+            let waiting = !self.has_work(root_woken, stop);
+            #[cfg(test)]
+            if waiting {
+                self.parked.waiting.fetch_add(1, Ordering::SeqCst);
+            }
+
+            crossbeam::select! {
+                recv(self.scheduled) -> task => {
+                    // `self` holds a sender, so the channel is never
+                    // disconnected while the loop runs.
+                    let task = task.unwrap();
+
+                    #[cfg(test)]
+                    if waiting {
+                        self.parked.waiting.fetch_sub(1, Ordering::SeqCst);
+                        self.parked.unparked.fetch_add(1, Ordering::SeqCst);
+                    }
+
+                    // Execute the task until it either completes or cannot make
+                    // further progress and returns `Poll::Pending`.
+                    task.poll();
+
+                    // A slot was freed in the scheduled channel. Use it for
+                    // the tasks waiting in the overflow queue, if any.
+                    self.refill();
+
+                    // The channel may never run empty. Check for I/O events
+                    // and timers every now and then anyway.
+                    polled += 1;
+
+                    if self.park_on_io && polled >= self.event_interval {
+                        polled = 0;
+                        self.turn(Some(Duration::ZERO));
+                    }
+                }
+                // `block_on`'s root future was woken.
+                recv(root_woken) -> _ => {
+                    if let Some((_, poll_root)) = root.as_mut() {
+                        if poll_root() {
+                            break;
+                        }
+                    }
+                }
+                // Shutdown was requested.
+                recv(self.shutdown) -> _ => {
+                    self.drain();
+                    break;
+                }
+                // The executor is idle, or the workers of `block_on` are no
+                // longer needed. The remaining tasks are left scheduled.
+                recv(stop) -> _ => break,
+            }
+        }
+    }
+
+    // Returns `true` if one of the channels of the executor loop is ready, so
+    // that `select!` returns right away.
+    fn has_work(&self, root_woken: &channel::Receiver<()>, stop: &channel::Receiver<()>) -> bool {
+        // The shutdown and stop channels never carry a message. They are only
+        // ever disconnected, and `try_recv` does not consume anything.
+        let disconnected =
+            |rx: &channel::Receiver<()>| rx.try_recv() == Err(channel::TryRecvError::Disconnected);
+
+        !self.scheduled.is_empty()
+            || !root_woken.is_empty()
+            || disconnected(&self.shutdown)
+            || disconnected(stop)
+    }
+
+    // Wait for I/O events, for as long as the nearest timer allows, then fire
+    // the expired timers. Returns early when a task is scheduled or the loop
+    // must stop: every sender of the executor loop's channels unparks the
+    // worker. See `Builder::park_on_io`.
+    fn park(&self) {
+        self.turn(self.timer.as_ref().and_then(time::Driver::park_timeout));
+    }
+
+    // Wait for I/O events for at most `timeout`, then fire the expired timers.
+    fn turn(&self, timeout: Option<Duration>) {
+        // `park_on_io` requires I/O to be enabled.
+        self.reactor.as_ref().unwrap().turn(timeout);
+
+        if let Some(timer) = &self.timer {
+            timer.fire_expired();
+        }
+    }
+
+    // Drain the tasks that were already scheduled when shutdown was observed.
+    // They were made ready before the shutdown and get one last poll. Only that
+    // many tasks are received: a task that keeps waking itself would otherwise
+    // keep the loop running forever. With multiple workers, the tasks are split
+    // between the workers that are draining.
+    fn drain(&self) {
+        for _ in 0..self.scheduled.len() {
+            match self.scheduled.try_recv() {
+                Ok(task) => task.poll(),
+                Err(_) => break,
+            }
+        }
+
+        // The tasks in the overflow queue are scheduled too.
+        let overflow = std::mem::take(&mut *self.handle.overflow.lock().unwrap());
+
+        for task in overflow {
+            task.poll();
+        }
+    }
+
+    // Move tasks from the overflow queue to the scheduled channel, as long as
+    // there is room. The overflow queue is only used when the channel is full,
+    // so a worker is bound to receive a task from the channel, and call this
+    // function, after a task was pushed onto it.
+    fn refill(&self) {
+        let mut overflow = self.handle.overflow.lock().unwrap();
+
+        while let Some(task) = overflow.pop_front() {
+            if let Err(err) = self.handle.sender.try_send(task) {
+                // Still no room. Put the task back, in front.
+                overflow.push_front(err.into_inner());
+                break;
+            }
+        }
+    }
+}
+
+impl Handle {
+    /// Spawn a future onto the mini-tokio instance this handle refers to.
+    ///
+    /// Unlike the free `spawn` function, this may be called from any thread.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the scheduled channel is full, or if the instance is shut
+    /// down. Use `try_spawn` to handle those cases.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.try_spawn(future)
+            .unwrap_or_else(|err| panic!("failed to spawn task; {}", err))
+    }
+
+    /// Spawn a future onto the mini-tokio instance this handle refers to, or
+    /// return an error if the scheduled channel is full or the instance is
+    /// shut down.
+    pub fn try_spawn<F>(&self, future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        Task::spawn(future, self)
+    }
+
+    /// Returns a snapshot of the counters of the instance. See
+    /// `MiniTokio::metrics`.
+    ///
+    /// Unlike `MiniTokio::metrics`, this may be called from the instance's
+    /// own tasks, which have no access to the `MiniTokio` value.
+    pub fn metrics(&self) -> RuntimeMetrics {
+        let overflow = self.overflow.lock().unwrap().len();
+        self.counters.snapshot(self.sender.len() + overflow)
+    }
+
+    /// Request the executor to shut down.
+    ///
+    /// The executor loop stops waiting for new tasks and `run` returns once
+    /// the tasks that are currently scheduled have been polled one last time.
+    ///
+    /// Tasks are never interrupted in the middle of a poll. When a task calls
+    /// `shutdown`, its current poll completes normally. Tasks that are not
+    /// scheduled, for example because they are waiting on a `delay`, are not
+    /// polled again. They are dropped once nothing references them anymore.
+    /// Calling `shutdown` more than once has no additional effect.
+    pub fn shutdown(&self) {
+        // Wake the tasks waiting for the signal first, so they are scheduled
+        // by the time the executor observes the shutdown and get the last
+        // poll.
+        self.shutdown_signal.cancel();
+
+        // Dropping the sender disconnects the shutdown channel.
+        self.shutdown.lock().unwrap().take();
+
+        if let Some(unpark) = &self.unpark {
+            unpark.unpark();
+        }
+    }
+
+    /// Returns a future that lets every task complete, then shuts the executor
+    /// down.
+    ///
+    /// New tasks are refused with `SpawnError::Shutdown` as soon as the future
+    /// is first polled. The existing tasks keep running, and the future
+    /// completes once all of them have completed, at which point `shutdown` is
+    /// called. Unlike `shutdown`, no task is left behind, but the wait lasts as
+    /// long as the slowest task: a task that never completes keeps the
+    /// executor running forever.
+    ///
+    /// The future may be awaited by one of the instance's tasks, which does
+    /// not wait for itself, or by the root future of `block_on`.
+    pub fn shutdown_graceful(&self) -> impl Future<Output = ShutdownSummary> + Send + 'static {
+        let handle = self.clone();
+
+        async move {
+            handle.closed.store(true, Ordering::SeqCst);
+
+            // The task awaiting this future, if it is one of ours.
+            let caller = usize::from(handle.live.is_polling());
+            let alive = handle.live.count() - caller;
+
+            future::poll_fn(|cx| handle.live.poll_at_most(caller, cx)).await;
+            handle.shutdown();
+
+            ShutdownSummary {
+                completed: alive,
+                remaining: handle.live.count(),
+            }
+        }
+    }
+
+    /// Returns a future completing once shutdown is requested.
+    ///
+    /// A task can race its work against this future to clean up before the
+    /// executor stops. A task waiting on it is woken by `shutdown` and gets
+    /// the last poll described there. That poll must do the cleanup without
+    /// awaiting anything else: if the task returns `Poll::Pending` again, it is
+    /// not polled any further.
+    pub fn shutdown_signal(&self) -> impl Future<Output = ()> + Send + 'static {
+        let token = self.shutdown_signal.clone();
+
+        async move { token.cancelled().await }
+    }
+}
+
+impl Drop for Enter {
+    fn drop(&mut self) {
+        CURRENT.with(|cell| *cell.borrow_mut() = self.0.take());
+    }
+}
+
+impl ArcWake for RootWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        // Fails if the channel is full, in which case the root future is
+        // already due to be polled, or if `block_on` returned.
+        let _ = arc_self.0.try_send(());
+
+        if let Some(unpark) = &arc_self.1 {
+            unpark.unpark();
+        }
+    }
+}
+
+impl Default for MiniTokio {
+    fn default() -> MiniTokio {
+        MiniTokio::new()
+    }
+}
+
+// An equivalent to `tokio::spawn`. When entering the mini-tokio executor, the
+// `CURRENT` thread-local is set to point to that executor's handle, which holds
+// the channel's Send half. Then, spawning requires creating the `Task` harness
+// for the given `future` and pushing it into the scheduled queue.
+//
+// Panics when called outside of a mini-tokio runtime, when the runtime's
+// scheduled channel is full or when the runtime is shut down. Use `try_spawn`
+// to handle those cases.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match try_spawn(future) {
+        Ok(join_handle) => join_handle,
+        Err(SpawnError::NotInRuntime) => panic!("spawn called outside of a MiniTokio runtime"),
+        Err(err) => panic!("failed to spawn task; {}", err),
+    }
+}
+
+// Like `spawn`, but returns an error instead of panicking when `CURRENT` is not
+// set, i.e. when called from a thread that is not running a mini-tokio
+// executor, when the scheduled channel is full or when the runtime is shut
+// down.
+pub fn try_spawn<F>(future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    CURRENT.with(|cell| {
+        let borrow = cell.borrow();
+        let handle = borrow.as_ref().ok_or(SpawnError::NotInRuntime)?;
+        Task::spawn(future, handle)
+    })
+}
+
+// Runs `future` on a new thread, with a mini-tokio instance of its own, and
+// returns a handle to that thread. Joining the handle returns the future's
+// output.
+//
+// The instance is independent of the one of the caller, if any: it has its own
+// queue, timer and I/O threads, and a task spawned by `future` runs on it. This
+// isolates a subsystem, whose tasks cannot starve the caller's tasks nor be
+// starved by them. The instance is dropped once `future` completes, along with
+// the tasks `future` left pending.
+pub fn run_isolated<F>(future: F) -> thread::JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    thread::spawn(move || MiniTokio::new().block_on(future))
+}
+
+// Used to track the current mini-tokio instance so that the `spawn` function is
+// able to schedule spawned tasks and `delay` is able to register timers.
+thread_local! {
+    static CURRENT: RefCell<Option<Handle>> = const { RefCell::new(None) };
+}
+
+// Task harness. Contains the future as well as the necessary data to schedule
+// the future once it is woken.
+struct Task {
+    // Identifies the task in `tracing` output and to the watchdog. Ids are
+    // assigned in spawn order, across all mini-tokio instances.
+    id: u64,
+
+    // The future is wrapped with a `Mutex` to make the `Task` structure `Sync`.
+    // There will only ever be a single thread at a time that attempts to use
+    // `future`. The Tokio runtime avoids the mutex by using `unsafe` code. The
+    // box is also avoided.
+    //
+    // The future is set to `None` once it has completed. A task may still be
+    // woken, and therefore scheduled, after that point. Polling a completed
+    // future is not allowed, so those late polls must be ignored.
+    future: Mutex<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+
+    // When a task is notified, it is queued into this channel. The executor
+    // pops notified tasks and executes them.
+    executor: channel::Sender<Arc<Task>>,
+
+    // Where the task is queued instead when the channel is full.
+    overflow: Overflow,
+
+    // Wakes the worker once the task is queued, if it waits for I/O events.
+    unpark: Option<reactor::ReactorHandle>,
+
+    // Whether the task is idle, queued, being polled or complete. Wakes are
+    // coalesced based on it. See the `state` module.
+    state: State,
+
+    // Set by `AbortHandle::abort`. The next time the task is polled, its
+    // future is dropped instead.
+    aborted: AtomicBool,
+
+    // Counters of the runtime the task belongs to.
+    counters: Arc<Counters>,
+
+    // Live tasks of the runtime the task belongs to. This task counts as live
+    // until its future is dropped.
+    live: Arc<LiveTasks>,
+
+    // Watches the polls of the task, if the runtime has a maximum poll
+    // duration.
+    watchdog: Option<watchdog::WatchdogHandle>,
+
+    // Completes the task's `JoinHandle` with `JoinError::Stuck`.
+    stuck: Box<dyn Fn() + Send + Sync>,
+}
+
+impl Task {
+    // Spawns a new taks with the given future.
+    //
+    // Initializes a new Task harness containing the given future and pushes it
+    // onto the scheduled channel of `handle`. The receiver half of the channel
+    // will get the task and execute it.
+    //
+    // Fails if the channel is bounded and full, or if the runtime is shut down.
+    // The task is then dropped without ever being polled.
+    fn spawn<F>(future: F, handle: &Handle) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        // Once shutdown is requested, the executor stops receiving tasks. A
+        // task spawned now would sit in the channel, never to be polled.
+        if handle.closed.load(Ordering::SeqCst) || handle.shutdown.lock().unwrap().is_none() {
+            return Err(SpawnError::Shutdown);
+        }
+
+        let (task, join_handle) = Task::new(future, handle);
+
+        #[cfg(feature = "tracing")]
+        let task_id = task.id;
+
+        match handle.sender.try_send(task) {
+            Ok(()) => {}
+            Err(channel::TrySendError::Full(_)) => return Err(SpawnError::AtCapacity),
+            // The `MiniTokio` instance, which holds the receiver, was dropped.
+            Err(channel::TrySendError::Disconnected(_)) => return Err(SpawnError::Shutdown),
+        }
+
+        if let Some(unpark) = &handle.unpark {
+            unpark.unpark();
+        }
+
+        handle.counters.inc_spawned();
+
+        #[cfg(feature = "tracing")]
+        tracing::trace!(task.id = task_id, "spawn");
+
+        Ok(join_handle)
+    }
+
+    // Like `spawn`, but never fails: the task goes through the overflow queue
+    // if the channel is full. Used where there is no caller to report an error
+    // to, such as when a `DeferAsync` guard is dropped.
+    fn spawn_detached<F>(future: F, handle: &Handle)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let (task, _) = Task::new(future, handle);
+
+        handle.counters.inc_spawned();
+
+        #[cfg(feature = "tracing")]
+        tracing::trace!(task.id = task.id, "spawn");
+
+        task.schedule();
+    }
+
+    // Initializes a new Task harness containing the given future.
+    //
+    // The harness only stores futures with an output of `()`. To support other
+    // output types, the future is wrapped in an `async` block that stores the
+    // output in the slot shared with the returned `JoinHandle`.
+    fn new<F>(future: F, handle: &Handle) -> (Arc<Task>, JoinHandle<F::Output>)
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        // The `JoinHandle` refers to the task, to abort it, and the task's
+        // future holds the `JoinSender`. `new_cyclic` gives access to a `Weak`
+        // reference to the task before it is created.
+        let mut join_handle = None;
+
+        let task = Arc::new_cyclic(|task: &Weak<Task>| {
+            let (join_tx, join) = join::channel(task.clone());
+            let stuck = join.stuck_fn();
+            join_handle = Some(join);
+
+            let mut future: Pin<Box<dyn Future<Output = ()> + Send>> = Box::pin(async move {
+                join_tx.send(future.await);
+            });
+
+            // The hook wraps the task once, here. The task then only ever
+            // polls the wrapper.
+            if let Some(spawn_hook) = &handle.spawn_hook {
+                future = spawn_hook(future);
+            }
+
+            Task {
+                id: NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed),
+                future: Mutex::new(Some(future)),
+                executor: handle.sender.clone(),
+                overflow: handle.overflow.clone(),
+                unpark: handle.unpark.clone(),
+                state: State::new(),
+                aborted: AtomicBool::new(false),
+                counters: handle.counters.clone(),
+                live: handle.live.clone(),
+                watchdog: handle.watchdog.clone(),
+                stuck,
+            }
+        });
+
+        handle.live.inc();
+
+        (task, join_handle.unwrap())
+    }
+
+    // Push the task onto the scheduled channel.
+    //
+    // This is called from wakers, which cannot block nor report an error. If
+    // the channel is bounded and full, the task is pushed onto the overflow
+    // queue instead. The workers move it to the channel once there is room.
+    fn schedule(self: &Arc<Self>) {
+        if let Err(channel::TrySendError::Full(task)) = self.executor.try_send(self.clone()) {
+            self.overflow.lock().unwrap().push_back(task);
+        }
+
+        if let Some(unpark) = &self.unpark {
+            unpark.unpark();
+        }
+    }
+
+    // Called by `AbortHandle::abort`. The task is scheduled so that it gets
+    // polled, which drops its future. If the task is being polled right now,
+    // the wake marks it notified and the next poll drops the future.
+    fn abort(self: &Arc<Self>) {
+        self.aborted.store(true, Ordering::SeqCst);
+        ArcWake::wake_by_ref(self);
+    }
+
+    // Called by the watchdog when a poll of the task takes too long. The
+    // `JoinHandle` is completed first, so it reports the stuck poll rather
+    // than a cancellation. The task is still being polled: the abort takes
+    // effect once that poll returns.
+    fn stuck(self: &Arc<Self>) {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(task.id = self.id, "stuck");
+
+        (self.stuck)();
+        self.abort();
+    }
+
+    // Execute a scheduled task. This creates the necessary `task::Context`
+    // containing a waker for the task. This waker pushes the task onto the
+    // mini-redis scheduled channel. The future is then polled with the waker.
+    fn poll(self: Arc<Self>) {
+        // Everything happening during the poll, including the events emitted
+        // by the task itself, is recorded within this span.
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("poll", task.id = self.id).entered();
+
+        // Get a waker referencing the task.
+        #[cfg(not(feature = "raw-waker"))]
+        let waker = task::waker(self.clone());
+        #[cfg(feature = "raw-waker")]
+        let waker = raw_waker::waker(self.clone());
+        // Initialize the task context with the waker.
+        let mut cx = Context::from_waker(&waker);
+
+        // This never blocks: a task is never in the channel while it is being
+        // polled, so no other worker can be polling it.
+        let mut future = self.future.lock().unwrap();
+
+        // The task is no longer in the channel. From now on, a wake marks the
+        // task notified instead of queuing it.
+        self.state.transition_to_running();
+
+        // The task was aborted. Drop the future instead of polling it. This
+        // drops the `JoinSender`, which reports `JoinError::Cancelled` to the
+        // `JoinHandle`. Nothing happens if the future already completed.
+        if self.aborted.load(Ordering::SeqCst) {
+            if future.take().is_some() {
+                self.counters.inc_completed();
+                self.live.dec();
+
+                #[cfg(feature = "tracing")]
+                tracing::trace!(task.id = self.id, "aborted");
+            }
+
+            self.state.transition_to_complete();
+            return;
+        }
+
+        // Poll the future, unless it already completed.
+        if let Some(fut) = future.as_mut() {
+            // A panic in the future must not unwind into the executor loop:
+            // it would take the worker thread down with it, and every other
+            // task along with it. The panic is caught here and the task is
+            // considered complete. While unwinding, the future drops the
+            // `JoinSender`, which reports `JoinError::Panic` to the
+            // `JoinHandle`.
+            //
+            // `AssertUnwindSafe` is needed as the future is not necessarily
+            // unwind safe. This is fine: the future is dropped right away and
+            // never observed again.
+            //
+            // The task is polled with a fresh coop budget. See the `coop`
+            // module. The watchdog, if any, watches the poll until it returns.
+            let polling = self
+                .watchdog
+                .as_ref()
+                .map(|watchdog| watchdog.polling(&self));
+            let res = self.live.polling(|| {
+                panic::catch_unwind(AssertUnwindSafe(|| {
+                    coop::budget(|| fut.as_mut().poll(&mut cx))
+                }))
+            });
+            drop(polling);
+            self.counters.inc_polls();
+
+            if !matches!(res, Ok(Poll::Pending)) {
+                // The future completed or panicked. Drop it now to release the
+                // resources it holds instead of waiting for the last waker to
+                // go away.
+                *future = None;
+                self.counters.inc_completed();
+                self.live.dec();
+                self.state.transition_to_complete();
+
+                #[cfg(feature = "tracing")]
+                tracing::trace!(task.id = self.id, panicked = res.is_err(), "complete");
+            } else if self.state.transition_to_idle() {
+                // The task was woken while it was being polled. It was not
+                // queued then, so queue it now. The lock is released first so
+                // that the worker receiving the task does not have to wait for
+                // it.
+                drop(future);
+                self.schedule();
+            }
+        }
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        // The task is dropped before its future completed. This happens when
+        // nothing references the task anymore: the task was not scheduled and
+        // no waker is left to schedule it. It can never make progress, so it
+        // no longer counts as live.
+        //
+        // The future is dropped first. Dropping it may spawn new tasks, for
+        // example with `defer_async`, and those must be counted before the
+        // count may reach zero.
+        if self.future.get_mut().unwrap().take().is_some() {
+            self.live.dec();
+
+            // The last reference may have been dropped on another thread,
+            // and the worker may be waiting for the count to reach zero.
+            if let Some(unpark) = &self.unpark {
+                unpark.unpark();
+            }
+        }
+    }
+}
+
+// The standard library provides low-level, unsafe  APIs for defining wakers.
+// Instead of writing unsafe code, we will use the helpers provided by the
+// `futures` crate to define a waker that is able to schedule our `Task`
+// structure.
+impl ArcWake for Task {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(task.id = arc_self.id, "wake");
+
+        arc_self.counters.inc_wakes();
+
+        // Schedule the task for execution. The executor receives from the
+        // channel and polls tasks.
+        //
+        // If the task is already queued, there is nothing to do: it will be
+        // polled, and that poll observes whatever caused this wake. If it is
+        // being polled, the worker queues it once the poll returns.
+        //
+        // The wake may come from a thread that is not a worker, such as the
+        // reactor or a blocking thread. No separate path is needed for it:
+        // idle workers are parked receiving from the channel, and the send
+        // unparks one of them. A worker waiting for I/O events instead is
+        // unparked by `schedule`.
+        if arc_self.state.transition_to_scheduled() {
+            arc_self.schedule();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn a_panicking_task_does_not_stop_the_executor() {
+        let mini_tokio = MiniTokio::new();
+
+        let panicking = mini_tokio.spawn(async {
+            yield_now().await;
+            panic!("boom");
+        });
+        let succeeding = mini_tokio.spawn(async {
+            yield_now().await;
+            yield_now().await;
+            "done"
+        });
+
+        mini_tokio.run_until_idle();
+
+        assert!(matches!(
+            mini_tokio.block_on(panicking),
+            Err(JoinError::Panic)
+        ));
+        assert_eq!(mini_tokio.block_on(succeeding).unwrap(), "done");
+    }
+
+    #[test]
+    fn spawning_onto_a_full_queue_fails() {
+        let mini_tokio = MiniTokio::with_capacity(2);
+
+        mini_tokio.spawn(async {});
+        mini_tokio.spawn(async {});
+
+        assert!(matches!(
+            mini_tokio.try_spawn(async {}),
+            Err(SpawnError::AtCapacity)
+        ));
+
+        // There is room again once the executor received the tasks.
+        mini_tokio.run_until_idle();
+        assert!(mini_tokio.try_spawn(async {}).is_ok());
+    }
+
+    #[test]
+    fn woken_tasks_overflow_a_full_queue() {
+        let mini_tokio = MiniTokio::with_capacity(1);
+        let completed = Arc::new(AtomicUsize::new(0));
+
+        let parent_completed = completed.clone();
+
+        mini_tokio.spawn(async move {
+            let child_completed = parent_completed.clone();
+
+            // The child fills the queue.
+            spawn(async move {
+                child_completed.fetch_add(1, Ordering::SeqCst);
+            });
+
+            // Yielding wakes the parent while the queue is full. The wake
+            // cannot fail: the parent goes to the overflow queue instead.
+            yield_now().await;
+            parent_completed.fetch_add(1, Ordering::SeqCst);
+        });
+
+        mini_tokio.run_until_idle();
+
+        assert_eq!(completed.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn handles_spawn_from_plain_threads() {
+        let mini_tokio = MiniTokio::new();
+        let handle = mini_tokio.handle();
+
+        let join_handle = thread::spawn(move || handle.spawn(async { 6 * 7 }))
+            .join()
+            .unwrap();
+
+        assert_eq!(mini_tokio.block_on(join_handle).unwrap(), 42);
+
+        // Once the instance is shut down, spawning fails instead of losing
+        // the task.
+        let handle = mini_tokio.handle();
+        mini_tokio.shutdown();
+
+        let res = thread::spawn(move || handle.try_spawn(async {}).map(drop))
+            .join()
+            .unwrap();
+
+        assert!(matches!(res, Err(SpawnError::Shutdown)));
+    }
+
+    #[test]
+    fn try_spawn_outside_of_a_runtime_fails() {
+        assert!(matches!(try_spawn(async {}), Err(SpawnError::NotInRuntime)));
+    }
+
+    #[test]
+    #[should_panic(expected = "spawn called outside of a MiniTokio runtime")]
+    fn spawn_outside_of_a_runtime_panics() {
+        spawn(async {});
+    }
+
+    #[test]
+    fn waking_a_queued_task_does_not_queue_it_again() {
+        let mini_tokio = MiniTokio::new();
+        let polls = Arc::new(AtomicUsize::new(0));
+        let (waker_tx, waker_rx) = oneshot::channel();
+
+        let task_polls = polls.clone();
+        let mut waker_tx = Some(waker_tx);
+
+        mini_tokio.spawn(future::poll_fn(move |cx| {
+            task_polls.fetch_add(1, Ordering::SeqCst);
+
+            // Hand the waker out on the first poll, complete on the second.
+            match waker_tx.take() {
+                Some(tx) => {
+                    let _ = tx.send(cx.waker().clone());
+                    Poll::Pending
+                }
+                None => Poll::Ready(()),
+            }
+        }));
+
+        let waker = mini_tokio.block_on(waker_rx).unwrap();
+        assert!(mini_tokio.scheduled.is_empty());
+
+        for _ in 0..3 {
+            waker.wake_by_ref();
+        }
+
+        assert_eq!(mini_tokio.scheduled.len(), 1);
+
+        mini_tokio.run_until_idle();
+        assert_eq!(polls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn multi_thread_runs_every_task() {
+        let mini_tokio = MiniTokio::new_multi_thread(4);
+        let completed = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..1000 {
+            let completed = completed.clone();
+
+            mini_tokio.spawn(async move {
+                // Spawning works from any worker.
+                spawn(async move {
+                    yield_now().await;
+                    completed.fetch_add(1, Ordering::SeqCst);
+                });
+            });
+        }
+
+        mini_tokio.run_until_idle();
+
+        assert_eq!(completed.load(Ordering::SeqCst), 1000);
+    }
+
+    #[test]
+    fn a_single_task_unparks_a_single_worker() {
+        let mini_tokio = MiniTokio::new_multi_thread(4);
+        let handle = mini_tokio.handle();
+        let parked = &mini_tokio.parked;
+
+        thread::scope(|scope| {
+            scope.spawn(|| mini_tokio.run());
+
+            // Wait for every worker to block on the channels.
+            while parked.waiting.load(Ordering::SeqCst) < 4 {
+                thread::yield_now();
+            }
+
+            let (done_tx, done_rx) = std::sync::mpsc::channel();
+            handle.spawn(async move { done_tx.send(()).unwrap() });
+            let done = done_rx.recv_timeout(Duration::from_secs(5));
+
+            mini_tokio.shutdown();
+            done.unwrap();
+        });
+
+        // The other workers kept waiting: nothing woke them only to find the
+        // task gone.
+        assert_eq!(parked.unparked.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn block_on_accepts_non_send_futures() {
+        let mini_tokio = MiniTokio::new();
+
+        // Neither `Send`, because of the `Rc`, nor `'static`, because of the
+        // borrow of `local`.
+        let local = String::from("borrowed");
+        let counter = Rc::new(Cell::new(0));
+
+        let len = mini_tokio.block_on(async {
+            counter.set(counter.get() + 1);
+            yield_now().await;
+            counter.set(counter.get() + 1);
+            local.len()
+        });
+
+        assert_eq!(len, 8);
+        assert_eq!(counter.get(), 2);
+    }
+
+    #[test]
+    fn block_on_polls_the_root_on_the_calling_thread() {
+        let caller = thread::current().id();
+
+        for workers in [1, 2] {
+            let mini_tokio = MiniTokio::new_multi_thread(workers);
+
+            let id = mini_tokio.block_on(async {
+                spawn(async {}).await.unwrap();
+                thread::current().id()
+            });
+
+            assert_eq!(id, caller);
+        }
+    }
+
+    #[test]
+    fn aborting_a_task_drops_its_future() {
+        struct SetOnDrop(Arc<AtomicBool>);
+
+        impl Drop for SetOnDrop {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let mini_tokio = Builder::new().clock(MockClock::new()).build();
+        let (started_tx, started_rx) = oneshot::channel();
+        let dropped = Arc::new(AtomicBool::new(false));
+
+        let guard = SetOnDrop(dropped.clone());
+
+        let handle = mini_tokio.spawn(async move {
+            let _guard = guard;
+            let _ = started_tx.send(());
+
+            // The clock never moves: only aborting ends this task.
+            delay(Duration::from_secs(60)).await;
+        });
+
+        // The task parks on its delay in the same poll it reports starting.
+        mini_tokio.block_on(started_rx).unwrap();
+        assert!(!dropped.load(Ordering::SeqCst));
+
+        handle.abort();
+        mini_tokio.run_until_idle();
+
+        // The future, and everything it held, was dropped on the task's next
+        // poll, without waiting for the delay or for the instance to go away.
+        assert!(dropped.load(Ordering::SeqCst));
+        assert!(matches!(
+            mini_tokio.block_on(handle),
+            Err(JoinError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn shutdown_signal_lets_tasks_clean_up() {
+        let mini_tokio = MiniTokio::new();
+        let handle = mini_tokio.handle();
+        let (started_tx, started_rx) = oneshot::channel();
+        let cleaned_up = Arc::new(AtomicBool::new(false));
+
+        let signal = handle.shutdown_signal();
+        let task_cleaned_up = cleaned_up.clone();
+
+        mini_tokio.spawn(async move {
+            let _ = started_tx.send(());
+            signal.await;
+            task_cleaned_up.store(true, Ordering::SeqCst);
+        });
+
+        // The task parks on the signal in the same poll it reports starting.
+        mini_tokio.block_on(started_rx).unwrap();
+        assert!(!cleaned_up.load(Ordering::SeqCst));
+
+        handle.shutdown();
+        mini_tokio.run();
+
+        assert!(cleaned_up.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn run_isolated_runs_on_its_own_thread_and_instance() {
+        let mini_tokio = MiniTokio::new();
+        let caller = thread::current().id();
+
+        let isolated = mini_tokio.block_on(async move {
+            let outer = CURRENT.with(|cell| cell.borrow().clone().unwrap());
+
+            run_isolated(async move {
+                let inner = CURRENT.with(|cell| cell.borrow().clone().unwrap());
+                let (sum, id) = spawn(async { ((1..=10).sum::<u32>(), thread::current().id()) })
+                    .await
+                    .unwrap();
+
+                (sum, id, Arc::ptr_eq(&inner.live, &outer.live))
+            })
+        });
+
+        let (sum, id, same_instance) = isolated.join().unwrap();
+
+        assert_eq!(sum, 55);
+        assert_ne!(id, caller);
+        assert!(!same_instance);
+    }
+
+    #[test]
+    fn shutdown_graceful_waits_for_every_task() {
+        let mini_tokio = MiniTokio::new();
+        let release = CancellationToken::new();
+        let completed = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..3 {
+            let release = release.clone();
+            let completed = completed.clone();
+
+            mini_tokio.spawn(async move {
+                release.cancelled().await;
+                completed.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        let (summary, refused) = mini_tokio.block_on(async {
+            // `join!` polls the graceful shutdown first, while every task
+            // is still waiting, and only then lets the tasks complete.
+            futures::join!(mini_tokio.shutdown_graceful(), async {
+                release.cancel();
+                mini_tokio.try_spawn(async {}).is_err()
+            })
+        });
+
+        assert!(refused);
+        assert_eq!(completed.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            summary,
+            ShutdownSummary {
+                completed: 3,
+                remaining: 0
+            }
+        );
+        assert!(matches!(
+            mini_tokio.try_spawn(async {}),
+            Err(SpawnError::Shutdown)
+        ));
+    }
+
+    #[test]
+    fn shutdown_graceful_from_a_task() {
+        let mini_tokio = MiniTokio::new();
+        let handle = mini_tokio.handle();
+
+        for _ in 0..2 {
+            mini_tokio.spawn(yield_now());
+        }
+
+        let supervisor = mini_tokio.spawn(async move { handle.shutdown_graceful().await });
+
+        // Returns once the supervisor shut the executor down.
+        mini_tokio.run();
+
+        let summary = mini_tokio.block_on(supervisor).unwrap();
+        assert_eq!(
+            summary,
+            ShutdownSummary {
+                completed: 2,
+                remaining: 1
+            }
+        );
+    }
+
+    #[test]
+    fn block_on_leaves_pending_children_detached() {
+        let mini_tokio = MiniTokio::new();
+        let (tx, rx) = oneshot::channel::<()>();
+        let done = Arc::new(AtomicUsize::new(0));
+
+        let child_done = done.clone();
+
+        mini_tokio.block_on(async move {
+            spawn(async move {
+                let _ = rx.await;
+                child_done.fetch_add(1, Ordering::SeqCst);
+            });
+        });
+
+        // The root future completed, but the child is still waiting.
+        assert_eq!(done.load(Ordering::SeqCst), 0);
+
+        // It resumes the next time the executor runs.
+        tx.send(()).unwrap();
+        mini_tokio.run_until_idle();
+
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+    }
+
+    // Records the `poll` spans and the events emitted while installed, each as
+    // its name followed by its `task.id` field.
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct Capture {
+        next_id: Arc<AtomicUsize>,
+        records: Arc<Mutex<Vec<(String, u64)>>>,
+    }
+
+    #[cfg(feature = "tracing")]
+    impl Capture {
+        fn push(&self, name: &str, values: &dyn Fn(&mut dyn tracing::field::Visit)) {
+            struct TaskId(Option<u64>);
+
+            impl tracing::field::Visit for TaskId {
+                fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+                    if field.name() == "task.id" {
+                        self.0 = Some(value);
+                    }
+                }
+
+                fn record_debug(&mut self, _: &tracing::field::Field, _: &dyn std::fmt::Debug) {}
+            }
+
+            let mut task_id = TaskId(None);
+            values(&mut task_id);
+
+            if let Some(id) = task_id.0 {
+                self.records.lock().unwrap().push((name.to_string(), id));
+            }
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for Capture {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            self.push(span.metadata().name(), &|visitor| span.record(visitor));
+
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            tracing::span::Id::from_u64(id as u64 + 1)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            struct Message(String);
+
+            impl tracing::field::Visit for Message {
+                fn record_debug(
+                    &mut self,
+                    field: &tracing::field::Field,
+                    value: &dyn std::fmt::Debug,
+                ) {
+                    if field.name() == "message" {
+                        self.0 = format!("{:?}", value);
+                    }
+                }
+            }
+
+            let mut message = Message(String::new());
+            event.record(&mut message);
+
+            self.push(&message.0, &|visitor| event.record(visitor));
+        }
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn polls_of_a_spawned_task_are_traced() {
+        let capture = Capture::default();
+        let mini_tokio = MiniTokio::new();
+
+        // The only worker is the thread calling `run_until_idle`, so the
+        // subscriber sees every poll.
+        tracing::subscriber::with_default(capture.clone(), || {
+            mini_tokio.spawn(async { yield_now().await });
+            mini_tokio.run_until_idle();
+        });
+
+        let records = capture.records.lock().unwrap();
+        let id = match records.first() {
+            Some((name, id)) if name == "spawn" => *id,
+            other => panic!("expected a spawn event first, got {:?}", other),
+        };
+
+        // Yielding wakes the task, which is then polled a second time.
+        let names: Vec<_> = records
+            .iter()
+            .filter(|(_, task_id)| *task_id == id)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["spawn", "poll", "wake", "poll", "complete"]);
+    }
+}
@@ -0,0 +1,343 @@
+//! Running `!Send` futures.
+//!
+//! `spawn` requires futures to be `Send`, as the task may be polled by any
+//! worker thread. A future holding an `Rc`, or a `RefCell` borrowed across an
+//! `.await`, is not `Send` and cannot be spawned, even on a single-threaded
+//! runtime.
+//!
+//! A `LocalSet` is a set of tasks that are all polled on the thread running
+//! the set. Since they never move to another thread, they do not need to be
+//! `Send`. The set needs its own task harness: `Task` holds its future behind
+//! a `Mutex` and is referenced from wakers, which must be `Send` and `Sync`.
+//! Here, the futures stay in the set, on its thread, and wakers only carry the
+//! task's id.
+//!
+//! The set is itself a future, returned by `run_until`. Whoever polls it, on
+//! its thread, polls the set's tasks in turn. Since it is not `Send` either,
+//! that is usually the root future of `MiniTokio::block_on`.
+
+use crate::coop;
+use crate::join::{self, JoinHandle};
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::future::{self, Future};
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::{pin, Pin};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+
+use crossbeam::channel;
+use futures::task::{self, ArcWake};
+
+/// A set of tasks executed on the current thread.
+///
+/// Tasks are spawned onto the set with `spawn_local` and run while the
+/// future returned by `run_until` is being awaited.
+pub struct LocalSet {
+    shared: Rc<Shared>,
+}
+
+// State shared by the `LocalSet` and the `spawn_local` function.
+struct Shared {
+    // The futures of the tasks that are not being polled. A task is removed
+    // from the map while it is polled, so that it can spawn new tasks.
+    tasks: RefCell<HashMap<usize, LocalFuture>>,
+
+    // Id of the next task. Id 0 is used by the future passed to `run_until`.
+    next_id: Cell<usize>,
+
+    // Woken tasks are identified by sending their id on this channel. Unlike
+    // the futures, the channel is `Send`: a task may be woken from another
+    // thread, for example by the timer driver.
+    woken_tx: channel::Sender<usize>,
+    woken_rx: channel::Receiver<usize>,
+
+    // The waker of the task awaiting `run_until`, woken along with the set's
+    // tasks. `None` while the set is not running.
+    parent: Parent,
+}
+
+type Parent = Arc<Mutex<Option<std::task::Waker>>>;
+
+// The future of a task in a `LocalSet`. Unlike `Task`'s, it is not `Send`.
+type LocalFuture = Pin<Box<dyn Future<Output = ()>>>;
+
+// The waker of a task in a `LocalSet`.
+struct Waker {
+    id: usize,
+    woken: channel::Sender<usize>,
+    parent: Parent,
+}
+
+// The id of the future passed to `run_until`.
+const ROOT: usize = 0;
+
+thread_local! {
+    // The `LocalSet` running on the current thread, used by `spawn_local`.
+    static CURRENT: RefCell<Option<Rc<Shared>>> = const { RefCell::new(None) };
+}
+
+// Spawns a `!Send` future onto the `LocalSet` running on the current thread.
+//
+// Panics if called from outside of `LocalSet::run_until`, i.e. if the current
+// thread is not polling a `LocalSet`.
+pub fn spawn_local<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    CURRENT.with(|cell| {
+        let borrow = cell.borrow();
+        let shared = borrow
+            .as_ref()
+            .expect("spawn_local called outside of a running LocalSet");
+        shared.spawn(future)
+    })
+}
+
+impl LocalSet {
+    /// Creates an empty set.
+    pub fn new() -> LocalSet {
+        let (woken_tx, woken_rx) = channel::unbounded();
+
+        LocalSet {
+            shared: Rc::new(Shared {
+                tasks: RefCell::new(HashMap::new()),
+                next_id: Cell::new(ROOT + 1),
+                woken_tx,
+                woken_rx,
+                parent: Arc::new(Mutex::new(None)),
+            }),
+        }
+    }
+
+    /// Spawns a `!Send` future onto the set. It runs the next time the set
+    /// runs.
+    pub fn spawn_local<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        self.shared.spawn(future)
+    }
+
+    /// Runs the tasks of the set until `future` completes, and returns its
+    /// output.
+    ///
+    /// `future` may use `spawn_local` to spawn more tasks onto the set. Tasks
+    /// that have not completed when `future` does are left in the set, and
+    /// resume the next time the set runs.
+    ///
+    /// This does not block: the set's tasks run while the returned future is
+    /// polled, on the thread polling it, and the future returns
+    /// `Poll::Pending` once none of them can make progress. It is not `Send`,
+    /// so it is usually passed to `MiniTokio::block_on`. The executor's other
+    /// tasks keep running in between polls, and the set's tasks may use
+    /// timers and the other mini-tokio resources.
+    pub async fn run_until<F: Future>(&self, future: F) -> F::Output {
+        let mut future = pin!(future);
+
+        // The root future is polled first.
+        let _ = self.shared.woken_tx.send(ROOT);
+
+        let output = future::poll_fn(|cx| self.poll_until(future.as_mut(), cx)).await;
+
+        // Wakes no longer concern the task that awaited the set.
+        self.shared.parent.lock().unwrap().take();
+
+        output
+    }
+
+    // Polls the woken tasks of the set, and `future` if it was woken.
+    fn poll_until<F: Future>(
+        &self,
+        mut future: Pin<&mut F>,
+        cx: &mut Context<'_>,
+    ) -> Poll<F::Output> {
+        // Restores the previous `LocalSet` when dropped, including when
+        // `future` panics.
+        struct Reset(Option<Rc<Shared>>);
+
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                CURRENT.with(|cell| *cell.borrow_mut() = self.0.take());
+            }
+        }
+
+        let _reset = Reset(CURRENT.with(|cell| cell.replace(Some(self.shared.clone()))));
+
+        // From now on, waking a task of the set also wakes the caller. The
+        // waker is stored before looking for woken tasks, so that a wake
+        // coming in between is not lost.
+        *self.shared.parent.lock().unwrap() = Some(cx.waker().clone());
+
+        // Only the tasks woken so far are polled. A task that keeps waking
+        // itself would otherwise keep this poll running forever, starving the
+        // executor's other tasks.
+        for _ in 0..self.shared.woken_rx.len() {
+            let id = match self.shared.woken_rx.try_recv() {
+                Ok(id) => id,
+                Err(_) => break,
+            };
+
+            if id == ROOT {
+                let waker = self.shared.waker(ROOT);
+                let mut cx = Context::from_waker(&waker);
+
+                if let Poll::Ready(output) = coop::budget(|| future.as_mut().poll(&mut cx)) {
+                    return Poll::Ready(output);
+                }
+            } else {
+                self.shared.poll(id);
+            }
+        }
+
+        // Tasks spawned or woken during this poll are polled the next time
+        // around. Spawning does not go through a waker, so the caller is
+        // woken here.
+        if !self.shared.woken_rx.is_empty() {
+            cx.waker().wake_by_ref();
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Default for LocalSet {
+    fn default() -> LocalSet {
+        LocalSet::new()
+    }
+}
+
+impl Shared {
+    fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        // Local tasks cannot be aborted: the `JoinHandle` refers to no `Task`.
+        let (join_tx, join_handle) = join::channel(Weak::new());
+
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+
+        self.tasks.borrow_mut().insert(
+            id,
+            Box::pin(async move {
+                join_tx.send(future.await);
+            }),
+        );
+
+        // The task is polled once the set gets to it.
+        let _ = self.woken_tx.send(id);
+
+        join_handle
+    }
+
+    // Polls the task with the given id.
+    fn poll(&self, id: usize) {
+        // Take the task's future out of the map. If it is not there, the task
+        // already completed and this is a late wake.
+        let mut future = match self.tasks.borrow_mut().remove(&id) {
+            Some(future) => future,
+            None => return,
+        };
+
+        let waker = self.waker(id);
+        let mut cx = Context::from_waker(&waker);
+
+        // As in `Task::poll`, panics are caught so they do not unwind through
+        // the set, and the task is polled with a fresh coop budget.
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            coop::budget(|| future.as_mut().poll(&mut cx))
+        }));
+
+        if let Ok(Poll::Pending) = res {
+            // Put the future back, to be polled on the next wake.
+            self.tasks.borrow_mut().insert(id, future);
+        }
+    }
+
+    fn waker(&self, id: usize) -> std::task::Waker {
+        task::waker(Arc::new(Waker {
+            id,
+            woken: self.woken_tx.clone(),
+            parent: self.parent.clone(),
+        }))
+    }
+}
+
+impl ArcWake for Waker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        let _ = arc_self.woken.send(arc_self.id);
+
+        // The waker is cloned so that it is woken outside of the lock.
+        let parent = arc_self.parent.lock().unwrap().clone();
+
+        if let Some(parent) = parent {
+            parent.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{oneshot, spawn, spawn_local, LocalSet, MiniTokio};
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn local_tasks_may_hold_rcs() {
+        let mini_tokio = MiniTokio::new();
+        let local = LocalSet::new();
+        let value = Rc::new(RefCell::new(0));
+
+        mini_tokio.block_on(local.run_until(async {
+            let mut handles = vec![];
+
+            for i in 1..=3 {
+                let value = value.clone();
+
+                handles.push(spawn_local(async move {
+                    *value.borrow_mut() += i;
+                }));
+            }
+
+            for handle in handles {
+                handle.await.unwrap();
+            }
+        }));
+
+        assert_eq!(*value.borrow(), 6);
+    }
+
+    #[test]
+    fn the_executor_keeps_running_while_the_set_waits() {
+        let mini_tokio = MiniTokio::new();
+        let local = LocalSet::new();
+
+        let output = mini_tokio.block_on(local.run_until(async {
+            let (tx, rx) = oneshot::channel();
+
+            // The local task waits on a regular task, which only runs if the
+            // set does not block the thread.
+            let local_task = spawn_local(async move { rx.await.unwrap() });
+            spawn(async move {
+                let _ = tx.send("from a task");
+            });
+
+            local_task.await.unwrap()
+        }));
+
+        assert_eq!(output, "from a task");
+    }
+
+    #[test]
+    #[should_panic(expected = "spawn_local called outside of a running LocalSet")]
+    fn spawn_local_outside_of_a_set_panics() {
+        spawn_local(async {});
+    }
+}
@@ -0,0 +1,132 @@
+//! Runtime metrics.
+//!
+//! A runtime is a black box unless it reports what it is doing. The executor
+//! keeps a few counters, updated as tasks are spawned and polled. They are
+//! atomics, so updating them from any worker thread only costs a few
+//! instructions and reading them never blocks the executor.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A snapshot of the counters of a mini-tokio instance, returned by
+/// `MiniTokio::metrics`.
+///
+/// The counters are read one at a time while the executor keeps running, so a
+/// snapshot taken on a busy runtime may be slightly inconsistent. For example,
+/// a task completing in between two reads may be counted as completed without
+/// its last poll being counted.
+#[derive(Debug, Clone)]
+pub struct RuntimeMetrics {
+    /// Number of tasks spawned since the instance was created.
+    pub tasks_spawned: u64,
+
+    /// Number of tasks whose future completed, panicked or was aborted.
+    pub tasks_completed: u64,
+
+    /// Number of tasks currently waiting in the scheduled channel.
+    pub current_scheduled_len: usize,
+
+    /// Number of times a task's future has been polled.
+    pub total_polls: u64,
+
+    /// Number of times a task has been woken. Wakes of a task that is already
+    /// scheduled are counted too, even though they do not lead to another
+    /// poll: comparing this with `total_polls` shows how many wakes were
+    /// coalesced.
+    pub total_wakes: u64,
+}
+
+// The counters backing `RuntimeMetrics`. Shared by the handle and every task of
+// a mini-tokio instance.
+//
+// The counters are statistics: nothing synchronizes on them, so `Relaxed`
+// ordering is enough.
+#[derive(Default)]
+pub(crate) struct Counters {
+    tasks_spawned: AtomicU64,
+    tasks_completed: AtomicU64,
+    total_polls: AtomicU64,
+    total_wakes: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn inc_spawned(&self) {
+        self.tasks_spawned.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_completed(&self) {
+        self.tasks_completed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_polls(&self) {
+        self.total_polls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_wakes(&self) {
+        self.total_wakes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self, current_scheduled_len: usize) -> RuntimeMetrics {
+        RuntimeMetrics {
+            tasks_spawned: self.tasks_spawned.load(Ordering::Relaxed),
+            tasks_completed: self.tasks_completed.load(Ordering::Relaxed),
+            current_scheduled_len,
+            total_polls: self.total_polls.load(Ordering::Relaxed),
+            total_wakes: self.total_wakes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{yield_now, MiniTokio};
+
+    use std::future;
+    use std::task::Poll;
+
+    #[test]
+    fn every_spawned_task_is_counted_as_completed() {
+        let mini_tokio = MiniTokio::new();
+
+        for _ in 0..10 {
+            mini_tokio.spawn(yield_now());
+        }
+
+        let before = mini_tokio.metrics();
+        assert_eq!(before.tasks_spawned, 10);
+        assert_eq!(before.tasks_completed, 0);
+        assert_eq!(before.current_scheduled_len, 10);
+
+        mini_tokio.run_until_idle();
+
+        let after = mini_tokio.metrics();
+        assert_eq!(after.tasks_completed, after.tasks_spawned);
+        assert_eq!(after.current_scheduled_len, 0);
+
+        // Each task is polled twice: once to yield, once to complete.
+        assert_eq!(after.total_polls, 20);
+    }
+
+    #[test]
+    fn coalesced_wakes_are_counted_without_extra_polls() {
+        let mini_tokio = MiniTokio::new();
+        let mut polled = false;
+
+        mini_tokio.spawn(future::poll_fn(move |cx| {
+            if polled {
+                return Poll::Ready(());
+            }
+
+            polled = true;
+            for _ in 0..3 {
+                cx.waker().wake_by_ref();
+            }
+            Poll::Pending
+        }));
+
+        mini_tokio.run_until_idle();
+
+        let metrics = mini_tokio.metrics();
+        assert_eq!(metrics.total_wakes, 3);
+        assert_eq!(metrics.total_polls, 2);
+    }
+}
@@ -0,0 +1,592 @@
+//! A bounded, multi-producer, single-consumer channel.
+//!
+//! This is the mini-tokio equivalent of `tokio::sync::mpsc::channel`. Values
+//! are queued in a `VecDeque` holding at most `capacity` values. The channel
+//! provides backpressure: once the queue is full, `Sender::send` does not
+//! complete until the receiver has made room. A producer that is faster than
+//! its consumer is slowed down to the consumer's pace instead of filling memory
+//! with values nobody has processed yet.
+//!
+//! Both sides are leaf futures. The receiver stores its waker when the queue
+//! is empty and is woken by the next send. A sender stores its waker when the
+//! queue is full and is woken by the next receive.
+//!
+//! Wakers are always taken out of the channel and woken after its lock is
+//! released. A waker may do anything, including polling the other half of the
+//! channel on the same thread, which would deadlock on the lock.
+
+use crate::{coop, time, Elapsed};
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::future;
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+/// Sends values to the associated `Receiver`. Created by `channel`.
+///
+/// Clone the sender to send from several tasks. The channel is closed once
+/// every sender has been dropped.
+pub struct Sender<T> {
+    chan: Arc<Mutex<Chan<T>>>,
+}
+
+/// A sender that does not keep the channel open. Created by
+/// `Sender::downgrade`.
+///
+/// Once every `Sender` is dropped, the channel is closed, even if weak
+/// senders remain: the receiver gets `None`. A weak sender must be upgraded to
+/// a `Sender` to send, which only succeeds while the channel is open.
+pub struct WeakSender<T> {
+    chan: Arc<Mutex<Chan<T>>>,
+}
+
+/// Receives values sent by the associated `Sender`s. Created by `channel`.
+pub struct Receiver<T> {
+    chan: Arc<Mutex<Chan<T>>>,
+}
+
+/// Error returned by `Sender::send` when the `Receiver` has been dropped. The
+/// value that could not be sent is returned with it.
+#[derive(Debug)]
+pub struct SendError<T>(pub T);
+
+/// Error returned by `Sender::ready` when the `Receiver` has been dropped.
+#[derive(Debug)]
+pub struct ClosedError(());
+
+// State shared by all the halves.
+struct Chan<T> {
+    // Values sent but not received yet. Never holds more than `capacity`.
+    queue: VecDeque<T>,
+
+    capacity: usize,
+
+    // The waker of the task waiting in `recv`, if any.
+    rx_waker: Option<Waker>,
+
+    // The wakers of the tasks waiting in `send` for room in the queue.
+    tx_wakers: Vec<Waker>,
+
+    // Number of live senders. The channel is closed once it drops to zero.
+    senders: usize,
+
+    // Set when the `Receiver` is dropped.
+    rx_closed: bool,
+}
+
+/// Creates a bounded channel holding at most `capacity` values, returning the
+/// two halves.
+///
+/// # Panics
+///
+/// Panics if `capacity` is zero.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "capacity must be non-zero");
+
+    let chan = Arc::new(Mutex::new(Chan {
+        queue: VecDeque::with_capacity(capacity),
+        capacity,
+        rx_waker: None,
+        tx_wakers: vec![],
+        senders: 1,
+        rx_closed: false,
+    }));
+
+    let tx = Sender { chan: chan.clone() };
+
+    (tx, Receiver { chan })
+}
+
+impl<T> Sender<T> {
+    /// Sends `value`, waiting for room in the queue if it is full.
+    ///
+    /// Fails if the `Receiver` has been dropped.
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut value = Some(value);
+
+        future::poll_fn(|cx| self.poll_send(cx, &mut value)).await
+    }
+
+    // Queues the value in `slot` if there is room. Otherwise, stores the
+    // waker to be woken once there may be.
+    fn poll_send(
+        &self,
+        cx: &mut Context<'_>,
+        slot: &mut Option<T>,
+    ) -> Poll<Result<(), SendError<T>>> {
+        let mut chan = self.chan.lock().unwrap();
+
+        if chan.rx_closed {
+            return Poll::Ready(Err(SendError(slot.take().unwrap())));
+        }
+
+        if chan.queue.len() == chan.capacity {
+            // The queue is full: this is where backpressure happens. The task
+            // is parked until the receiver takes a value out.
+            if !chan.tx_wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                chan.tx_wakers.push(cx.waker().clone());
+            }
+
+            return Poll::Pending;
+        }
+
+        if coop::poll_proceed(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        chan.queue.push_back(slot.take().unwrap());
+
+        // Wake the receiver, in case it was waiting for a value.
+        let waker = chan.rx_waker.take();
+        drop(chan);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T> Sender<T> {
+    /// Waits until the queue has room for a value.
+    ///
+    /// Fails if the `Receiver` has been dropped.
+    ///
+    /// The room is not reserved: another sender may fill it before this one
+    /// sends, in which case `send` waits again. `ready` tells a producer that
+    /// the consumer is keeping up, so it can hold off preparing a value while
+    /// it is not.
+    pub async fn ready(&self) -> Result<(), ClosedError> {
+        future::poll_fn(|cx| self.poll_ready(cx)).await
+    }
+
+    /// Polls for room in the queue. See `ready`.
+    pub fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), ClosedError>> {
+        let mut chan = self.chan.lock().unwrap();
+
+        if chan.rx_closed {
+            return Poll::Ready(Err(ClosedError(())));
+        }
+
+        if chan.queue.len() == chan.capacity {
+            if !chan.tx_wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                chan.tx_wakers.push(cx.waker().clone());
+            }
+
+            return Poll::Pending;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Returns a `WeakSender` for the same channel.
+    ///
+    /// This is useful for a task that needs to send on the channel but must
+    /// not prevent it from closing, such as a background task started by the
+    /// owner of the channel.
+    pub fn downgrade(&self) -> WeakSender<T> {
+        WeakSender {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> WeakSender<T> {
+    /// Returns a `Sender` for the channel, or `None` if every `Sender` has
+    /// been dropped.
+    ///
+    /// A closed channel stays closed: the receiver has been told that no more
+    /// values are coming, so the weak sender cannot revive it.
+    pub fn upgrade(&self) -> Option<Sender<T>> {
+        let mut chan = self.chan.lock().unwrap();
+
+        if chan.senders == 0 {
+            return None;
+        }
+
+        chan.senders += 1;
+
+        Some(Sender {
+            chan: self.chan.clone(),
+        })
+    }
+}
+
+impl<T> Clone for WeakSender<T> {
+    fn clone(&self) -> WeakSender<T> {
+        WeakSender {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.chan.lock().unwrap().senders += 1;
+
+        Sender {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut chan = self.chan.lock().unwrap();
+        chan.senders -= 1;
+
+        // The last sender is gone. Wake the receiver so that it sees the
+        // channel is closed once the queue is empty.
+        if chan.senders == 0 {
+            let waker = chan.rx_waker.take();
+            drop(chan);
+
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Receives the next value, waiting for one if the queue is empty.
+    ///
+    /// Returns `None` once every sender has been dropped and every value sent
+    /// before that has been received.
+    pub async fn recv(&mut self) -> Option<T> {
+        future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Receives the next value, waiting at most `dur` for one.
+    ///
+    /// Returns `Err(Elapsed)` if no value arrived in time, which lets a
+    /// consumer do some periodic work while the channel is idle. No value is
+    /// lost when that happens: a value is only taken out of the queue when it
+    /// is returned, and a value arriving just as `dur` elapses is returned
+    /// rather than `Elapsed`.
+    pub async fn recv_timeout(&mut self, dur: Duration) -> Result<Option<T>, Elapsed> {
+        time::timeout(dur, self.recv()).await
+    }
+
+    /// Polls to receive the next value.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut chan = self.chan.lock().unwrap();
+
+        if chan.queue.is_empty() {
+            if chan.senders == 0 {
+                return Poll::Ready(None);
+            }
+
+            chan.rx_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        if coop::poll_proceed(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let value = chan.queue.pop_front();
+
+        // There is room in the queue again.
+        wake_senders(chan);
+
+        Poll::Ready(value)
+    }
+
+    /// Receives up to `limit` values at once, appending them to `buf`, and
+    /// returns how many were received.
+    ///
+    /// Waits until at least one value is queued, then takes as many of the
+    /// queued values as `limit` allows. A consumer processing values in
+    /// batches saves an `.await` per value. Returns 0 once every sender has
+    /// been dropped and every value sent before that has been received, or
+    /// right away if `limit` is 0.
+    pub async fn recv_many(&mut self, buf: &mut Vec<T>, limit: usize) -> usize {
+        future::poll_fn(|cx| self.poll_recv_many(cx, buf, limit)).await
+    }
+
+    /// Polls to receive up to `limit` values. See `recv_many`.
+    pub fn poll_recv_many(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut Vec<T>,
+        limit: usize,
+    ) -> Poll<usize> {
+        if limit == 0 {
+            return Poll::Ready(0);
+        }
+
+        let mut chan = self.chan.lock().unwrap();
+
+        if chan.queue.is_empty() {
+            if chan.senders == 0 {
+                return Poll::Ready(0);
+            }
+
+            chan.rx_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        if coop::poll_proceed(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let n = limit.min(chan.queue.len());
+        buf.extend(chan.queue.drain(..n));
+
+        wake_senders(chan);
+
+        Poll::Ready(n)
+    }
+
+    /// Takes every value currently queued, without waiting.
+    ///
+    /// Only the values queued when `drain` is called are returned: values sent
+    /// afterwards are left for the next receive. This is meant for shutdown,
+    /// to process whatever is left in one go. Unlike `recv`, an empty vector
+    /// does not mean the channel is closed.
+    pub fn drain(&mut self) -> Vec<T> {
+        let mut chan = self.chan.lock().unwrap();
+        let values: Vec<T> = chan.queue.drain(..).collect();
+
+        if !values.is_empty() {
+            wake_senders(chan);
+        }
+
+        values
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut chan = self.chan.lock().unwrap();
+        chan.rx_closed = true;
+
+        // Wake the waiting senders so that they fail instead of waiting for
+        // room that will never come.
+        wake_senders(chan);
+    }
+}
+
+// Wakes every sender waiting for room in the queue, after releasing the lock.
+//
+// Every waiting sender is woken rather than just one: a woken sender may have
+// given up on sending in the meantime, and waking only that one would leave
+// the others parked with room available. The senders that lose the race for
+// the free slots store their waker again.
+fn wake_senders<T>(mut chan: MutexGuard<'_, Chan<T>>) {
+    let wakers = mem::take(&mut chan.tx_wakers);
+    drop(chan);
+
+    for waker in wakers {
+        waker.wake();
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "channel closed".fmt(fmt)
+    }
+}
+
+impl<T: fmt::Debug> Error for SendError<T> {}
+
+impl fmt::Display for ClosedError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "channel closed".fmt(fmt)
+    }
+}
+
+impl Error for ClosedError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{noop_context, spawn, yield_now, MiniTokio};
+
+    use std::future::Future;
+    use std::pin::pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn send_waits_for_room_once_full() {
+        let (tx, mut rx) = channel(2);
+        let mut cx = noop_context();
+
+        let mut first = pin!(tx.send(1));
+        let mut second = pin!(tx.send(2));
+        let mut third = pin!(tx.send(3));
+
+        assert!(first.as_mut().poll(&mut cx).is_ready());
+        assert!(second.as_mut().poll(&mut cx).is_ready());
+        assert!(third.as_mut().poll(&mut cx).is_pending());
+
+        assert_eq!(rx.poll_recv(&mut cx), Poll::Ready(Some(1)));
+        assert!(third.as_mut().poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn producer_runs_at_the_consumer_pace() {
+        let mini_tokio = MiniTokio::new();
+        let sent = Arc::new(AtomicUsize::new(0));
+
+        mini_tokio.block_on(async {
+            let (tx, mut rx) = channel(2);
+
+            let producer_sent = sent.clone();
+            spawn(async move {
+                for i in 0..10 {
+                    tx.send(i).await.unwrap();
+                    producer_sent.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+
+            for i in 0..10 {
+                // Let the producer run as far as it can.
+                for _ in 0..5 {
+                    yield_now().await;
+                }
+
+                // It may only be ahead of the consumer by the capacity.
+                assert!(sent.load(Ordering::SeqCst) <= i + 2);
+                assert_eq!(rx.recv().await, Some(i));
+            }
+
+            assert_eq!(rx.recv().await, None);
+        });
+    }
+
+    #[test]
+    fn drain_takes_the_queued_values() {
+        let (tx, mut rx) = channel(4);
+        let mut cx = noop_context();
+
+        for i in 0..3 {
+            assert!(pin!(tx.send(i)).poll(&mut cx).is_ready());
+        }
+
+        assert_eq!(rx.drain(), vec![0, 1, 2]);
+
+        // The channel is empty, but not closed.
+        assert!(rx.poll_recv(&mut cx).is_pending());
+        assert!(rx.drain().is_empty());
+    }
+
+    #[test]
+    fn ready_waits_for_room() {
+        let (tx, mut rx) = channel(1);
+        let mut cx = noop_context();
+
+        assert!(pin!(tx.send(1)).poll(&mut cx).is_ready());
+
+        let mut ready = pin!(tx.ready());
+        assert!(ready.as_mut().poll(&mut cx).is_pending());
+
+        assert_eq!(rx.poll_recv(&mut cx), Poll::Ready(Some(1)));
+        assert!(matches!(ready.as_mut().poll(&mut cx), Poll::Ready(Ok(()))));
+
+        drop(rx);
+        assert!(matches!(tx.poll_ready(&mut cx), Poll::Ready(Err(_))));
+    }
+
+    #[test]
+    fn recv_timeout_gives_up_on_an_empty_channel() {
+        let mini_tokio = MiniTokio::new();
+
+        mini_tokio.block_on(async {
+            let (tx, mut rx) = channel(1);
+            let dur = Duration::from_millis(10);
+
+            assert!(rx.recv_timeout(dur).await.is_err());
+
+            tx.send(1).await.unwrap();
+            assert_eq!(rx.recv_timeout(dur).await.unwrap(), Some(1));
+
+            drop(tx);
+            assert_eq!(rx.recv_timeout(dur).await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn recv_many_takes_at_most_limit_values() {
+        let (tx, mut rx) = channel(8);
+        let mut cx = noop_context();
+
+        for i in 0..5 {
+            assert!(pin!(tx.send(i)).poll(&mut cx).is_ready());
+        }
+
+        let mut buf = vec![];
+        assert_eq!(rx.poll_recv_many(&mut cx, &mut buf, 3), Poll::Ready(3));
+        assert_eq!(rx.poll_recv_many(&mut cx, &mut buf, 3), Poll::Ready(2));
+        assert_eq!(buf, vec![0, 1, 2, 3, 4]);
+
+        // Nothing is queued: wait for the next value.
+        assert!(rx.poll_recv_many(&mut cx, &mut buf, 3).is_pending());
+
+        drop(tx);
+        assert_eq!(rx.poll_recv_many(&mut cx, &mut buf, 3), Poll::Ready(0));
+    }
+
+    #[test]
+    fn recv_returns_none_once_every_sender_is_dropped() {
+        let (tx, mut rx) = channel(4);
+        let tx2 = tx.clone();
+        let mut cx = noop_context();
+
+        assert!(pin!(tx.send(1)).poll(&mut cx).is_ready());
+        drop(tx);
+        assert!(rx.poll_recv(&mut cx).is_ready());
+        assert!(rx.poll_recv(&mut cx).is_pending());
+
+        // Values sent before the last sender is dropped are still received.
+        assert!(pin!(tx2.send(2)).poll(&mut cx).is_ready());
+        drop(tx2);
+
+        assert_eq!(rx.poll_recv(&mut cx), Poll::Ready(Some(2)));
+        assert_eq!(rx.poll_recv(&mut cx), Poll::Ready(None));
+    }
+
+    #[test]
+    fn weak_senders_do_not_keep_the_channel_open() {
+        let (tx, mut rx) = channel(4);
+        let weak = tx.downgrade();
+        let mut cx = noop_context();
+
+        let upgraded = weak.upgrade().unwrap();
+        assert!(pin!(upgraded.send(1)).poll(&mut cx).is_ready());
+        drop(upgraded);
+
+        drop(tx);
+
+        assert_eq!(rx.poll_recv(&mut cx), Poll::Ready(Some(1)));
+        assert_eq!(rx.poll_recv(&mut cx), Poll::Ready(None));
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn dropping_the_receiver_fails_waiting_senders() {
+        let mini_tokio = MiniTokio::new();
+
+        mini_tokio.block_on(async {
+            let (tx, rx) = channel(1);
+            tx.send(1).await.unwrap();
+
+            let blocked = spawn(async move { tx.send(2).await });
+            yield_now().await;
+
+            drop(rx);
+
+            match blocked.await.unwrap() {
+                Err(SendError(value)) => assert_eq!(value, 2),
+                Ok(()) => panic!("sent to a dropped receiver"),
+            }
+        });
+    }
+}
@@ -0,0 +1,242 @@
+//! TCP sockets.
+//!
+//! `MiniTcpListener` and `MiniTcpStream` are resources, just like timers: leaf
+//! futures that integrate with an operating system detail. They wrap
+//! non-blocking `mio` sockets registered with the reactor. Each operation
+//! first stores the task's waker with the reactor, then tries the operation.
+//! If the socket is not ready, the operation fails with `WouldBlock` and
+//! `Poll::Pending` is returned. The reactor wakes the task once the socket
+//! becomes ready, and the operation is tried again.
+
+use crate::coop;
+use crate::reactor::Registration;
+
+use futures::io::{AsyncRead, AsyncWrite};
+use std::future;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// A TCP socket server, listening for connections. The mini-tokio equivalent
+/// of `tokio::net::TcpListener`.
+pub struct MiniTcpListener {
+    io: mio::net::TcpListener,
+    registration: Registration,
+}
+
+/// A TCP connection. The mini-tokio equivalent of `tokio::net::TcpStream`.
+///
+/// Read and write with the `AsyncReadExt` and `AsyncWriteExt` traits of the
+/// `futures` crate.
+pub struct MiniTcpStream {
+    io: mio::net::TcpStream,
+    registration: Registration,
+}
+
+impl MiniTcpListener {
+    /// Creates a listener bound to `addr`.
+    ///
+    /// Binding to port 0 picks a free port; `local_addr` returns it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a mini-tokio runtime, or if I/O is
+    /// disabled.
+    pub fn bind(addr: SocketAddr) -> io::Result<MiniTcpListener> {
+        let io = mio::net::TcpListener::bind(&addr)?;
+        let registration = Registration::new(&io)?;
+
+        Ok(MiniTcpListener { io, registration })
+    }
+
+    /// Returns the address the listener is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.local_addr()
+    }
+
+    /// Accepts a new connection.
+    pub async fn accept(&self) -> io::Result<(MiniTcpStream, SocketAddr)> {
+        future::poll_fn(|cx| self.poll_accept(cx)).await
+    }
+
+    /// Polls to accept a new connection.
+    ///
+    /// If no connection is pending, `Poll::Pending` is returned and the task
+    /// is woken once one may be.
+    pub fn poll_accept(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<(MiniTcpStream, SocketAddr)>> {
+        if coop::poll_proceed(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        self.registration.register_reader(cx.waker());
+
+        match self.io.accept() {
+            Ok((io, addr)) => Poll::Ready(MiniTcpStream::new(io).map(|stream| (stream, addr))),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+}
+
+impl MiniTcpStream {
+    /// Opens a connection to `addr`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a mini-tokio runtime, or if I/O is
+    /// disabled.
+    pub async fn connect(addr: SocketAddr) -> io::Result<MiniTcpStream> {
+        // The socket is created right away, but the connection is established
+        // in the background. It has completed, or failed, once the socket
+        // becomes writable.
+        let stream = MiniTcpStream::new(mio::net::TcpStream::connect(&addr)?)?;
+
+        future::poll_fn(|cx| stream.poll_connected(cx)).await?;
+
+        Ok(stream)
+    }
+
+    fn new(io: mio::net::TcpStream) -> io::Result<MiniTcpStream> {
+        let registration = Registration::new(&io)?;
+
+        Ok(MiniTcpStream { io, registration })
+    }
+
+    // Completes once the connection started by `connect` is established.
+    fn poll_connected(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.registration.register_writer(cx.waker());
+
+        if let Some(err) = self.io.take_error()? {
+            return Poll::Ready(Err(err));
+        }
+
+        // `peer_addr` fails with `NotConnected` while the connection is still
+        // being established.
+        match self.io.peer_addr() {
+            Ok(_) => Poll::Ready(Ok(())),
+            Err(err) if err.kind() == io::ErrorKind::NotConnected => Poll::Pending,
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+
+    /// Returns the address of the remote peer.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.io.peer_addr()
+    }
+
+    /// Returns the local address of the connection.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.local_addr()
+    }
+
+    /// Enables TCP keepalive with `keepalive` as the idle time before the
+    /// first probe, or disables it with `None`.
+    ///
+    /// This is best effort: platforms that do not support configuring the
+    /// interval only enable or disable keepalive, and the operating system may
+    /// round the interval, typically to whole seconds.
+    pub fn set_keepalive(&self, keepalive: Option<Duration>) -> io::Result<()> {
+        self.io.set_keepalive(keepalive)
+    }
+
+    /// Returns the keepalive interval of the connection, `None` if keepalive
+    /// is disabled.
+    pub fn keepalive(&self) -> io::Result<Option<Duration>> {
+        self.io.keepalive()
+    }
+
+    /// Polls to read data into `buf`. Returns the number of bytes read, 0 once
+    /// the peer closed its half of the connection.
+    ///
+    /// If no data is available, `Poll::Pending` is returned and the task is
+    /// woken once data may be.
+    pub fn poll_read(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if coop::poll_proceed(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        self.registration.register_reader(cx.waker());
+
+        match (&self.io).read(buf) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+            res => Poll::Ready(res),
+        }
+    }
+
+    /// Polls to write data from `buf`. Returns the number of bytes written.
+    ///
+    /// If the socket's send buffer is full, `Poll::Pending` is returned and the
+    /// task is woken once there may be room.
+    pub fn poll_write(&self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if coop::poll_proceed(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        self.registration.register_writer(cx.waker());
+
+        match (&self.io).write(buf) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+            res => Poll::Ready(res),
+        }
+    }
+}
+
+impl AsyncRead for MiniTcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        MiniTcpStream::poll_read(&self, cx, buf)
+    }
+}
+
+impl AsyncWrite for MiniTcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        MiniTcpStream::poll_write(&self, cx, buf)
+    }
+
+    // Writes go straight to the socket. There is nothing to flush.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    // Shuts down the write half of the connection. The peer reads 0 bytes once
+    // it has read everything written before.
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.io.shutdown(Shutdown::Write))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{MiniTcpListener, MiniTcpStream, MiniTokio};
+    use std::time::Duration;
+
+    #[test]
+    fn keepalive_round_trips() {
+        let mini_tokio = MiniTokio::new();
+
+        mini_tokio.block_on(async {
+            let listener = MiniTcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            let stream = MiniTcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+
+            stream.set_keepalive(Some(Duration::from_secs(30))).unwrap();
+            assert_eq!(stream.keepalive().unwrap(), Some(Duration::from_secs(30)));
+
+            stream.set_keepalive(None).unwrap();
+            assert_eq!(stream.keepalive().unwrap(), None);
+        });
+    }
+}
@@ -0,0 +1,24 @@
+//! Wakers that do nothing.
+//!
+//! Polling a future requires a `Context`, and a `Context` requires a `Waker`.
+//! Sometimes the caller has no intention of being woken: a benchmark measuring
+//! the cost of `poll` itself, or a check that a future is ready right now.
+//! These helpers provide a waker for that case.
+
+use std::task::{Context, Waker};
+
+/// Returns a `Waker` that does nothing when woken.
+///
+/// A future that returns `Poll::Pending` after registering this waker is
+/// never scheduled again. It is up to the caller to poll it again, if at all.
+pub fn noop_waker() -> Waker {
+    futures::task::noop_waker()
+}
+
+/// Returns a `Context` backed by a no-op waker.
+///
+/// The waker is a static, so the context can be created and used without
+/// any allocation or reference counting.
+pub fn noop_context() -> Context<'static> {
+    Context::from_waker(futures::task::noop_waker_ref())
+}
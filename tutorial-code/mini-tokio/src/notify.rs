@@ -0,0 +1,245 @@
+//! Notifying a task.
+//!
+//! Every leaf future so far has handled wakers by hand: store the waker when
+//! not ready, replace it if the future moved to another task, take it and wake
+//! it when the event happens, and clear it when the future is dropped. `Notify`
+//! does that once, so a resource does not have to. The resource only calls
+//! `notify_one` when the event happens, and the waiting side awaits
+//! `notified()`. The timers are built this way: the timer driver notifies a
+//! `Notify` when a deadline is reached, without ever seeing a waker.
+//!
+//! A `Notify` holds a single permit and a list of waiters. `notify_one` wakes
+//! the first waiter or, if nobody is waiting, stores the permit so that the
+//! next call to `notified` completes right away. A notification is therefore
+//! never lost, even if it happens before the task starts waiting.
+
+use crate::coop;
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// Notifies a single task, the mini-tokio equivalent of
+/// `tokio::sync::Notify`.
+#[derive(Default)]
+pub struct Notify {
+    state: Mutex<State>,
+}
+
+/// Future returned by `Notify::notified`. Completes once the task is notified.
+pub struct Notified<'a> {
+    notify: &'a Notify,
+
+    // Set once the future is queued as a waiter.
+    waiter: Option<Arc<Mutex<Waiter>>>,
+}
+
+#[derive(Default)]
+struct State {
+    // Set by `notify_one` when nobody is waiting. Consumed by the next
+    // `notified`.
+    permit: bool,
+
+    // The tasks waiting to be notified, first come first served.
+    waiters: VecDeque<Arc<Mutex<Waiter>>>,
+}
+
+// A queued `Notified` future.
+struct Waiter {
+    // Set by `notify_one` when it picks this waiter.
+    notified: bool,
+
+    // The waker of the task awaiting the `Notified` future.
+    waker: Option<Waker>,
+}
+
+impl Notify {
+    /// Creates a `Notify` with no permit and no waiters.
+    pub fn new() -> Notify {
+        Notify::default()
+    }
+
+    /// Waits for a notification.
+    ///
+    /// Completes right away, consuming the permit, if `notify_one` was called
+    /// while nobody was waiting.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            waiter: None,
+        }
+    }
+
+    /// Notifies the first waiting task, or stores a permit if no task is
+    /// waiting.
+    ///
+    /// Calling `notify_one` several times while nobody is waiting stores a
+    /// single permit.
+    pub fn notify_one(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        match state.waiters.pop_front() {
+            Some(waiter) => {
+                drop(state);
+
+                let waker = {
+                    let mut waiter = waiter.lock().unwrap();
+                    waiter.notified = true;
+                    waiter.waker.take()
+                };
+
+                // Wake the task without holding any lock: the waker may do
+                // anything, including polling the `Notified` future.
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+            }
+            None => state.permit = true,
+        }
+    }
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        match &self.waiter {
+            // First poll: take the permit if there is one, or queue up.
+            None => {
+                let mut state = self.notify.state.lock().unwrap();
+
+                if state.permit {
+                    // The `Notified` future is a leaf future: completing
+                    // consumes the task's coop budget. The permit is left in
+                    // place if the task must yield first.
+                    if coop::poll_proceed(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+
+                    state.permit = false;
+                    return Poll::Ready(());
+                }
+
+                let waiter = Arc::new(Mutex::new(Waiter {
+                    notified: false,
+                    waker: Some(cx.waker().clone()),
+                }));
+                state.waiters.push_back(waiter.clone());
+                drop(state);
+
+                self.waiter = Some(waiter);
+                Poll::Pending
+            }
+            // Already queued: check whether `notify_one` picked this waiter.
+            Some(waiter) => {
+                let mut waiter = waiter.lock().unwrap();
+
+                if !waiter.notified {
+                    // The future may have moved to a different task since the
+                    // previous poll. Make sure the right task is woken.
+                    match &waiter.waker {
+                        Some(waker) if waker.will_wake(cx.waker()) => {}
+                        _ => waiter.waker = Some(cx.waker().clone()),
+                    }
+
+                    return Poll::Pending;
+                }
+
+                drop(waiter);
+
+                if coop::poll_proceed(cx).is_pending() {
+                    return Poll::Pending;
+                }
+
+                // The notification is consumed. Forget the waiter so that
+                // dropping the future does not pass the notification on.
+                self.waiter = None;
+                Poll::Ready(())
+            }
+        }
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let waiter = match self.waiter.take() {
+            Some(waiter) => waiter,
+            None => return,
+        };
+
+        let mut state = self.notify.state.lock().unwrap();
+
+        // Leave the queue, releasing the waker and the reference it holds on
+        // the task right away.
+        state.waiters.retain(|w| !Arc::ptr_eq(w, &waiter));
+        drop(state);
+
+        // The future was notified but dropped before it could complete, for
+        // example because it lost a race against another future. Pass the
+        // notification on so that it is not lost.
+        if waiter.lock().unwrap().notified {
+            self.notify.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Notify;
+
+    use futures::task::noop_waker_ref;
+
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::Context;
+
+    #[test]
+    fn a_notification_before_waiting_is_kept_as_a_single_permit() {
+        let notify = Notify::new();
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        notify.notify_one();
+        notify.notify_one();
+
+        assert!(pin!(notify.notified()).poll(&mut cx).is_ready());
+        assert!(pin!(notify.notified()).poll(&mut cx).is_pending());
+    }
+
+    #[test]
+    fn waiters_are_notified_in_order() {
+        let notify = Notify::new();
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        let mut first = pin!(notify.notified());
+        let mut second = pin!(notify.notified());
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+
+        notify.notify_one();
+        assert!(first.as_mut().poll(&mut cx).is_ready());
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+
+        notify.notify_one();
+        assert!(second.as_mut().poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn a_dropped_waiter_passes_its_notification_on() {
+        let notify = Notify::new();
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        let mut second = pin!(notify.notified());
+        {
+            let mut first = pin!(notify.notified());
+            assert!(first.as_mut().poll(&mut cx).is_pending());
+            assert!(second.as_mut().poll(&mut cx).is_pending());
+
+            // `first` is picked, then dropped without completing.
+            notify.notify_one();
+        }
+
+        assert!(second.as_mut().poll(&mut cx).is_ready());
+    }
+}
@@ -0,0 +1,209 @@
+//! A channel for sending a single value between tasks.
+//!
+//! This is the mini-tokio equivalent of `tokio::sync::oneshot`, the channel
+//! used in the tutorial to send a response back to the task that issued a
+//! command. Both halves share a slot behind a mutex. The `Receiver` is a leaf
+//! future: when it finds the slot empty, it stores its waker in the slot and
+//! returns `Poll::Pending`. `Sender::send` stores the value and wakes the
+//! receiver, which is polled again and takes the value out.
+//!
+//! Dropping either half closes the channel. A receiver waiting on a dropped
+//! sender is woken and gets an error instead of waiting forever, and sending
+//! to a dropped receiver hands the value back to the caller.
+
+use crate::coop;
+
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// Sends a value to the associated `Receiver`. Created by `channel`.
+pub struct Sender<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+/// Receives the value sent by the associated `Sender`. Created by `channel`.
+///
+/// Awaiting the receiver resolves to the value, or to `RecvError` if the
+/// `Sender` was dropped without sending. A `Receiver` must not be polled again
+/// once it has returned `Poll::Ready`.
+pub struct Receiver<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+/// Error returned by the `Receiver` when the `Sender` is dropped without
+/// sending a value.
+#[derive(Debug)]
+pub struct RecvError(());
+
+// State shared by the two halves.
+struct Shared<T> {
+    // The sent value. Set once by the `Sender` and taken by the `Receiver`.
+    value: Option<T>,
+
+    // The waker of the task awaiting the `Receiver`, if any.
+    waker: Option<Waker>,
+
+    // Set when the `Sender` is consumed by `send` or dropped. No value can
+    // arrive after that.
+    tx_closed: bool,
+
+    // Set when the `Receiver` is dropped. A value sent after that could never
+    // be received.
+    rx_closed: bool,
+}
+
+/// Creates a new oneshot channel, returning the two halves.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Mutex::new(Shared {
+        value: None,
+        waker: None,
+        tx_closed: false,
+        rx_closed: false,
+    }));
+
+    let tx = Sender {
+        shared: shared.clone(),
+    };
+
+    (tx, Receiver { shared })
+}
+
+impl<T> Sender<T> {
+    /// Sends `value` to the `Receiver`.
+    ///
+    /// Sending never waits: the value is stored in the channel. If the
+    /// `Receiver` has been dropped, the value is returned as the error.
+    pub fn send(self, value: T) -> Result<(), T> {
+        let mut shared = self.shared.lock().unwrap();
+
+        if shared.rx_closed {
+            return Err(value);
+        }
+
+        shared.value = Some(value);
+
+        // `Drop` marks the sender closed and wakes the receiver.
+        Ok(())
+    }
+
+    /// Returns `true` if the `Receiver` has been dropped.
+    ///
+    /// A task computing a response can check this to stop early when nobody
+    /// is waiting for the response anymore.
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().unwrap().rx_closed
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut shared = self.shared.lock().unwrap();
+            shared.tx_closed = true;
+            shared.waker.take()
+        };
+
+        // Wake the receiver, whether a value was sent or not. Either way, it
+        // is done waiting. The lock is released first, so the woken task does
+        // not contend on it.
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<T, RecvError>> {
+        let mut shared = self.shared.lock().unwrap();
+
+        if !shared.tx_closed {
+            // Nothing sent yet. Store the waker so that `send`, or dropping
+            // the sender, can wake the task. It replaces the waker stored by a
+            // previous poll, which may belong to another task if the receiver
+            // was moved.
+            shared.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        // The receiver is a leaf future: completing consumes the task's coop
+        // budget.
+        if coop::poll_proceed(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        Poll::Ready(shared.value.take().ok_or(RecvError(())))
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.lock().unwrap().rx_closed = true;
+    }
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "channel closed".fmt(fmt)
+    }
+}
+
+impl Error for RecvError {}
+
+#[cfg(test)]
+mod tests {
+    use super::channel;
+    use crate::{spawn, yield_now, MiniTokio};
+
+    use futures::task::noop_waker_ref;
+
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll};
+
+    #[test]
+    fn the_receiver_waits_for_the_value() {
+        let mini_tokio = MiniTokio::new();
+
+        let value = mini_tokio.block_on(async {
+            let (tx, rx) = channel();
+
+            spawn(async move {
+                // Make sure the receiver is waiting first.
+                yield_now().await;
+                tx.send("hello").unwrap();
+            });
+
+            rx.await
+        });
+
+        assert_eq!(value.unwrap(), "hello");
+    }
+
+    #[test]
+    fn dropping_the_sender_closes_the_channel() {
+        let (tx, rx) = channel::<()>();
+        let mut rx = pin!(rx);
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        assert!(rx.as_mut().poll(&mut cx).is_pending());
+
+        drop(tx);
+        assert!(matches!(rx.as_mut().poll(&mut cx), Poll::Ready(Err(_))));
+    }
+
+    #[test]
+    fn sending_to_a_dropped_receiver_returns_the_value() {
+        let (tx, rx) = channel();
+        assert!(!tx.is_closed());
+
+        drop(rx);
+        assert!(tx.is_closed());
+        assert_eq!(tx.send(1), Err(1));
+    }
+}
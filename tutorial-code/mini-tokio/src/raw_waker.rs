@@ -0,0 +1,95 @@
+//! A `Waker` built by hand.
+//!
+//! mini-tokio normally lets `futures::task::waker` turn an `Arc<Task>` into a
+//! `Waker`, so that it does not need any `unsafe` code. This module shows what
+//! that helper does. Enable it with `--features raw-waker`.
+//!
+//! A `Waker` is a data pointer plus a table of four functions, the
+//! `RawWakerVTable`, that the standard library calls to clone, wake and drop
+//! it. Here, the data pointer is an `Arc<Task>` turned into a raw pointer with
+//! `Arc::into_raw`. Each `Waker` owns one reference count of the task:
+//!
+//! * `clone` increments the count and returns a second `RawWaker` with the same
+//!   pointer.
+//! * `wake` consumes the waker: the pointer is turned back into an `Arc`, which
+//!   is dropped once the task has been scheduled.
+//! * `wake_by_ref` schedules the task without consuming the waker, so the
+//!   reference count must be left alone.
+//! * `drop` turns the pointer back into an `Arc` and drops it.
+//!
+//! Getting any of these wrong leaks the task or frees it while a waker still
+//! points to it. That is why the safe helper is preferred.
+
+use crate::Task;
+
+use futures::task::ArcWake;
+use std::mem::ManuallyDrop;
+use std::sync::Arc;
+use std::task::{RawWaker, RawWakerVTable, Waker};
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop_waker);
+
+// Returns a waker that schedules `task` when woken.
+pub(crate) fn waker(task: Arc<Task>) -> Waker {
+    let raw = RawWaker::new(Arc::into_raw(task) as *const (), &VTABLE);
+
+    // SAFETY: the pointer comes from `Arc::into_raw`, and the functions of
+    // `VTABLE` treat it as such, keeping the reference count balanced.
+    unsafe { Waker::from_raw(raw) }
+}
+
+unsafe fn clone(ptr: *const ()) -> RawWaker {
+    // The new waker owns a reference count of its own.
+    Arc::increment_strong_count(ptr as *const Task);
+    RawWaker::new(ptr, &VTABLE)
+}
+
+unsafe fn wake(ptr: *const ()) {
+    // Take back the reference count owned by the waker. It is released once
+    // the task has been scheduled.
+    let task = Arc::from_raw(ptr as *const Task);
+    ArcWake::wake(task);
+}
+
+unsafe fn wake_by_ref(ptr: *const ()) {
+    // The waker keeps its reference count, so the `Arc` must not be dropped.
+    let task = ManuallyDrop::new(Arc::from_raw(ptr as *const Task));
+    ArcWake::wake_by_ref(&task);
+}
+
+unsafe fn drop_waker(ptr: *const ()) {
+    drop(Arc::from_raw(ptr as *const Task));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::waker;
+    use crate::{MiniTokio, Task};
+
+    use std::sync::Arc;
+
+    #[test]
+    fn wakers_keep_the_reference_count_balanced() {
+        let mini_tokio = MiniTokio::new();
+
+        // A new task counts as scheduled, so the wakes below do not queue it
+        // and the queue does not hold a reference of its own.
+        let (task, _join) = Task::new(async {}, &mini_tokio.handle);
+        assert_eq!(Arc::strong_count(&task), 1);
+
+        let waker = waker(task.clone());
+        assert_eq!(Arc::strong_count(&task), 2);
+
+        let clone = waker.clone();
+        assert_eq!(Arc::strong_count(&task), 3);
+
+        clone.wake_by_ref();
+        assert_eq!(Arc::strong_count(&task), 3);
+
+        clone.wake();
+        assert_eq!(Arc::strong_count(&task), 2);
+
+        drop(waker);
+        assert_eq!(Arc::strong_count(&task), 1);
+    }
+}
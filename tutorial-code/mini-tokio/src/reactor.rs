@@ -0,0 +1,493 @@
+//! The I/O driver, or reactor.
+//!
+//! Sockets are put in non-blocking mode: reading from a socket with no data
+//! available fails with `WouldBlock` instead of waiting. Something has to tell
+//! the task when to try again. That is the reactor's job. Every socket is
+//! registered with a single `mio::Poll`, which wraps the operating system's
+//! readiness API (epoll, kqueue, ...). The reactor thread waits on it and,
+//! whenever a socket becomes ready, wakes the tasks waiting on that socket.
+//!
+//! Like the timer driver, the reactor runs on a thread of its own. The thread
+//! is only spawned once the first resource is registered, so a runtime that
+//! never touches a socket does not pay for it. Tokio does not use a separate
+//! thread: the worker threads wait for I/O events whenever they run out of
+//! tasks. `Builder::park_on_io` does the same on a single-threaded instance:
+//! no reactor thread is spawned, and the worker calls `Driver::turn` instead.
+
+use crate::CURRENT;
+
+use mio::{Evented, Events, Poll, PollOpt, Ready, SetReadiness, Token};
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Waker;
+use std::thread;
+use std::time::Duration;
+
+// Token of the registration used to wake the reactor thread on shutdown, or
+// the worker waiting in `Driver::turn`. Sockets get tokens starting at 1.
+const WAKEUP: Token = Token(0);
+
+// Drives the I/O resources of a mini-tokio instance. Owned by `MiniTokio`:
+// dropping the driver stops the reactor thread, if it was started.
+pub(crate) struct Driver {
+    handle: ReactorHandle,
+
+    // Receives the events of `turn`.
+    events: Mutex<Events>,
+}
+
+// Used to register resources with the reactor.
+#[derive(Clone)]
+pub(crate) struct ReactorHandle {
+    shared: Arc<Shared>,
+}
+
+// State shared by the reactor thread and the registered resources.
+struct Shared {
+    poll: Poll,
+
+    // The tasks waiting on each registered resource, by token.
+    resources: Mutex<HashMap<Token, Arc<Waiters>>>,
+
+    // The token of the next resource to be registered.
+    next_token: AtomicUsize,
+
+    // Set when the driver is dropped.
+    shutdown: AtomicBool,
+
+    // The reactor thread. `None` until the first resource is registered, and
+    // forever when the worker polls for events itself.
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+    thread_name: String,
+
+    // Set when the worker polls for events itself, with `turn`.
+    inline: bool,
+
+    // Made ready to wake the reactor thread from `poll` when shutting down,
+    // or the worker waiting in `turn`. The registration must be kept alive
+    // for as long as it is used.
+    wakeup: SetReadiness,
+    _wakeup_registration: mio::Registration,
+}
+
+// The tasks waiting on a resource: one to read from it, or accept on it, and
+// one to write to it.
+#[derive(Default)]
+struct Waiters {
+    reader: Mutex<Option<Waker>>,
+    writer: Mutex<Option<Waker>>,
+}
+
+// A resource registered with the reactor. It stops receiving events when
+// dropped.
+pub(crate) struct Registration {
+    handle: ReactorHandle,
+    token: Token,
+    waiters: Arc<Waiters>,
+}
+
+impl Driver {
+    // Create a reactor. Its thread is started by the first registration,
+    // unless `inline` is set: the worker then calls `turn` itself and no
+    // thread is ever started.
+    pub(crate) fn new(thread_name: String, inline: bool) -> Driver {
+        let poll = Poll::new().expect("failed to create the I/O driver");
+        let (registration, wakeup) = mio::Registration::new2();
+        poll.register(&registration, WAKEUP, Ready::readable(), PollOpt::edge())
+            .expect("failed to create the I/O driver");
+
+        let shared = Arc::new(Shared {
+            poll,
+            resources: Mutex::new(HashMap::new()),
+            next_token: AtomicUsize::new(1),
+            shutdown: AtomicBool::new(false),
+            thread: Mutex::new(None),
+            thread_name,
+            inline,
+            wakeup,
+            _wakeup_registration: registration,
+        });
+
+        Driver {
+            handle: ReactorHandle { shared },
+            events: Mutex::new(Events::with_capacity(1024)),
+        }
+    }
+
+    pub(crate) fn handle(&self) -> ReactorHandle {
+        self.handle.clone()
+    }
+
+    // Wait for I/O events, for at most `timeout`, and wake the tasks waiting
+    // on the resources that became ready. Returns early if `unpark` is
+    // called, including if it was called since the last `turn`.
+    //
+    // Called by the worker when it runs out of tasks. See
+    // `Builder::park_on_io`.
+    pub(crate) fn turn(&self, timeout: Option<Duration>) {
+        let mut events = self.events.lock().unwrap();
+
+        if self.handle.shared.poll(&mut events, timeout) {
+            self.handle.shared.dispatch(&events);
+        }
+    }
+}
+
+impl Drop for Driver {
+    fn drop(&mut self) {
+        let shared = &self.handle.shared;
+        shared.shutdown.store(true, Ordering::SeqCst);
+        let _ = shared.wakeup.set_readiness(Ready::readable());
+
+        // `shutdown` is set before taking the lock, so a registration racing
+        // with the drop either sees it and does not start the thread, or
+        // starts it before the lock is taken here and the thread is joined.
+        let thread = shared.thread.lock().unwrap().take();
+
+        if let Some(thread) = thread {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl ReactorHandle {
+    // Start the reactor thread, unless it is already running or the driver
+    // was dropped.
+    fn ensure_started(&self) {
+        let mut thread = self.shared.thread.lock().unwrap();
+
+        if thread.is_some() || self.shared.inline || self.shared.shutdown.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let shared = self.shared.clone();
+
+        *thread = Some(
+            thread::Builder::new()
+                .name(self.shared.thread_name.clone())
+                .spawn(move || shared.run())
+                .expect("failed to spawn the I/O thread"),
+        );
+    }
+
+    // Make the current or next `Driver::turn` return right away.
+    //
+    // Called whenever a task is scheduled on an instance whose worker waits
+    // for I/O events itself: the task may be scheduled from another thread,
+    // while the worker is blocked in `turn`. The wakeup is coalesced: until
+    // `turn` consumed it, setting it again costs a few atomic operations.
+    pub(crate) fn unpark(&self) {
+        let _ = self.shared.wakeup.set_readiness(Ready::readable());
+    }
+}
+
+impl Shared {
+    // The reactor thread's loop.
+    fn run(&self) {
+        let mut events = Events::with_capacity(1024);
+
+        loop {
+            // Block until at least one registered resource is ready.
+            if !self.poll(&mut events, None) {
+                continue;
+            }
+
+            if self.shutdown.load(Ordering::SeqCst) {
+                return;
+            }
+
+            self.dispatch(&events);
+        }
+    }
+
+    // Wait for events, for at most `timeout`. Returns `false` if the wait was
+    // interrupted by a signal, in which case `events` must be ignored.
+    fn poll(&self, events: &mut Events, timeout: Option<Duration>) -> bool {
+        match self.poll.poll(events, timeout) {
+            Ok(_) => true,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => false,
+            Err(err) => panic!("failed to poll for I/O events; err = {}", err),
+        }
+    }
+
+    // Wake the tasks waiting on the resources `events` are about.
+    fn dispatch(&self, events: &Events) {
+        for event in events {
+            if event.token() == WAKEUP {
+                continue;
+            }
+
+            // The resource may have been dropped since the event fired.
+            let waiters = self.resources.lock().unwrap().get(&event.token()).cloned();
+
+            if let Some(waiters) = waiters {
+                waiters.wake();
+            }
+        }
+    }
+}
+
+impl Waiters {
+    // Wake the tasks waiting on the resource.
+    //
+    // Both are woken whatever the event was. A task that still cannot make
+    // progress tries its operation, gets `WouldBlock` and waits again, so a
+    // spurious wakeup costs a little time but is never wrong. Telling the
+    // events apart portably, including errors and the peer hanging up, takes
+    // more code than it saves.
+    fn wake(&self) {
+        for waiter in &[&self.reader, &self.writer] {
+            if let Some(waker) = waiter.lock().unwrap().take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl Registration {
+    // Register `io` with the reactor of the current mini-tokio instance.
+    //
+    // # Panics
+    //
+    // Panics if called outside of a mini-tokio runtime, or if I/O is disabled.
+    pub(crate) fn new(io: &impl Evented) -> io::Result<Registration> {
+        let handle = CURRENT.with(|cell| {
+            let borrow = cell.borrow();
+            let handle = borrow
+                .as_ref()
+                .expect("I/O resource created outside of a mini-tokio runtime");
+            handle
+                .io
+                .clone()
+                .expect("I/O is disabled; enable it with `Builder::enable_io`")
+        });
+
+        handle.ensure_started();
+
+        let shared = &handle.shared;
+        let token = Token(shared.next_token.fetch_add(1, Ordering::Relaxed));
+        let waiters = Arc::new(Waiters::default());
+
+        shared
+            .resources
+            .lock()
+            .unwrap()
+            .insert(token, waiters.clone());
+
+        // Edge-triggered: an event is delivered when the resource becomes
+        // ready, not for as long as it is ready. This is fine because a task
+        // always stores its waker before trying the operation: if the
+        // operation fails with `WouldBlock`, the next readiness change is
+        // guaranteed to come after the waker was stored.
+        if let Err(err) = shared.poll.register(
+            io,
+            token,
+            Ready::readable() | Ready::writable(),
+            PollOpt::edge(),
+        ) {
+            shared.resources.lock().unwrap().remove(&token);
+            return Err(err);
+        }
+
+        Ok(Registration {
+            handle,
+            token,
+            waiters,
+        })
+    }
+
+    // Wake `waker` when the resource may be readable.
+    pub(crate) fn register_reader(&self, waker: &Waker) {
+        register(&self.waiters.reader, waker);
+    }
+
+    // Wake `waker` when the resource may be writable.
+    pub(crate) fn register_writer(&self, waker: &Waker) {
+        register(&self.waiters.writer, waker);
+    }
+}
+
+// Store `waker`, unless the stored waker already wakes the same task.
+fn register(waiter: &Mutex<Option<Waker>>, waker: &Waker) {
+    let mut waiter = waiter.lock().unwrap();
+
+    match waiter.as_ref() {
+        Some(stored) if stored.will_wake(waker) => {}
+        _ => *waiter = Some(waker.clone()),
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        // The operating system stops reporting events for the socket once it
+        // is closed, which happens right after this when the socket itself is
+        // dropped. Only the waiters need to be removed.
+        self.handle
+            .shared
+            .resources
+            .lock()
+            .unwrap()
+            .remove(&self.token);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        delay, spawn, yield_now, Builder, MiniTcpListener, MiniTcpStream, MiniTokio, CURRENT,
+    };
+
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use std::fs;
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+
+    // Returns `true` if the reactor thread of the current runtime is running.
+    fn started() -> bool {
+        CURRENT.with(|cell| {
+            let borrow = cell.borrow();
+            let io = borrow.as_ref().unwrap().io.as_ref().unwrap();
+            let thread = io.shared.thread.lock().unwrap();
+            thread.is_some()
+        })
+    }
+
+    #[test]
+    fn thread_starts_with_the_first_socket() {
+        let mini_tokio = MiniTokio::new();
+
+        mini_tokio.block_on(async {
+            assert!(!started());
+
+            let _listener = MiniTcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            assert!(started());
+        });
+    }
+
+    // Returns the names of the threads of this process that start with
+    // `prefix`.
+    #[cfg(target_os = "linux")]
+    fn threads_named(prefix: &str) -> Vec<String> {
+        fs::read_dir("/proc/self/task")
+            .unwrap()
+            .filter_map(|task| fs::read_to_string(task.ok()?.path().join("comm")).ok())
+            .map(|name| name.trim_end().to_string())
+            .filter(|name| name.starts_with(prefix))
+            .collect()
+    }
+
+    // Thread names are read from `/proc`.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn an_echo_server_runs_without_auxiliary_threads() {
+        let mini_tokio = Builder::new()
+            .thread_name_prefix("parked")
+            .park_on_io(true)
+            .build();
+
+        let (echoed, threads) = mini_tokio.block_on(async {
+            let listener = MiniTcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0; 1024];
+
+                loop {
+                    match socket.read(&mut buf).await.unwrap() {
+                        0 => return,
+                        n => socket.write_all(&buf[..n]).await.unwrap(),
+                    }
+                }
+            });
+
+            let mut stream = MiniTcpStream::connect(addr).await.unwrap();
+            let mut echoed = vec![];
+
+            for msg in &["hello", "world"] {
+                // The worker also waits for the timer while it waits for I/O.
+                delay(Duration::from_millis(10)).await;
+                stream.write_all(msg.as_bytes()).await.unwrap();
+
+                let mut buf = vec![0; msg.len()];
+                stream.read_exact(&mut buf).await.unwrap();
+                echoed.push(String::from_utf8(buf).unwrap());
+            }
+
+            (echoed, threads_named("parked"))
+        });
+
+        assert_eq!(echoed, ["hello", "world"]);
+        assert!(threads.is_empty(), "unexpected threads: {:?}", threads);
+    }
+
+    #[test]
+    fn io_is_serviced_while_a_task_keeps_yielding() {
+        const EVENT_INTERVAL: u32 = 8;
+
+        let mini_tokio = Builder::new()
+            .park_on_io(true)
+            .event_interval(EVENT_INTERVAL)
+            .build();
+
+        let spins = mini_tokio.block_on(async {
+            let listener = MiniTcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            // A plain thread writes to the socket once told to, and reports
+            // when the data was sent.
+            let (write_tx, write_rx) = mpsc::channel::<()>();
+            let (written_tx, written_rx) = mpsc::channel();
+
+            thread::spawn(move || {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                write_rx.recv().unwrap();
+                stream.write_all(b"ping").unwrap();
+                written_tx.send(()).unwrap();
+            });
+
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let read = Arc::new(AtomicBool::new(false));
+
+            // Spawned first, so it waits on the socket by the time the other
+            // task starts.
+            let reader_read = read.clone();
+
+            spawn(async move {
+                let mut buf = [0; 4];
+                socket.read_exact(&mut buf).await.unwrap();
+                reader_read.store(true, Ordering::SeqCst);
+            });
+
+            // Always ready: the scheduled channel never runs empty while it
+            // runs, so the worker never parks.
+            let spinner = spawn(async move {
+                yield_now().await;
+
+                write_tx.send(()).unwrap();
+                written_rx.recv().unwrap();
+
+                let mut spins = 0;
+
+                while !read.load(Ordering::SeqCst) && spins < 10_000 {
+                    spins += 1;
+                    yield_now().await;
+                }
+
+                spins
+            });
+
+            spinner.await.unwrap()
+        });
+
+        // The readiness is noticed within `EVENT_INTERVAL` polls, and the
+        // reader is polled right after the spinner's next turn.
+        assert!(spins <= EVENT_INTERVAL + 2, "spun {} times", spins);
+    }
+}
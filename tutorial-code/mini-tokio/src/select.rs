@@ -0,0 +1,108 @@
+//! Waiting on several futures at once.
+//!
+//! `select2` races two futures: it completes with the output of the first one
+//! to complete and drops the other one. Dropping a future is how a computation
+//! is cancelled in async Rust. For example, a `delay` that loses the race has
+//! its timer cancelled when it is dropped.
+
+use std::future::{self, Future};
+use std::pin::pin;
+use std::task::Poll;
+
+/// The output of `select2`: which of the two futures completed first, along
+/// with its output.
+#[derive(Debug)]
+pub enum Either<A, B> {
+    /// The first future completed first.
+    Left(A),
+
+    /// The second future completed first.
+    Right(B),
+}
+
+// Waits for either `a` or `b` to complete. The future that did not complete is
+// dropped.
+//
+// Both futures are polled in the same call to `poll`, `a` first. If both are
+// ready, `a` wins. Polling `a` first every time is not fair: a future that is
+// always ready starves the other one. Tokio's `select!` picks a random branch
+// to poll first for that reason.
+pub async fn select2<A, B>(a: A, b: B) -> Either<A::Output, B::Output>
+where
+    A: Future,
+    B: Future,
+{
+    let mut a = pin!(a);
+    let mut b = pin!(b);
+
+    future::poll_fn(|cx| {
+        // Both futures are given the same `Context`. Any future returning
+        // `Poll::Pending` stores the waker of the current task, so the task is
+        // woken when either of them can make progress. Skipping the poll of
+        // one of them would lose its wakeup.
+        if let Poll::Ready(output) = a.as_mut().poll(cx) {
+            return Poll::Ready(Either::Left(output));
+        }
+
+        if let Poll::Ready(output) = b.as_mut().poll(cx) {
+            return Poll::Ready(Either::Right(output));
+        }
+
+        Poll::Pending
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{delay, oneshot, select2, spawn, Builder, Either, MiniTokio, MockClock};
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn the_ready_future_wins_and_the_loser_is_dropped() {
+        struct SetOnDrop(Arc<AtomicBool>);
+
+        impl Drop for SetOnDrop {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let mini_tokio = Builder::new().clock(MockClock::new()).build();
+        let dropped = Arc::new(AtomicBool::new(false));
+
+        let guard = SetOnDrop(dropped.clone());
+        let slow = async move {
+            let _guard = guard;
+            delay(Duration::from_secs(60)).await;
+        };
+
+        let winner = mini_tokio.block_on(select2(slow, async { "ready" }));
+
+        assert!(matches!(winner, Either::Right("ready")));
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn either_future_wakes_the_task() {
+        let mini_tokio = MiniTokio::new();
+
+        let winner = mini_tokio.block_on(async {
+            let (_first_tx, first_rx) = oneshot::channel::<&str>();
+            let (second_tx, second_rx) = oneshot::channel();
+
+            // Both futures are pending when first polled. Only the second
+            // one is completed, later, from another task.
+            spawn(async move {
+                let _ = second_tx.send("second");
+            });
+
+            select2(first_rx, second_rx).await
+        });
+
+        assert!(matches!(winner, Either::Right(Ok("second"))));
+    }
+}
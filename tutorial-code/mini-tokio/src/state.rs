@@ -0,0 +1,149 @@
+//! The scheduling state of a task.
+//!
+//! A task may be woken any number of times, from any thread, including while
+//! it is being polled. Every wake must lead to a poll that happens after it, or
+//! the task could miss the event that caused the wake and hang. But queuing the
+//! task once per wake would poll it redundantly, and queuing it while it is
+//! being polled would let a second worker pick it up and wait for the first
+//! poll to complete.
+//!
+//! Each task tracks where it is with a small atomic state machine:
+//!
+//! ```text
+//!            wake                 poll
+//!   IDLE ------------> SCHEDULED ------> RUNNING
+//!    ^                                   |  |  |
+//!    |            poll returns Pending   |  |  | poll returns Ready
+//!    +-----------------------------------+  |  +--------------------> COMPLETE
+//!                                           |
+//!                                     wake  v
+//!   SCHEDULED <------------------------ NOTIFIED
+//!               poll returns Pending
+//! ```
+//!
+//! * A wake only queues the task when moving it from `IDLE` to `SCHEDULED`.
+//!   Waking a task that is already `SCHEDULED` does nothing: the pending poll
+//!   observes whatever caused the wake.
+//! * Waking a `RUNNING` task marks it `NOTIFIED` instead of queuing it. When the
+//!   poll returns, the worker sees the mark and queues the task itself. A task
+//!   is therefore never in the queue while it is being polled.
+//! * Once `COMPLETE`, wakes are ignored.
+//!
+//! Tokio's task state works the same way, with more states packed in the same
+//! word: the join handle's interest, cancellation and a reference count.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+const IDLE: u8 = 0;
+const SCHEDULED: u8 = 1;
+const RUNNING: u8 = 2;
+const NOTIFIED: u8 = 3;
+const COMPLETE: u8 = 4;
+
+pub(crate) struct State(AtomicU8);
+
+impl State {
+    // The state of a new task. New tasks are queued right after being
+    // created.
+    pub(crate) fn new() -> State {
+        State(AtomicU8::new(SCHEDULED))
+    }
+
+    // Called when the task is woken. Returns `true` if the caller must queue
+    // the task.
+    pub(crate) fn transition_to_scheduled(&self) -> bool {
+        let mut curr = self.0.load(Ordering::SeqCst);
+
+        loop {
+            let next = match curr {
+                IDLE => SCHEDULED,
+                RUNNING => NOTIFIED,
+                // Already queued, already notified, or nothing left to run.
+                _ => return false,
+            };
+
+            match self
+                .0
+                .compare_exchange(curr, next, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return next == SCHEDULED,
+                Err(actual) => curr = actual,
+            }
+        }
+    }
+
+    // Called by the worker that received the task, before polling it.
+    //
+    // The task is only in the queue while `SCHEDULED`, and wakes leave that
+    // state alone, so there is nothing to check.
+    pub(crate) fn transition_to_running(&self) {
+        self.0.store(RUNNING, Ordering::SeqCst);
+    }
+
+    // Called after a poll returned `Poll::Pending`. Returns `true` if the task
+    // was woken during the poll, in which case it is `SCHEDULED` again and the
+    // caller must queue it.
+    pub(crate) fn transition_to_idle(&self) -> bool {
+        match self
+            .0
+            .compare_exchange(RUNNING, IDLE, Ordering::SeqCst, Ordering::SeqCst)
+        {
+            Ok(_) => false,
+            Err(_) => {
+                // Only a wake can change the state of a running task, and it
+                // can only move it to `NOTIFIED`.
+                self.0.store(SCHEDULED, Ordering::SeqCst);
+                true
+            }
+        }
+    }
+
+    // Called once the future completed, panicked or was aborted.
+    pub(crate) fn transition_to_complete(&self) {
+        self.0.store(COMPLETE, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::State;
+
+    #[test]
+    fn wakes_while_scheduled_are_coalesced() {
+        let state = State::new();
+
+        // A new task is already queued.
+        assert!(!state.transition_to_scheduled());
+
+        state.transition_to_running();
+        assert!(!state.transition_to_idle());
+
+        // Only the first of several wakes queues the task.
+        assert!(state.transition_to_scheduled());
+        assert!(!state.transition_to_scheduled());
+        assert!(!state.transition_to_scheduled());
+    }
+
+    #[test]
+    fn a_wake_during_the_poll_requeues_the_task_once_it_returns() {
+        let state = State::new();
+        state.transition_to_running();
+
+        // The task is not queued while it is being polled...
+        assert!(!state.transition_to_scheduled());
+        assert!(!state.transition_to_scheduled());
+
+        // ...but once the poll returns, and only once.
+        assert!(state.transition_to_idle());
+        assert!(!state.transition_to_scheduled());
+    }
+
+    #[test]
+    fn wakes_after_completion_are_ignored() {
+        let state = State::new();
+        state.transition_to_running();
+        state.transition_to_complete();
+
+        assert!(!state.transition_to_scheduled());
+    }
+}
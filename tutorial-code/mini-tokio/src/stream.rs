@@ -0,0 +1,318 @@
+//! Streams.
+//!
+//! mini-tokio does not define its own `Stream` trait. It uses the one from the
+//! `futures` crate, along with its `StreamExt` combinators. The combinators
+//! that need a timer are in this module's `StreamExt`.
+
+use crate::{sleep_until, spawn_blocking, time, timeout, Elapsed, JoinHandle};
+
+use futures::Stream;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Stream combinators using the timers of the current mini-tokio instance.
+///
+/// Implemented for every stream. Bring it into scope with
+/// `use mini_tokio::stream::StreamExt`. The methods do not clash with the ones
+/// of `futures::StreamExt`, so both traits may be used together.
+pub trait StreamExt: Stream + Sized {
+    /// Awaits each future yielded by `self`, giving up on it after `dur`.
+    ///
+    /// Yields `Ok` with the output of the future if it completed in time, and
+    /// `Err(Elapsed)` otherwise, in which case the future is dropped and the
+    /// next one is awaited. The futures are awaited one at a time: the
+    /// deadline of a future starts once the previous one is done.
+    fn timeout_per_item(
+        self,
+        dur: Duration,
+    ) -> impl Stream<Item = Result<<Self::Item as Future>::Output, Elapsed>>
+    where
+        Self::Item: Future,
+    {
+        futures::StreamExt::then(self, move |item| timeout(dur, item))
+    }
+
+    /// Groups the items of `self` into batches.
+    ///
+    /// A batch is yielded once it holds `max_len` items, or once `dur` has
+    /// elapsed since its first item arrived, whichever comes first. A batch is
+    /// never empty. When `self` ends, the last, partial, batch is yielded
+    /// right away.
+    ///
+    /// This is the shape of a write path flushing in batches: a busy stream
+    /// fills batches quickly, while a quiet one still sees its items go out
+    /// within `dur`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_len` is zero.
+    fn chunks_timeout(self, max_len: usize, dur: Duration) -> ChunksTimeout<Self> {
+        assert!(max_len > 0, "`max_len` must be non-zero");
+
+        ChunksTimeout {
+            stream: Box::pin(self),
+            max_len,
+            dur,
+            items: Vec::with_capacity(max_len),
+            deadline: None,
+            done: false,
+        }
+    }
+}
+
+impl<S: Stream> StreamExt for S {}
+
+/// A stream yielding batches of items. Created by `StreamExt::chunks_timeout`.
+pub struct ChunksTimeout<S: Stream> {
+    // Boxed, so that the adapter can be moved around regardless of whether
+    // `S` is `Unpin`.
+    stream: Pin<Box<S>>,
+    max_len: usize,
+    dur: Duration,
+
+    // The batch being filled.
+    items: Vec<S::Item>,
+
+    // Completes when the current batch is due. Set along with the first item
+    // of each batch.
+    deadline: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+
+    // Set once `stream` ended.
+    done: bool,
+}
+
+/// A stream yielding the items of a blocking iterator. Created by
+/// `iter_blocking`.
+pub struct IterBlocking<I: Iterator> {
+    state: State<I>,
+}
+
+enum State<I: Iterator> {
+    // Waiting for the next call to `poll_next`.
+    Idle(I),
+
+    // `next` is running on the blocking pool. The job hands the iterator
+    // back along with the item.
+    Busy(JoinHandle<(I, Option<I::Item>)>),
+
+    // The iterator returned `None`.
+    Done,
+}
+
+// Turns `iter` into a stream. Every call to `iter.next()` runs on the blocking
+// thread pool, with `spawn_blocking`, so an iterator that blocks, such as a
+// cursor over the rows of a synchronous database client, does not block the
+// worker polling the stream.
+//
+// Items are read one at a time: the next one is only requested once the
+// previous one was yielded. If the stream is dropped while `next` runs, the
+// call completes on the blocking pool and its item is dropped.
+//
+// Polling the stream panics outside of a mini-tokio runtime, or if `next`
+// panics.
+pub fn iter_blocking<I>(iter: I) -> IterBlocking<I>
+where
+    I: Iterator + Send + 'static,
+    I::Item: Send + 'static,
+{
+    IterBlocking {
+        state: State::Idle(iter),
+    }
+}
+
+impl<S: Stream> ChunksTimeout<S> {
+    // Takes the current batch, leaving an empty one behind.
+    fn flush(&mut self) -> Vec<S::Item> {
+        self.deadline = None;
+        mem::replace(&mut self.items, Vec::with_capacity(self.max_len))
+    }
+}
+
+// The inner stream is pinned in its own box, and the batched items are never
+// pinned.
+impl<S: Stream> Unpin for ChunksTimeout<S> {}
+
+impl<S: Stream> Stream for ChunksTimeout<S> {
+    type Item = Vec<S::Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Vec<S::Item>>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+
+        // Take every item that is ready, until the batch is full.
+        loop {
+            match self.stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(item)) => {
+                    // The deadline is computed now, when the first item
+                    // arrives, rather than when the timer is first polled.
+                    if self.items.is_empty() {
+                        let when = time::now() + self.dur;
+                        self.deadline = Some(Box::pin(sleep_until(when)));
+                    }
+
+                    self.items.push(item);
+
+                    if self.items.len() == self.max_len {
+                        return Poll::Ready(Some(self.flush()));
+                    }
+                }
+                Poll::Ready(None) => {
+                    self.done = true;
+
+                    if self.items.is_empty() {
+                        return Poll::Ready(None);
+                    }
+                    return Poll::Ready(Some(self.flush()));
+                }
+                Poll::Pending => break,
+            }
+        }
+
+        // No more items for now. Yield the partial batch if it is due.
+        let due = match self.deadline.as_mut() {
+            Some(deadline) => deadline.as_mut().poll(cx).is_ready(),
+            None => false,
+        };
+
+        if due {
+            return Poll::Ready(Some(self.flush()));
+        }
+
+        Poll::Pending
+    }
+}
+
+// The iterator is moved in and out of the blocking pool, never pinned.
+impl<I: Iterator> Unpin for IterBlocking<I> {}
+
+impl<I> Stream for IterBlocking<I>
+where
+    I: Iterator + Send + 'static,
+    I::Item: Send + 'static,
+{
+    type Item = I::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<I::Item>> {
+        loop {
+            match &mut self.state {
+                State::Idle(_) => {
+                    if let State::Idle(mut iter) = mem::replace(&mut self.state, State::Done) {
+                        self.state = State::Busy(spawn_blocking(move || {
+                            let item = iter.next();
+                            (iter, item)
+                        }));
+                    }
+                }
+                State::Busy(job) => {
+                    let (iter, item) = match Pin::new(job).poll(cx) {
+                        Poll::Ready(Ok(output)) => output,
+                        Poll::Ready(Err(err)) => panic!("iterator failed; {}", err),
+                        Poll::Pending => return Poll::Pending,
+                    };
+
+                    self.state = match item {
+                        Some(_) => State::Idle(iter),
+                        None => State::Done,
+                    };
+
+                    return Poll::Ready(item);
+                }
+                State::Done => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stream::{iter_blocking, StreamExt as _};
+    use crate::{delay, spawn, yield_now, MiniTokio, TestRuntime};
+
+    use futures::stream;
+    use futures::StreamExt;
+    use std::iter;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn a_blocking_iterator_does_not_block_the_worker() {
+        let mini_tokio = MiniTokio::new();
+        let (item_tx, item_rx) = mpsc::channel();
+
+        let items = mini_tokio.block_on(async move {
+            // Each item blocks until the task below sent it. The task runs on
+            // the same, single, worker: had `next` blocked it, the items
+            // would never come and the iterator would end early.
+            let stream = iter_blocking(iter::from_fn(move || {
+                item_rx.recv_timeout(Duration::from_secs(5)).ok()
+            }));
+
+            spawn(async move {
+                for i in 0..3 {
+                    yield_now().await;
+                    item_tx.send(i).unwrap();
+                }
+            });
+
+            stream.collect::<Vec<_>>().await
+        });
+
+        assert_eq!(items, [0, 1, 2]);
+    }
+
+    #[test]
+    fn each_item_gets_its_own_timeout() {
+        let rt = TestRuntime::new();
+
+        let items = stream::iter([10, 30, 5, 20])
+            .map(|ms| delay(Duration::from_millis(ms)))
+            .timeout_per_item(Duration::from_millis(20));
+
+        let completed: Vec<_> = rt
+            .block_on_with_controls(items.collect::<Vec<_>>(), |ctl| {
+                ctl.advance(Duration::from_millis(1))
+            })
+            .iter()
+            .map(Result::is_ok)
+            .collect();
+
+        // A delay as long as the timeout completes: the timeout only wins
+        // once the future is still pending at the deadline.
+        assert_eq!(completed, [true, false, true, true]);
+    }
+
+    // A stream yielding `count` items, one every 10ms.
+    fn every_10ms(count: usize) -> impl futures::Stream<Item = usize> {
+        stream::iter(0..count).then(|i| async move {
+            delay(Duration::from_millis(10)).await;
+            i
+        })
+    }
+
+    // Collects `items` on a `TestRuntime`, advancing the clock 1ms at a time.
+    fn collect_batches(items: impl futures::Stream<Item = Vec<usize>>) -> Vec<Vec<usize>> {
+        TestRuntime::new().block_on_with_controls(items.collect::<Vec<_>>(), |ctl| {
+            ctl.advance(Duration::from_millis(1))
+        })
+    }
+
+    #[test]
+    fn full_batches_are_yielded_before_the_deadline() {
+        let batches = collect_batches(every_10ms(7).chunks_timeout(3, Duration::from_millis(100)));
+
+        assert_eq!(batches, [vec![0, 1, 2], vec![3, 4, 5], vec![6]]);
+    }
+
+    #[test]
+    fn partial_batches_are_yielded_at_the_deadline() {
+        // Items arrive at 10, 20, 30, 40 and 50ms. The first batch is due at
+        // 35ms, the second one would be at 65ms, but the stream ends first.
+        let batches = collect_batches(every_10ms(5).chunks_timeout(10, Duration::from_millis(25)));
+
+        assert_eq!(batches, [vec![0, 1, 2], vec![3, 4]]);
+    }
+}
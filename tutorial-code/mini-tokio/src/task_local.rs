@@ -0,0 +1,151 @@
+//! Task-local storage.
+//!
+//! A thread-local, like `CURRENT`, is not enough to attach data to a task: a
+//! task is polled on whichever worker receives it, and many tasks are polled on
+//! the same thread. A task-local value is stored in the task's future instead.
+//! Every time the future is polled, the value is moved into a thread-local for
+//! the duration of the poll, then moved back out. Code running within the
+//! poll, including any future awaited by the task, reads the value from the
+//! thread-local.
+
+use std::cell::RefCell;
+use std::future::{self, Future};
+use std::mem;
+use std::pin::pin;
+use std::thread;
+
+/// Declares a new task-local key of type `mini_tokio::LocalKey`.
+///
+/// The syntax is the same as `thread_local!`, without an initializer: a
+/// task-local only has a value within a `LocalKey::scope`. For example,
+/// `task_local! { static REQUEST_ID: u64; }`.
+#[macro_export]
+macro_rules! task_local {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty;) => {
+        $(#[$attr])*
+        $vis static $name: $crate::LocalKey<$t> = {
+            ::std::thread_local! {
+                static KEY: ::std::cell::RefCell<::std::option::Option<$t>> =
+                    const { ::std::cell::RefCell::new(::std::option::Option::None) };
+            }
+
+            $crate::LocalKey { inner: KEY }
+        };
+    };
+}
+
+/// A key for task-local data, declared with `task_local!`.
+pub struct LocalKey<T: 'static> {
+    // The thread-local holding the value while a task in its scope is polled.
+    #[doc(hidden)]
+    pub inner: thread::LocalKey<RefCell<Option<T>>>,
+}
+
+impl<T: 'static> LocalKey<T> {
+    /// Runs `future` with the task-local set to `value`.
+    ///
+    /// The value is visible to `future` and to everything it awaits, on every
+    /// poll, whichever thread polls it. It is not visible to tasks spawned by
+    /// `future`: those are separate tasks. Scopes may be nested, the innermost
+    /// value is visible.
+    pub async fn scope<F: Future>(&'static self, value: T, future: F) -> F::Output {
+        let mut value = Some(value);
+        let mut future = pin!(future);
+
+        future::poll_fn(|cx| self.enter(&mut value, || future.as_mut().poll(cx))).await
+    }
+
+    /// Calls `f` with a reference to the task-local value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a `scope` for this key.
+    pub fn with<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+        self.inner.with(|cell| {
+            let value = cell.borrow();
+            f(value
+                .as_ref()
+                .expect("task-local value accessed outside of its scope"))
+        })
+    }
+
+    // Moves `value` into the thread-local while `f` runs.
+    //
+    // The previous content of the thread-local is kept in `value` meanwhile,
+    // and put back once `f` returns. This way, a scope polled from within
+    // another scope of the same key, or a task polled right after another one
+    // on the same worker, does not see a value that is not its own.
+    fn enter<R>(&'static self, value: &mut Option<T>, f: impl FnOnce() -> R) -> R {
+        // Swaps the values back when dropped, including when `f` panics.
+        struct Guard<'a, T: 'static> {
+            key: &'static LocalKey<T>,
+            value: &'a mut Option<T>,
+        }
+
+        impl<T: 'static> Drop for Guard<'_, T> {
+            fn drop(&mut self) {
+                self.key
+                    .inner
+                    .with(|cell| mem::swap(self.value, &mut *cell.borrow_mut()));
+            }
+        }
+
+        self.inner
+            .with(|cell| mem::swap(value, &mut *cell.borrow_mut()));
+
+        let _guard = Guard { key: self, value };
+
+        f()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{spawn, yield_now, MiniTokio};
+
+    task_local! {
+        static REQUEST_ID: u64;
+    }
+
+    // An awaited call reading the task-local, as a handler would.
+    async fn current_request_id() -> u64 {
+        yield_now().await;
+        REQUEST_ID.with(|id| *id)
+    }
+
+    #[test]
+    fn awaited_futures_read_the_task_local() {
+        let mini_tokio = MiniTokio::new();
+
+        let ids = mini_tokio.block_on(async {
+            // Both tasks yield, so their polls interleave on the worker.
+            let first = spawn(REQUEST_ID.scope(1, async {
+                let inner = spawn(REQUEST_ID.scope(2, current_request_id()));
+                (current_request_id().await, inner.await.unwrap())
+            }));
+            let second = spawn(REQUEST_ID.scope(3, current_request_id()));
+
+            (first.await.unwrap(), second.await.unwrap())
+        });
+
+        assert_eq!(ids, ((1, 2), 3));
+    }
+
+    #[test]
+    fn nested_scopes_restore_the_outer_value() {
+        let mini_tokio = MiniTokio::new();
+
+        let ids = mini_tokio.block_on(REQUEST_ID.scope(1, async {
+            let inner = REQUEST_ID.scope(2, current_request_id()).await;
+            (inner, current_request_id().await)
+        }));
+
+        assert_eq!(ids, (2, 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "task-local value accessed outside of its scope")]
+    fn reading_outside_of_a_scope_panics() {
+        REQUEST_ID.with(|_| ());
+    }
+}
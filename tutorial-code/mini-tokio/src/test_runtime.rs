@@ -0,0 +1,311 @@
+//! A runtime for deterministic tests.
+//!
+//! A `TestRuntime` is a single-threaded mini-tokio instance with a `MockClock`
+//! where nothing happens on its own. The root future is polled whenever it is
+//! woken. Otherwise, the test decides what happens next: run one scheduled
+//! task, or move the clock forward. Tasks run in the order they were
+//! scheduled, timers fire when the clock says so, and no test ever sleeps.
+//!
+//! A runtime created with `with_seed` also decides, from the seed, whether
+//! each call to `maybe_yield` yields. Those calls act as preemption points: a
+//! test tries many seeds to explore interleavings of its tasks, and any seed
+//! that exposes a bug reproduces it on every run.
+
+use crate::{coop, yield_now, Builder, MiniTokio, MockClock, RootWaker};
+
+use crossbeam::channel;
+
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+thread_local! {
+    // The state of the random number generator of the seeded `TestRuntime`
+    // running on this thread, if any. See `maybe_yield`.
+    static RNG: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// A single-threaded runtime driven step by step. See the module
+/// documentation.
+pub struct TestRuntime {
+    mini_tokio: MiniTokio,
+    clock: MockClock,
+
+    // Decides whether `maybe_yield` yields. `None` if it never does.
+    seed: Option<u64>,
+}
+
+/// Controls passed to the closure of `TestRuntime::block_on_with_controls`.
+pub struct Controls<'a> {
+    runtime: &'a TestRuntime,
+
+    // Set when a task ran or the clock moved. Reset before every call to the
+    // closure.
+    progressed: Cell<bool>,
+}
+
+impl TestRuntime {
+    /// Returns a runtime whose clock is frozen at the current instant.
+    /// `maybe_yield` never yields on it.
+    pub fn new() -> TestRuntime {
+        let clock = MockClock::new();
+        let mini_tokio = Builder::new().clock(clock.clone()).build();
+
+        TestRuntime {
+            mini_tokio,
+            clock,
+            seed: None,
+        }
+    }
+
+    /// Returns a runtime like `new`, on which `maybe_yield` yields or not
+    /// depending on `seed`.
+    ///
+    /// Every call to `block_on_with_controls` starts over from the seed: the
+    /// same seed and the same controls make the same `maybe_yield` calls
+    /// yield.
+    pub fn with_seed(seed: u64) -> TestRuntime {
+        TestRuntime {
+            seed: Some(seed),
+            ..TestRuntime::new()
+        }
+    }
+
+    /// Runs `future` to completion and returns its output.
+    ///
+    /// The root future is polled first, then again every time it is woken.
+    /// In between, tasks are not run and time does not move: `controls` is
+    /// called instead, and uses the `Controls` to step a task or advance the
+    /// clock. Each call must make some progress possible.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the future panics, or if `controls` returns without the
+    /// root future being woken, a task having run or the clock having moved:
+    /// nothing could ever change, and the runtime would wait forever.
+    pub fn block_on_with_controls<F, C>(&self, future: F, mut controls: C) -> F::Output
+    where
+        F: Future,
+        C: FnMut(&Controls<'_>),
+    {
+        // Restores the previous generator when dropped, including when the
+        // future panics.
+        struct Reset(Option<u64>);
+
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                RNG.with(|cell| cell.set(self.0));
+            }
+        }
+
+        let _enter = self.mini_tokio.enter();
+        let _reset = Reset(RNG.with(|cell| cell.replace(self.seed)));
+        let mut future = pin!(future);
+
+        // As in `MiniTokio::block_on`, wakes of the root future are sent on
+        // this channel.
+        let (woken_tx, woken) = channel::bounded(1);
+        let waker = futures::task::waker(Arc::new(RootWaker(woken_tx, None)));
+        let ctl = Controls {
+            runtime: self,
+            progressed: Cell::new(false),
+        };
+
+        loop {
+            let mut cx = Context::from_waker(&waker);
+
+            if let Poll::Ready(output) = coop::budget(|| future.as_mut().poll(&mut cx)) {
+                return output;
+            }
+
+            while woken.try_recv().is_err() {
+                ctl.progressed.set(false);
+                controls(&ctl);
+
+                if !ctl.progressed.get() && woken.is_empty() {
+                    panic!("the controls made no progress while the root future is pending");
+                }
+            }
+        }
+    }
+}
+
+// Yields to the other tasks, or not, at random: a preemption point for tests.
+//
+// On a `TestRuntime` created with `with_seed`, whether each call yields is
+// drawn from the seed, so a given seed always interleaves the tasks the same
+// way. Anywhere else, this completes right away, and costs a thread-local
+// read.
+pub async fn maybe_yield() {
+    if RNG.with(next_bool) {
+        yield_now().await;
+    }
+}
+
+// Draws the next value of the generator stored in `rng`, if any, with
+// SplitMix64. Returns `false` if there is no generator.
+fn next_bool(rng: &Cell<Option<u64>>) -> bool {
+    let state = match rng.get() {
+        Some(state) => state.wrapping_add(0x9e37_79b9_7f4a_7c15),
+        None => return false,
+    };
+
+    rng.set(Some(state));
+
+    let mut z = state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+
+    z & 1 == 1
+}
+
+impl Default for TestRuntime {
+    fn default() -> TestRuntime {
+        TestRuntime::new()
+    }
+}
+
+impl Controls<'_> {
+    /// Polls the next scheduled task, if any. Returns `false` if no task is
+    /// scheduled.
+    pub fn tick(&self) -> bool {
+        let mini_tokio = &self.runtime.mini_tokio;
+
+        match mini_tokio.scheduled.try_recv() {
+            Ok(task) => {
+                self.progressed.set(true);
+                task.poll();
+                mini_tokio.refill();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Moves the clock forward by `dur`. The timers that expire are fired
+    /// before this returns, which schedules the tasks waiting on them.
+    pub fn advance(&self, dur: Duration) {
+        self.progressed.set(true);
+        self.runtime.clock.advance(dur);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{delay, maybe_yield, spawn, timeout, TestRuntime};
+
+    use std::future;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn a_timeout_elapses_by_advancing_the_clock() {
+        let rt = TestRuntime::new();
+        let real_start = Instant::now();
+        let mut advances = 0;
+
+        let res = rt.block_on_with_controls(
+            timeout(Duration::from_secs(1), future::pending::<()>()),
+            |ctl| {
+                advances += 1;
+                ctl.advance(Duration::from_millis(500));
+            },
+        );
+
+        assert!(res.is_err());
+        assert_eq!(advances, 2);
+        assert!(real_start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn tasks_run_one_tick_at_a_time_in_order() {
+        let rt = TestRuntime::new();
+        let order = Arc::new(Mutex::new(vec![]));
+        let mut ticks = 0;
+
+        let spawned = order.clone();
+
+        rt.block_on_with_controls(
+            async move {
+                let handles: Vec<_> = (0..3)
+                    .map(|i| {
+                        let order = spawned.clone();
+
+                        spawn(async move {
+                            delay(Duration::from_secs(i)).await;
+                            order.lock().unwrap().push(i);
+                        })
+                    })
+                    .collect();
+
+                for handle in handles {
+                    handle.await.unwrap();
+                }
+            },
+            |ctl| {
+                // Every task gets a first poll, in spawn order, before any
+                // time passes.
+                if ctl.tick() {
+                    ticks += 1;
+                } else {
+                    ctl.advance(Duration::from_secs(1));
+                }
+            },
+        );
+
+        assert_eq!(*order.lock().unwrap(), [0, 1, 2]);
+        assert_eq!(ticks, 5);
+    }
+
+    // Runs two tasks incrementing a counter 10 times each, with a preemption
+    // point in between reading and writing it. Returns the final count.
+    fn racy_increments(rt: &TestRuntime) -> usize {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let spawned = counter.clone();
+
+        rt.block_on_with_controls(
+            async move {
+                let handles: Vec<_> = (0..2)
+                    .map(|_| {
+                        let counter = spawned.clone();
+
+                        spawn(async move {
+                            for _ in 0..10 {
+                                let count = counter.load(Ordering::SeqCst);
+                                maybe_yield().await;
+                                counter.store(count + 1, Ordering::SeqCst);
+                            }
+                        })
+                    })
+                    .collect();
+
+                for handle in handles {
+                    handle.await.unwrap();
+                }
+            },
+            |ctl| assert!(ctl.tick()),
+        );
+
+        counter.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn a_seed_reproduces_the_same_interleaving() {
+        // Without a seed, each task runs its loop in a single poll.
+        assert_eq!(racy_increments(&TestRuntime::new()), 20);
+
+        // With one, the tasks are preempted in between reading and writing
+        // the counter, and updates get lost. The same ones, every time.
+        let lost = racy_increments(&TestRuntime::with_seed(1));
+        assert!(lost < 20);
+
+        for _ in 0..3 {
+            assert_eq!(racy_increments(&TestRuntime::with_seed(1)), lost);
+        }
+    }
+}
@@ -0,0 +1,801 @@
+//! Timers.
+//!
+//! All of the timers of a mini-tokio instance are managed by a single timer
+//! driver. The driver owns one background thread and a timing wheel of pending
+//! deadlines, see the `timer_wheel` module. Each timer registers a deadline
+//! with the driver, and the driver thread sleeps until the nearest deadline,
+//! notifies the timers whose deadline has been reached, and goes back to
+//! sleep. The timers wait on a `Notify`, so the driver never handles the
+//! task's waker itself.
+//!
+//! This is a lot cheaper than spawning a thread per timer, but it is still not
+//! how Tokio does it. Tokio does not use a separate thread at all: the worker
+//! threads check for expired timers whenever they park. With
+//! `Builder::park_on_io`, a single-threaded instance does the same: the worker
+//! waits for I/O events until the next deadline, then fires the expired
+//! timers.
+//!
+//! `timeout` and `interval` use the timer of the current instance. The same
+//! combinators work with any `Timer`, through `timeout_with` and
+//! `interval_with`. See the `executor` module.
+
+use crate::executor::{RuntimeTimer, Timer};
+use crate::timer_wheel::{TimerKey, TimerWheel};
+use crate::{coop, Clock, Notify, CURRENT};
+
+use futures::task::{self, ArcWake};
+use std::error::Error;
+use std::fmt;
+use std::future::{self, Future};
+use std::pin::pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::Poll;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Asynchronous equivalent to `thread::sleep`. Awaiting on this function pauses
+// for the given duration.
+//
+// This is the name used throughout the tutorial. It is the same as `sleep`.
+pub async fn delay(dur: Duration) {
+    sleep_until(now() + dur).await;
+}
+
+// Pauses for the given duration. Same as `tokio::time::sleep`.
+pub async fn sleep(dur: Duration) {
+    sleep_until(now() + dur).await;
+}
+
+// Pauses until `deadline` is reached. Completes right away if the deadline is
+// in the past.
+//
+// Waiting on an absolute deadline is useful when the same deadline applies to
+// several operations, for example all the attempts of a retry loop: computing
+// a duration on each iteration would push the deadline back every time.
+pub async fn sleep_until(deadline: Instant) {
+    // The deadline is already reached. Complete right away, without going
+    // through the timer driver, once the task's coop budget allows it.
+    if now() >= deadline {
+        future::poll_fn(coop::poll_proceed).await;
+        return;
+    }
+
+    // Register the deadline with the timer driver of the current mini-tokio
+    // instance. The driver does not deal with wakers: it notifies `notify`
+    // once the deadline is reached, and `Notify` takes care of waking the
+    // task. See the `notify` module.
+    let notify = Arc::new(Notify::new());
+    let timer = timer();
+    let key = timer.register(deadline, notify.clone());
+
+    // If this future is dropped before the deadline, because it lost a race
+    // against another future or because its task was dropped, the guard
+    // removes the deadline from the driver. Nothing is notified, and the
+    // `Notified` future leaves the waiter list, releasing the task's waker
+    // right away. Once the timer fired, cancelling it does nothing.
+    let _guard = CancelOnDrop { timer, key };
+
+    notify.notified().await;
+}
+
+/// Error returned by `timeout` when the duration elapsed before the future
+/// completed.
+#[derive(Debug)]
+pub struct Elapsed(());
+
+// Requires `future` to complete before `dur` has elapsed. Returns the output of
+// `future`, or `Err(Elapsed)` if the duration elapsed first. In that case,
+// `future` is dropped without being polled again.
+pub async fn timeout<F: Future>(dur: Duration, future: F) -> Result<F::Output, Elapsed> {
+    timeout_with(&RuntimeTimer, dur, future).await
+}
+
+// Same as `timeout`, waiting on `timer` instead of the timer of the current
+// mini-tokio instance.
+pub async fn timeout_with<T, F>(timer: &T, dur: Duration, future: F) -> Result<F::Output, Elapsed>
+where
+    T: Timer,
+    F: Future,
+{
+    // The deadline is computed now. With the mini-tokio timer, the deadline
+    // is only registered with the timer driver when the delay is first
+    // polled, which is after `future` returned `Poll::Pending`. A future that
+    // completes on its first poll never touches the timer driver.
+    let mut delay = pin!(timer.sleep(dur));
+
+    // `future` may not be `Unpin`. Pinning it on the stack of this `async fn`
+    // lets us poll it without boxing it.
+    let mut future = pin!(future);
+
+    // Both futures are polled from the same `poll` call, with the same
+    // `Context`. Whichever one is not ready stores the waker of the current
+    // task, so the task is woken by whichever completes first.
+    //
+    // `delay` is dropped when `timeout` returns. If the inner future won, this
+    // cancels the timer so the driver does not wake a task that no longer
+    // waits on it.
+    future::poll_fn(|cx| {
+        // Poll the inner future first. If it completes on the same tick the
+        // delay expires, its output is returned rather than thrown away.
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+
+        match delay.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed(()))),
+            Poll::Pending => Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// Timeouts as methods on futures.
+///
+/// Implemented for every future. Bring it into scope with
+/// `use mini_tokio::FutureExt`.
+pub trait FutureExt: Future + Sized {
+    /// Awaits `self`, giving up after `dur`.
+    ///
+    /// Returns `Some` with the output of `self` if it completed in time, and
+    /// `None` otherwise, in which case `self` is dropped without being polled
+    /// again. This is `timeout`, for when the caller does not care about the
+    /// `Elapsed` error: `resp_rx.with_timeout(dur).await`.
+    fn with_timeout(self, dur: Duration) -> impl Future<Output = Option<Self::Output>> {
+        async move { timeout(dur, self).await.ok() }
+    }
+}
+
+impl<F: Future> FutureExt for F {}
+
+/// A stream of instants, spaced `period` apart. Created by `interval`, or by
+/// `interval_with` for a timer other than mini-tokio's.
+pub struct Interval<T = RuntimeTimer> {
+    // The instant at which the next tick is due.
+    next: Instant,
+    period: Duration,
+    timer: T,
+}
+
+// Creates an `Interval` that ticks every `period`. The first tick completes
+// immediately.
+//
+// Panics if `period` is zero.
+pub fn interval(period: Duration) -> Interval {
+    interval_with(RuntimeTimer, period)
+}
+
+// Same as `interval`, ticking on `timer` instead of the timer of the current
+// mini-tokio instance.
+//
+// Panics if `period` is zero.
+pub fn interval_with<T: Timer>(timer: T, period: Duration) -> Interval<T> {
+    assert!(
+        period > Duration::from_millis(0),
+        "`period` must be non-zero"
+    );
+
+    Interval {
+        next: timer.now(),
+        period,
+        timer,
+    }
+}
+
+impl<T: Timer> Interval<T> {
+    /// Waits until the next tick is due and returns the instant it was
+    /// scheduled for.
+    ///
+    /// Each deadline is computed from the previous *scheduled* tick, not from
+    /// the time `tick` is called. The time spent by the caller in between two
+    /// ticks does not push the schedule back, so the interval does not drift.
+    ///
+    /// If the caller falls behind by more than one period, the missed ticks
+    /// are not skipped: the following calls complete immediately, in a burst,
+    /// until the interval has caught up with the schedule.
+    pub async fn tick(&mut self) -> Instant {
+        let when = self.next;
+
+        self.timer.sleep_until(when).await;
+
+        self.next = when + self.period;
+        when
+    }
+}
+
+// Returns the current instant according to the clock of the current mini-tokio
+// instance. Outside of a runtime, or if its timers are disabled, this is the
+// real time.
+pub(crate) fn now() -> Instant {
+    CURRENT.with(|cell| {
+        let borrow = cell.borrow();
+
+        match borrow.as_ref().and_then(|handle| handle.timer.as_ref()) {
+            Some(timer) => timer.shared.clock.now(),
+            None => Instant::now(),
+        }
+    })
+}
+
+// Returns the timer driver of the current mini-tokio instance.
+//
+// Panics if called outside of a mini-tokio runtime, or if timers are
+// disabled.
+fn timer() -> TimerHandle {
+    CURRENT.with(|cell| {
+        let borrow = cell.borrow();
+        let handle = borrow
+            .as_ref()
+            .expect("delay polled outside of a mini-tokio runtime");
+        handle
+            .timer
+            .clone()
+            .expect("timers are disabled; enable them with `Builder::enable_timer`")
+    })
+}
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "deadline has elapsed".fmt(fmt)
+    }
+}
+
+impl Error for Elapsed {}
+
+// The timer driver of a mini-tokio instance. Owns the driver thread, which is
+// stopped when the driver is dropped. `thread` is `None` when the worker
+// fires the timers itself.
+pub(crate) struct Driver {
+    handle: TimerHandle,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+// Used to register deadlines with a timer driver. Cheap to clone.
+#[derive(Clone)]
+pub(crate) struct TimerHandle {
+    shared: Arc<Shared>,
+}
+
+// State shared by the driver thread and the timer handles.
+struct Shared {
+    state: Mutex<State>,
+
+    // Signaled when the driver thread must re-evaluate how long to sleep: a
+    // deadline earlier than all others was registered, the clock jumped
+    // forward, or the driver is shutting down.
+    condvar: Condvar,
+
+    // Where the current time is read from.
+    clock: Arc<dyn Clock>,
+
+    // Held while collecting and notifying expired timers. See
+    // `fire_expired`.
+    firing: Mutex<()>,
+}
+
+struct State {
+    // Pending deadlines.
+    wheel: TimerWheel,
+
+    // Set when the driver is dropped.
+    shutdown: bool,
+}
+
+// The waker stored in the wheel for a deadline. Waking it notifies the timer.
+struct NotifyWaker(Arc<Notify>);
+
+// Cancels a registered deadline when dropped. Held by `sleep_until`.
+struct CancelOnDrop {
+    timer: TimerHandle,
+    key: TimerKey,
+}
+
+impl Driver {
+    // Start a timer driver and its thread. If `inline` is set, no thread is
+    // started: the worker uses `park_timeout` and `fire_expired` instead.
+    pub(crate) fn new(thread_name: String, clock: Arc<dyn Clock>, inline: bool) -> Driver {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                wheel: TimerWheel::starting_at(clock.now()),
+                shutdown: false,
+            }),
+            condvar: Condvar::new(),
+            clock,
+            firing: Mutex::new(()),
+        });
+
+        // When the clock jumps, the timers it made expire are notified right
+        // away, on the thread moving the clock, instead of waiting for the
+        // driver thread to notice. Code advancing a `MockClock` then knows
+        // that every expired timer was notified once `advance` returns.
+        let weak = Arc::downgrade(&shared);
+
+        shared.clock.on_advance(Box::new(move || {
+            if let Some(shared) = weak.upgrade() {
+                shared.fire_expired();
+            }
+        }));
+
+        let thread = if inline {
+            None
+        } else {
+            let shared = shared.clone();
+
+            Some(
+                thread::Builder::new()
+                    .name(thread_name)
+                    .spawn(move || shared.run())
+                    .expect("failed to spawn the timer thread"),
+            )
+        };
+
+        Driver {
+            handle: TimerHandle { shared },
+            thread,
+        }
+    }
+
+    pub(crate) fn handle(&self) -> TimerHandle {
+        self.handle.clone()
+    }
+
+    // Returns how long the worker may wait before the next deadline, in real
+    // time, or `None` if there is no deadline. Like the driver thread, the
+    // worker wakes up early with a clock that does not follow real time.
+    //
+    // Deadlines are only registered on the worker, so none can be added while
+    // it waits.
+    pub(crate) fn park_timeout(&self) -> Option<Duration> {
+        let shared = &self.handle.shared;
+        let state = shared.state.lock().unwrap();
+        let now = shared.clock.now();
+
+        state
+            .wheel
+            .next_deadline()
+            .map(|when| when.saturating_duration_since(now))
+    }
+
+    // Notify the timers whose deadline has been reached. Called by the worker
+    // once it is done waiting.
+    pub(crate) fn fire_expired(&self) {
+        self.handle.shared.fire_expired();
+    }
+}
+
+impl Drop for Driver {
+    fn drop(&mut self) {
+        self.handle.shared.state.lock().unwrap().shutdown = true;
+        self.handle.shared.condvar.notify_one();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl TimerHandle {
+    // Notify `notify` once `when` is reached. The returned key cancels the
+    // deadline.
+    fn register(&self, when: Instant, notify: Arc<Notify>) -> TimerKey {
+        let mut state = self.shared.state.lock().unwrap();
+
+        // The driver thread sleeps until the earliest deadline it knows about.
+        // If the new deadline comes first, the thread must wake up and sleep
+        // for a shorter time.
+        let earliest = match state.wheel.next_deadline() {
+            Some(next) => when < next,
+            None => true,
+        };
+
+        let key = state
+            .wheel
+            .insert(when, task::waker(Arc::new(NotifyWaker(notify))));
+
+        if earliest {
+            self.shared.condvar.notify_one();
+        }
+
+        key
+    }
+
+    // Remove the deadline identified by `key`, unless it already fired.
+    //
+    // The driver thread is not notified: it may wake up for a deadline that
+    // is no longer there, find nothing to do and go back to sleep.
+    fn cancel(&self, key: TimerKey) {
+        self.shared.state.lock().unwrap().wheel.cancel(key);
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.timer.cancel(self.key);
+    }
+}
+
+impl Shared {
+    // Notify the timers whose deadline has been reached.
+    //
+    // Both the driver thread and the clock, when it jumps, call this. The
+    // `firing` lock is held until the timers are notified, so that once this
+    // returns, the timers collected by a concurrent call were notified too.
+    fn fire_expired(&self) {
+        let _firing = self.firing.lock().unwrap();
+
+        // Collect the timers whose deadline has been reached.
+        let expired = {
+            let mut state = self.state.lock().unwrap();
+            let now = self.clock.now();
+            state.wheel.advance(now)
+        };
+
+        // Notify without holding the state lock. Notifying wakes a task, and
+        // a waker may do anything, including registering a new deadline.
+        for waker in expired {
+            waker.wake();
+        }
+    }
+
+    // The driver thread's loop.
+    fn run(&self) {
+        loop {
+            self.fire_expired();
+
+            // The lock is held from the moment the time is read until the
+            // thread waits on the condition variable, so that a deadline
+            // registered in between cannot slip through.
+            let state = self.state.lock().unwrap();
+
+            if state.shutdown {
+                return;
+            }
+
+            let now = self.clock.now();
+
+            // Sleep until the next deadline, or until notified if there is
+            // none. The condition variable is also notified when an earlier
+            // deadline is registered. Spurious wakeups are fine, the loop
+            // re-checks everything.
+            //
+            // The sleep is measured in real time. With a clock that does not
+            // follow real time, the driver may wake up before the clock
+            // reached the deadline. It then goes back to sleep.
+            match state.wheel.next_deadline() {
+                Some(when) => {
+                    let timeout = when.saturating_duration_since(now);
+                    drop(self.condvar.wait_timeout(state, timeout).unwrap());
+                }
+                None => drop(self.condvar.wait(state).unwrap()),
+            }
+        }
+    }
+}
+
+impl ArcWake for NotifyWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{oneshot, spawn, yield_now, Builder, MiniTokio, MockClock};
+
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::task::Context;
+
+    // A waker counting how many times it was woken.
+    #[derive(Default)]
+    struct CountWakes(AtomicUsize);
+
+    impl ArcWake for CountWakes {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // Returns the number of deadlines registered with the current runtime's
+    // timer driver.
+    fn pending_timers() -> usize {
+        timer().shared.state.lock().unwrap().wheel.len()
+    }
+
+    #[test]
+    fn with_timeout_drops_the_slow_future() {
+        struct SetOnDrop(Arc<AtomicBool>);
+
+        impl Drop for SetOnDrop {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let mini_tokio = MiniTokio::new();
+        let dropped = Arc::new(AtomicBool::new(false));
+
+        let guard = SetOnDrop(dropped.clone());
+        let slow = async move {
+            let _guard = guard;
+            sleep(Duration::from_secs(60)).await;
+        };
+
+        let (fast, slow) = mini_tokio.block_on(async {
+            let fast = spawn(async { 1 }.with_timeout(Duration::from_millis(10)));
+            let slow = slow.with_timeout(Duration::from_millis(10)).await;
+
+            (fast.await.unwrap(), slow)
+        });
+
+        assert_eq!(fast, Some(1));
+        assert_eq!(slow, None);
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn timeout_elapses_before_a_slow_future() {
+        let clock = MockClock::new();
+        let mini_tokio = Builder::new().clock(clock.clone()).build();
+        let (started_tx, started_rx) = oneshot::channel();
+
+        let handle = mini_tokio.spawn(async move {
+            let _ = started_tx.send(());
+            timeout(Duration::from_secs(1), future::pending::<()>()).await
+        });
+
+        // The task registers its timer in the same poll it reports starting.
+        mini_tokio.block_on(started_rx).unwrap();
+
+        clock.advance(Duration::from_secs(1));
+
+        assert!(mini_tokio.block_on(handle).unwrap().is_err());
+        mini_tokio.block_on(async { assert_eq!(pending_timers(), 0) });
+    }
+
+    #[test]
+    fn timeout_prefers_the_output_on_a_tie() {
+        let clock = MockClock::new();
+        let mini_tokio = Builder::new().clock(clock.clone()).build();
+        let (started_tx, started_rx) = oneshot::channel();
+
+        let handle = mini_tokio.spawn(async move {
+            let _ = started_tx.send(());
+
+            // Both deadlines are reached by the same advance of the clock.
+            let inner = async {
+                sleep(Duration::from_secs(1)).await;
+                "inner"
+            };
+
+            timeout(Duration::from_secs(1), inner).await
+        });
+
+        mini_tokio.block_on(started_rx).unwrap();
+        clock.advance(Duration::from_secs(1));
+
+        assert_eq!(mini_tokio.block_on(handle).unwrap().unwrap(), "inner");
+    }
+
+    #[test]
+    fn timeout_cancels_its_timer_once_the_future_completes() {
+        let mini_tokio = Builder::new().clock(MockClock::new()).build();
+
+        mini_tokio.block_on(async {
+            let output = timeout(Duration::from_secs(60), async {
+                yield_now().await;
+                1
+            })
+            .await;
+
+            assert_eq!(output.unwrap(), 1);
+            assert_eq!(pending_timers(), 0);
+        });
+    }
+
+    #[test]
+    fn timeout_over_a_ready_future_registers_no_timer() {
+        let mini_tokio = MiniTokio::new();
+
+        mini_tokio.block_on(async {
+            let output = timeout(Duration::from_secs(1), async { 1 }).await;
+
+            assert_eq!(output.unwrap(), 1);
+            assert_eq!(pending_timers(), 0);
+        });
+
+        // Without a timer driver at all, the timeout does not notice.
+        let mini_tokio = Builder::new().enable_timer(false).build();
+        let output = mini_tokio.block_on(timeout(Duration::from_secs(1), async { 1 }));
+
+        assert_eq!(output.unwrap(), 1);
+    }
+
+    #[test]
+    fn interval_ticks_period_apart() {
+        let clock = MockClock::new();
+        let mini_tokio = Builder::new().clock(clock.clone()).build();
+        let period = Duration::from_secs(10);
+
+        mini_tokio.block_on(async {
+            let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+            let start = now();
+            let mut interval = interval(period);
+
+            // The first tick completes immediately.
+            assert_eq!(interval.tick().await, start);
+
+            let mut tick = Box::pin(interval.tick());
+            clock.advance(period - Duration::from_millis(1));
+            assert!(tick.as_mut().poll(&mut cx).is_pending());
+
+            clock.advance(Duration::from_millis(1));
+            assert_eq!(tick.await, start + period);
+
+            // The consumer is late, but the schedule does not drift: the next
+            // tick is still due `period` after the previous one.
+            clock.advance(period + period / 2);
+            assert_eq!(interval.tick().await, start + period * 2);
+        });
+    }
+
+    #[test]
+    fn sleep_until_a_past_deadline_completes_right_away() {
+        let mini_tokio = Builder::new().clock(MockClock::new()).build();
+
+        mini_tokio.block_on(async {
+            let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+            let mut sleep = Box::pin(sleep_until(now() - Duration::from_secs(1)));
+
+            assert!(sleep.as_mut().poll(&mut cx).is_ready());
+            assert_eq!(pending_timers(), 0);
+        });
+    }
+
+    #[test]
+    fn sleep_until_wakes_the_task_that_polled_it_last() {
+        let clock = MockClock::new();
+        let mini_tokio = Builder::new().clock(clock.clone()).build();
+
+        mini_tokio.block_on(async {
+            let first = Arc::new(CountWakes::default());
+            let second = Arc::new(CountWakes::default());
+            let first_waker = task::waker(first.clone());
+            let second_waker = task::waker(second.clone());
+
+            let mut sleep = Box::pin(sleep_until(now() + Duration::from_secs(1)));
+
+            // The future moves from one task to another in between polls.
+            let mut cx = Context::from_waker(&first_waker);
+            assert!(sleep.as_mut().poll(&mut cx).is_pending());
+            let mut cx = Context::from_waker(&second_waker);
+            assert!(sleep.as_mut().poll(&mut cx).is_pending());
+
+            clock.advance(Duration::from_secs(1));
+
+            // The driver thread notices the advance asynchronously.
+            for _ in 0..5000 {
+                if second.0.load(Ordering::SeqCst) > 0 {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+
+            assert_eq!(second.0.load(Ordering::SeqCst), 1);
+            assert_eq!(first.0.load(Ordering::SeqCst), 0);
+            assert!(sleep.as_mut().poll(&mut cx).is_ready());
+        });
+    }
+
+    #[test]
+    fn sleeps_sharing_a_deadline_complete_in_order() {
+        let clock = MockClock::new();
+        let mini_tokio = Builder::new().clock(clock.clone()).build();
+        let deadline = clock.now() + Duration::from_secs(1);
+        let order = Arc::new(Mutex::new(vec![]));
+
+        let started: Vec<_> = (0..5)
+            .map(|i| {
+                let order = order.clone();
+                let (started_tx, started_rx) = oneshot::channel();
+
+                mini_tokio.spawn(async move {
+                    let _ = started_tx.send(());
+                    sleep_until(deadline).await;
+                    order.lock().unwrap().push(i);
+                });
+
+                started_rx
+            })
+            .collect();
+
+        // Each task registers its deadline in the same poll it reports
+        // starting.
+        for started_rx in started {
+            mini_tokio.block_on(started_rx).unwrap();
+        }
+
+        clock.advance(Duration::from_secs(1));
+        mini_tokio.run_until_idle();
+
+        assert_eq!(*order.lock().unwrap(), [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn dropping_a_sleep_cancels_its_timer() {
+        let clock = MockClock::new();
+        let mini_tokio = Builder::new().clock(clock.clone()).build();
+
+        mini_tokio.block_on(async {
+            let wakes = Arc::new(CountWakes::default());
+            let waker = task::waker(wakes.clone());
+            let mut cx = Context::from_waker(&waker);
+
+            let mut sleep = Box::pin(sleep(Duration::from_secs(1)));
+            assert!(sleep.as_mut().poll(&mut cx).is_pending());
+            assert_eq!(pending_timers(), 1);
+
+            drop(sleep);
+            assert_eq!(pending_timers(), 0);
+
+            // Give the driver a chance to fire the deadline, had it been
+            // left behind.
+            clock.advance(Duration::from_secs(2));
+            thread::sleep(Duration::from_millis(50));
+
+            assert_eq!(wakes.0.load(Ordering::SeqCst), 0);
+        });
+    }
+
+    // Returns the number of threads of this process whose name starts with
+    // `prefix`.
+    #[cfg(target_os = "linux")]
+    fn count_threads(prefix: &str) -> usize {
+        std::fs::read_dir("/proc/self/task")
+            .unwrap()
+            .filter_map(|task| std::fs::read_to_string(task.ok()?.path().join("comm")).ok())
+            .filter(|name| name.starts_with(prefix))
+            .count()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn concurrent_delays_share_a_single_thread() {
+        // Threads spawned without a name inherit the name of the thread
+        // spawning them. Running the runtime on a thread with a unique name
+        // catches those too, along with the runtime's own named threads.
+        // Linux truncates thread names to 15 bytes.
+        const PREFIX: &str = "delays-1000";
+
+        let runtime = thread::Builder::new().name(PREFIX.to_string()).spawn(|| {
+            let clock = MockClock::new();
+            let mini_tokio = Builder::new()
+                .thread_name_prefix(PREFIX)
+                .clock(clock.clone())
+                .build();
+
+            mini_tokio.block_on(async {
+                let delays: Vec<_> = (0..1000)
+                    .map(|_| spawn(delay(Duration::from_secs(60))))
+                    .collect();
+
+                while pending_timers() < 1000 {
+                    yield_now().await;
+                }
+
+                // The thread running the runtime, plus the timer thread.
+                let extra = count_threads(PREFIX) - 1;
+
+                clock.advance(Duration::from_secs(60));
+
+                for delay in delays {
+                    delay.await.unwrap();
+                }
+
+                extra
+            })
+        });
+
+        assert_eq!(runtime.unwrap().join().unwrap(), 1);
+    }
+}
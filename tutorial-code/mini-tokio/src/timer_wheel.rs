@@ -0,0 +1,508 @@
+//! A hierarchical hashed timing wheel.
+//!
+//! The timer driver keeps its deadlines in this wheel. The obvious structure
+//! for the job, a `BinaryHeap`, costs `O(log n)` to register a deadline and
+//! `O(n)` to cancel one, since cancelling means searching the heap. Servers set
+//! and cancel timers constantly: most request timeouts never fire because the
+//! request completes first. Under that kind of churn the heap becomes the
+//! bottleneck, which is why Tokio uses a timing wheel instead.
+//!
+//! A timing wheel divides time into fixed-size ticks, one millisecond here,
+//! and hashes each timer into a slot by its deadline. Inserting and cancelling
+//! only touch a single slot, so both are `O(1)`.
+//!
+//! A single wheel of 64 slots only covers 64 milliseconds. To cover longer
+//! durations, the wheel is hierarchical, like the hands of a clock. It has six
+//! levels of 64 slots each. A slot on level 0 spans one tick, a slot on level 1
+//! spans 64 ticks, a slot on level 2 spans 64 * 64 ticks, and so on. A timer
+//! is stored on the lowest level whose current rotation contains its deadline.
+//! When the wheel reaches a slot on a higher level, the timers in that slot
+//! are "cascaded": they are re-inserted into lower levels, now that their
+//! deadline is close enough. Each timer is moved at most once per level.
+//!
+//! Six levels of 64 slots cover 2^36 milliseconds, a little over two years.
+//! Timers further out are parked on the top level and moved again once the
+//! wheel gets there.
+//!
+//! Timers sharing a deadline fire in the order they were inserted. The order
+//! of the timers within a slot is lost along the way, as timers are cancelled
+//! and cascaded, so each timer carries a sequence number to restore it.
+
+use std::task::Waker;
+use std::time::{Duration, Instant};
+
+// Number of slots per level, as a power of two.
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = (SLOTS as u64) - 1;
+const LEVELS: usize = 6;
+
+/// A hierarchical timing wheel with millisecond resolution.
+///
+/// The wheel does not have a thread of its own. The owner calls `advance` to
+/// collect the timers whose deadline has been reached, typically after
+/// sleeping until `next_deadline`, and wakes them.
+pub struct TimerWheel {
+    // The instant corresponding to tick 0.
+    start: Instant,
+
+    // Number of ticks the wheel has been advanced to. Every timer with a
+    // deadline at or before this tick has fired.
+    elapsed: u64,
+
+    levels: Vec<Level>,
+
+    // Storage for the timers, indexed by `TimerKey::index`. Vacant entries are
+    // tracked in `free` and reused.
+    entries: Vec<Slab>,
+    free: Vec<usize>,
+
+    // Number of pending timers.
+    len: usize,
+
+    // Sequence number of the next timer inserted.
+    next_seq: u64,
+}
+
+/// Identifies a timer registered with a `TimerWheel`. Used to cancel it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimerKey {
+    index: usize,
+
+    // Storage is reused once a timer fires or is cancelled. The generation
+    // distinguishes the current occupant from earlier ones, so a stale key
+    // cannot cancel an unrelated timer.
+    generation: u64,
+}
+
+struct Level {
+    // Bit `n` is set when slot `n` is not empty. Finding the next occupied slot
+    // is then a matter of counting zero bits.
+    occupied: u64,
+
+    // The timers in each slot, as indices into `TimerWheel::entries`.
+    slots: Vec<Vec<usize>>,
+}
+
+struct Slab {
+    generation: u64,
+    entry: Option<Entry>,
+}
+
+struct Entry {
+    // Deadline, in ticks.
+    when: u64,
+    waker: Waker,
+
+    // Insertion order, to fire timers sharing a deadline first come, first
+    // served.
+    seq: u64,
+
+    // Where the entry is stored: level, slot and position within the slot.
+    // Keeping the position makes removal `O(1)`.
+    level: usize,
+    slot: usize,
+    pos: usize,
+}
+
+// The next slot to process, as returned by `next_expiration`.
+struct Expiration {
+    level: usize,
+    slot: usize,
+    // The tick at which the slot starts.
+    deadline: u64,
+}
+
+impl TimerWheel {
+    /// Creates an empty wheel. Tick 0 is the current instant.
+    pub fn new() -> TimerWheel {
+        TimerWheel::starting_at(Instant::now())
+    }
+
+    /// Creates an empty wheel whose tick 0 is `start`.
+    ///
+    /// Deadlines and the instants passed to `advance` must come from the same
+    /// clock as `start`, which does not have to be the real time.
+    pub fn starting_at(start: Instant) -> TimerWheel {
+        TimerWheel {
+            start,
+            elapsed: 0,
+            levels: (0..LEVELS)
+                .map(|_| Level {
+                    occupied: 0,
+                    slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+                })
+                .collect(),
+            entries: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+            next_seq: 0,
+        }
+    }
+
+    /// Registers `waker` to be woken once `deadline` is reached.
+    ///
+    /// Deadlines are rounded up to the next millisecond so that a timer never
+    /// fires early. A deadline that has already been reached fires on the next
+    /// call to `advance`.
+    pub fn insert(&mut self, deadline: Instant, waker: Waker) -> TimerKey {
+        let when = self.tick_for(deadline).max(self.elapsed);
+
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.entries.push(Slab {
+                    generation: 0,
+                    entry: None,
+                });
+                self.entries.len() - 1
+            }
+        };
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        let generation = self.entries[index].generation;
+        self.entries[index].entry = Some(Entry {
+            when,
+            waker,
+            seq,
+            level: 0,
+            slot: 0,
+            pos: 0,
+        });
+
+        self.link(index);
+        self.len += 1;
+
+        TimerKey { index, generation }
+    }
+
+    /// Cancels the timer identified by `key`.
+    ///
+    /// Returns `false` if the timer already fired or was already cancelled.
+    pub fn cancel(&mut self, key: TimerKey) -> bool {
+        match self.entries.get(key.index) {
+            Some(slab) if slab.generation == key.generation && slab.entry.is_some() => {}
+            _ => return false,
+        }
+
+        self.unlink(key.index);
+        self.release(key.index);
+        true
+    }
+
+    /// Removes every timer whose deadline is at or before `now` and returns
+    /// their wakers, by deadline then by insertion order.
+    ///
+    /// The wakers are not woken by the wheel. The caller wakes them, after
+    /// releasing whatever lock protects the wheel: a waker may do anything,
+    /// including registering a new timer.
+    pub fn advance(&mut self, now: Instant) -> Vec<Waker> {
+        let now = self.tick_for_elapsed(now);
+        let mut fired = vec![];
+
+        while let Some(expiration) = self.next_expiration() {
+            if expiration.deadline > now {
+                break;
+            }
+
+            self.elapsed = expiration.deadline;
+
+            // Take every timer out of the slot. On level 0, a slot spans a
+            // single tick, so all of its timers are due. On higher levels,
+            // the slot spans many ticks: its timers are re-inserted and land
+            // on a lower level, closer to their actual deadline.
+            let level = &mut self.levels[expiration.level];
+            let mut indices = std::mem::take(&mut level.slots[expiration.slot]);
+            level.occupied &= !(1 << expiration.slot);
+
+            // All the timers of a level 0 slot share the same deadline.
+            if expiration.level == 0 {
+                indices.sort_by_key(|&index| self.entries[index].entry.as_ref().unwrap().seq);
+            }
+
+            for index in indices {
+                if expiration.level == 0 {
+                    fired.push(self.release(index));
+                } else {
+                    self.link(index);
+                }
+            }
+        }
+
+        // Nothing else is due before `now`.
+        self.elapsed = self.elapsed.max(now);
+
+        fired
+    }
+
+    /// Returns the instant at which the next timer fires, if any.
+    ///
+    /// For timers on higher levels, this is the instant at which they cascade
+    /// down, which may come before their deadline. Sleeping until then and
+    /// calling `advance` is still correct: it simply moves the timers closer.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.next_expiration()
+            .map(|expiration| self.start + Duration::from_millis(expiration.deadline))
+    }
+
+    /// Returns the number of pending timers.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if no timers are pending.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Store the entry at `index` in the slot matching its deadline.
+    fn link(&mut self, index: usize) {
+        let entry = self.entries[index].entry.as_mut().unwrap();
+        let (level, slot) = slot_for(self.elapsed, entry.when);
+
+        let slots = &mut self.levels[level];
+        entry.level = level;
+        entry.slot = slot;
+        entry.pos = slots.slots[slot].len();
+
+        slots.slots[slot].push(index);
+        slots.occupied |= 1 << slot;
+    }
+
+    // Remove the entry at `index` from its slot.
+    fn unlink(&mut self, index: usize) {
+        let entry = self.entries[index].entry.as_ref().unwrap();
+        let (level, slot, pos) = (entry.level, entry.slot, entry.pos);
+
+        let slots = &mut self.levels[level].slots[slot];
+        slots.swap_remove(pos);
+
+        // `swap_remove` moved the last entry of the slot into `pos`.
+        if let Some(&moved) = slots.get(pos) {
+            self.entries[moved].entry.as_mut().unwrap().pos = pos;
+        }
+
+        if slots.is_empty() {
+            self.levels[level].occupied &= !(1 << slot);
+        }
+    }
+
+    // Free the storage of the entry at `index`, returning its waker.
+    fn release(&mut self, index: usize) -> Waker {
+        let slab = &mut self.entries[index];
+        let entry = slab.entry.take().unwrap();
+
+        slab.generation += 1;
+        self.free.push(index);
+        self.len -= 1;
+
+        entry.waker
+    }
+
+    // Find the next slot to process. Timers on a lower level always expire
+    // before timers on a higher level, so the first occupied level wins.
+    fn next_expiration(&self) -> Option<Expiration> {
+        for (level, slots) in self.levels.iter().enumerate() {
+            if slots.occupied == 0 {
+                continue;
+            }
+
+            let slot_range = 1u64 << (SLOT_BITS as usize * level);
+            let level_range = slot_range << SLOT_BITS;
+
+            // Find the first occupied slot at or after the current position,
+            // wrapping around the end of the level.
+            let now_slot = ((self.elapsed / slot_range) & SLOT_MASK) as u32;
+            let distance = slots.occupied.rotate_right(now_slot).trailing_zeros();
+            let slot = ((now_slot + distance) as usize) % SLOTS;
+
+            let level_start = self.elapsed & !(level_range - 1);
+            let mut deadline = level_start + slot as u64 * slot_range;
+
+            if (slot as u32) < now_slot {
+                // The slot is before the current position, so it belongs to
+                // the next rotation of this level.
+                deadline += level_range;
+            }
+
+            return Some(Expiration {
+                level,
+                slot,
+                deadline,
+            });
+        }
+
+        None
+    }
+
+    // Convert a deadline to ticks, rounding up.
+    fn tick_for(&self, deadline: Instant) -> u64 {
+        let since_start = deadline.saturating_duration_since(self.start);
+        let ms = since_start.as_millis() as u64;
+
+        if since_start > Duration::from_millis(ms) {
+            ms + 1
+        } else {
+            ms
+        }
+    }
+
+    // Convert the current instant to ticks, rounding down: the current
+    // millisecond has not fully elapsed yet.
+    fn tick_for_elapsed(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.start).as_millis() as u64
+    }
+}
+
+impl Default for TimerWheel {
+    fn default() -> TimerWheel {
+        TimerWheel::new()
+    }
+}
+
+// Returns the level and slot for a timer expiring at `when`, given the current
+// `elapsed` tick.
+//
+// The level is the lowest one whose current rotation contains `when`, that is
+// the lowest level on which `elapsed` and `when` only differ in that level's
+// slot bits or below. Comparing the two with XOR finds the most significant
+// bit in which they differ.
+fn slot_for(elapsed: u64, when: u64) -> (usize, usize) {
+    let masked = (elapsed ^ when) | SLOT_MASK;
+    let significant = 63 - masked.leading_zeros() as usize;
+    let level = significant / SLOT_BITS as usize;
+
+    if level >= LEVELS {
+        // The deadline is beyond the horizon of the wheel. Park the timer on
+        // the top level, in the slot that comes last in the current rotation.
+        // Once that slot is reached, the timer is re-inserted and the process
+        // repeats until its deadline is within range.
+        let level = LEVELS - 1;
+        let now_slot = (elapsed >> (level * SLOT_BITS as usize)) & SLOT_MASK;
+
+        return (level, ((now_slot + SLOT_MASK) & SLOT_MASK) as usize);
+    }
+
+    let slot = ((when >> (level * SLOT_BITS as usize)) & SLOT_MASK) as usize;
+
+    (level, slot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::noop_waker;
+
+    use futures::task::{self, ArcWake};
+    use std::sync::Arc;
+
+    // Returns the deadline `ms` milliseconds after `start`.
+    fn at(start: Instant, ms: u64) -> Instant {
+        start + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn fires_once_the_deadline_is_reached() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::starting_at(start);
+
+        // One deadline on each of the first four levels.
+        for ms in [5, 100, 5_000, 300_000] {
+            wheel.insert(at(start, ms), noop_waker());
+        }
+
+        assert_eq!(wheel.len(), 4);
+
+        for ms in [5, 100, 5_000, 300_000] {
+            assert!(wheel.advance(at(start, ms - 1)).is_empty());
+            assert_eq!(wheel.advance(at(start, ms)).len(), 1);
+        }
+
+        assert!(wheel.is_empty());
+        assert_eq!(wheel.next_deadline(), None);
+    }
+
+    #[test]
+    fn next_deadline_is_never_after_the_earliest_timer() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::starting_at(start);
+
+        wheel.insert(at(start, 1_000), noop_waker());
+        wheel.insert(at(start, 70), noop_waker());
+
+        // Timers on higher levels cascade down before they fire, so the wheel
+        // may report an earlier instant than the actual deadline.
+        let next = wheel.next_deadline().unwrap();
+        assert!(next <= at(start, 70));
+
+        wheel.advance(next);
+        assert_eq!(wheel.advance(at(start, 70)).len(), 1);
+    }
+
+    #[test]
+    fn cancelled_timers_do_not_fire() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::starting_at(start);
+
+        let keys: Vec<_> = (0..10_000)
+            .map(|i| wheel.insert(at(start, i % 500 + 1), noop_waker()))
+            .collect();
+
+        for key in &keys {
+            assert!(wheel.cancel(*key));
+        }
+
+        assert!(wheel.is_empty());
+        assert!(wheel.advance(at(start, 1_000)).is_empty());
+
+        // The storage is reused, but the old keys cannot cancel the new
+        // occupants.
+        let key = wheel.insert(at(start, 2_000), noop_waker());
+        assert!(!wheel.cancel(keys[0]));
+        assert!(wheel.cancel(key));
+    }
+
+    #[test]
+    fn deadlines_in_the_past_fire_on_the_next_advance() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::starting_at(start);
+
+        wheel.advance(at(start, 100));
+        wheel.insert(at(start, 10), noop_waker());
+
+        assert_eq!(wheel.advance(at(start, 100)).len(), 1);
+    }
+
+    #[test]
+    fn timers_sharing_a_deadline_fire_in_insertion_order() {
+        struct Id;
+
+        impl ArcWake for Id {
+            fn wake_by_ref(_: &Arc<Self>) {}
+        }
+
+        let start = Instant::now();
+        let mut wheel = TimerWheel::starting_at(start);
+
+        // The deadline is far enough to start out on level 1 and cascade.
+        let wakers: Vec<_> = (0..6).map(|_| task::waker(Arc::new(Id))).collect();
+        let keys: Vec<_> = wakers
+            .iter()
+            .map(|waker| wheel.insert(at(start, 100), waker.clone()))
+            .collect();
+
+        // Cancelling a timer moves another one into its place in the slot.
+        wheel.cancel(keys[1]);
+
+        let fired: Vec<_> = wheel
+            .advance(at(start, 100))
+            .iter()
+            .map(|fired| wakers.iter().position(|w| w.will_wake(fired)).unwrap())
+            .collect();
+
+        assert_eq!(fired, [0, 2, 3, 4, 5]);
+    }
+}
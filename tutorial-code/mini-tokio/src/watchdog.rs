@@ -0,0 +1,225 @@
+//! Aborting tasks whose poll runs for too long.
+//!
+//! A task that blocks inside `poll` holds its worker thread for as long as it
+//! blocks. With `Builder::max_poll_duration`, each worker records when its
+//! current poll started, and a watchdog thread checks those timestamps. Once a
+//! poll exceeds the limit, the watchdog completes the task's `JoinHandle` with
+//! `JoinError::Stuck` and aborts the task.
+//!
+//! Rust cannot interrupt a running poll. The worker stays stuck until the poll
+//! returns, and only then is the future dropped. What the watchdog buys is
+//! that whoever awaits the task finds out right away, and that the task is
+//! never polled again.
+
+use crate::Task;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The watchdog of a mini-tokio instance. The watchdog thread exits once it is
+// dropped.
+pub(crate) struct Watchdog {
+    handle: WatchdogHandle,
+}
+
+// Used by the workers to report the polls they start. Cheap to clone.
+#[derive(Clone)]
+pub(crate) struct WatchdogHandle {
+    shared: Arc<Shared>,
+}
+
+// Unregisters a poll from the watchdog when dropped, once the poll returned.
+pub(crate) struct Polling<'a> {
+    handle: &'a WatchdogHandle,
+    task_id: u64,
+}
+
+struct Shared {
+    state: Mutex<State>,
+
+    // Signaled when a poll starts or the watchdog is dropped.
+    condvar: Condvar,
+
+    // The longest a single poll may take.
+    max_poll_duration: Duration,
+}
+
+struct State {
+    // The polls in progress, by task id, with the time they started.
+    polls: HashMap<u64, (Instant, Weak<Task>)>,
+
+    // Set when the watchdog is dropped.
+    shutdown: bool,
+}
+
+impl Watchdog {
+    pub(crate) fn new(thread_name: String, max_poll_duration: Duration) -> Watchdog {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                polls: HashMap::new(),
+                shutdown: false,
+            }),
+            condvar: Condvar::new(),
+            max_poll_duration,
+        });
+
+        let thread_shared = shared.clone();
+
+        thread::Builder::new()
+            .name(thread_name)
+            .spawn(move || thread_shared.run())
+            .expect("failed to spawn the watchdog thread");
+
+        Watchdog {
+            handle: WatchdogHandle { shared },
+        }
+    }
+
+    pub(crate) fn handle(&self) -> WatchdogHandle {
+        self.handle.clone()
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.handle.shared.state.lock().unwrap().shutdown = true;
+        self.handle.shared.condvar.notify_one();
+    }
+}
+
+impl WatchdogHandle {
+    // Called by a worker right before it polls `task`. The poll is watched
+    // until the returned guard is dropped.
+    pub(crate) fn polling(&self, task: &Arc<Task>) -> Polling<'_> {
+        let mut state = self.shared.state.lock().unwrap();
+        state
+            .polls
+            .insert(task.id, (Instant::now(), Arc::downgrade(task)));
+
+        // The watchdog thread may be waiting with no poll to watch.
+        self.shared.condvar.notify_one();
+
+        Polling {
+            handle: self,
+            task_id: task.id,
+        }
+    }
+}
+
+impl Drop for Polling<'_> {
+    fn drop(&mut self) {
+        // Already removed if the watchdog found the poll stuck.
+        let mut state = self.handle.shared.state.lock().unwrap();
+        state.polls.remove(&self.task_id);
+    }
+}
+
+impl Shared {
+    // The loop executed by the watchdog thread.
+    fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        while !state.shutdown {
+            let now = Instant::now();
+            let mut stuck = vec![];
+            let mut next_deadline = None;
+
+            state.polls.retain(|_, (started, task)| {
+                let deadline = *started + self.max_poll_duration;
+
+                if deadline <= now {
+                    stuck.push(task.clone());
+                    return false;
+                }
+
+                next_deadline = Some(next_deadline.map_or(deadline, |d: Instant| d.min(deadline)));
+                true
+            });
+
+            if !stuck.is_empty() {
+                // Aborting wakes the task, which may take other locks. The
+                // state lock is released first.
+                drop(state);
+
+                for task in stuck {
+                    if let Some(task) = task.upgrade() {
+                        task.stuck();
+                    }
+                }
+
+                state = self.state.lock().unwrap();
+                continue;
+            }
+
+            state = match next_deadline {
+                Some(deadline) => self.condvar.wait_timeout(state, deadline - now).unwrap().0,
+                None => self.condvar.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{spawn, yield_now, Builder, JoinError};
+
+    use std::future;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    // Sets the flag when dropped.
+    struct SetOnDrop(Arc<AtomicBool>);
+
+    impl Drop for SetOnDrop {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn a_stuck_poll_aborts_the_task_while_others_continue() {
+        let mini_tokio = Builder::new()
+            .worker_threads(2)
+            .max_poll_duration(Duration::from_millis(50))
+            .build();
+
+        let dropped = Arc::new(AtomicBool::new(false));
+        let guard = SetOnDrop(dropped.clone());
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+
+        let (stuck, other) = mini_tokio.block_on(async move {
+            // Blocks its worker until released, far longer than allowed.
+            let stuck = spawn(async move {
+                let _guard = guard;
+                let _ = release_rx.recv_timeout(Duration::from_secs(5));
+
+                // Only the abort gets the future dropped from here.
+                future::pending::<()>().await;
+            });
+
+            let other = spawn(async {
+                yield_now().await;
+                42
+            });
+
+            let stuck = stuck.await;
+            let other = other.await;
+
+            // The worker is still blocked in the stuck poll. Let it go.
+            release_tx.send(()).unwrap();
+
+            (stuck, other)
+        });
+
+        assert!(matches!(stuck, Err(JoinError::Stuck)));
+        assert_eq!(other.unwrap(), 42);
+
+        // The task was aborted, its future is dropped instead of being polled
+        // again.
+        mini_tokio.run_until_idle();
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+}
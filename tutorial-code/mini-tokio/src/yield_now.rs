@@ -0,0 +1,72 @@
+//! Voluntarily giving control back to the executor.
+//!
+//! The executor cannot interrupt a task. Once a task is polled, the worker
+//! thread is busy until `poll` returns. A task that runs a long loop without
+//! ever returning `Poll::Pending` keeps every other task waiting. `yield_now`
+//! lets such a task step aside in between chunks of work.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Yields execution back to the executor.
+///
+/// The task is scheduled again right away, behind the tasks that are already
+/// queued. Those run first, then the task resumes after the `.await`.
+pub async fn yield_now() {
+    YieldNow { yielded: false }.await
+}
+
+struct YieldNow {
+    // `true` once the future has returned `Poll::Pending`.
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+
+        self.yielded = true;
+
+        // Waking the task pushes it to the back of the scheduled channel. The
+        // executor then polls the other queued tasks before getting back to
+        // this one.
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{yield_now, MiniTokio};
+
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn yielding_tasks_interleave() {
+        let mini_tokio = MiniTokio::new();
+        let output = Arc::new(Mutex::new(vec![]));
+
+        for name in ["ping", "pong"] {
+            let output = output.clone();
+
+            mini_tokio.spawn(async move {
+                for i in 0..3 {
+                    output.lock().unwrap().push(format!("{} {}", name, i));
+                    yield_now().await;
+                }
+            });
+        }
+
+        mini_tokio.run_until_idle();
+
+        assert_eq!(
+            *output.lock().unwrap(),
+            ["ping 0", "pong 0", "ping 1", "pong 1", "ping 2", "pong 2"]
+        );
+    }
+}
@@ -14,6 +14,8 @@ async fn publish() -> mini_redis::Result<()> {
     Ok(())
 }
 
+// The `filter` closure is kept as a `match` to mirror the tutorial text.
+#[allow(clippy::match_like_matches_macro)]
 async fn subscribe() -> mini_redis::Result<()> {
     let client = client::connect("127.0.0.1:6379").await?;
     let subscriber = client.subscribe(vec!["numbers".to_string()]).await?;